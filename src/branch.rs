@@ -0,0 +1,261 @@
+//! Module to create, list, delete and rename branches
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::config::{config_file_path, ConfigFile, ConfigScope};
+use crate::refs::{check_ref_format, RefChange, RefTransaction, RefUpdate, RefValue};
+use crate::{GitRepo, Head, ObjectId, Ref};
+
+impl GitRepo {
+    /// Local branches, sorted by name, along with the remote-tracking branches if `remotes`
+    pub fn list_branches(&self, remotes: bool) -> Result<Vec<Ref>> {
+        let mut branches = self.refs.list("refs/heads/")?;
+        if remotes {
+            branches.extend(self.refs.list("refs/remotes/")?);
+        }
+        Ok(branches)
+    }
+
+    /// Create a branch at the commit `start` (HEAD by default). An existing branch is only
+    /// replaced with `force`. Starting from a remote-tracking branch sets it as upstream.
+    pub fn create_branch(&self, name: &str, start: Option<&str>, force: bool) -> Result<()> {
+        let start = start.unwrap_or("HEAD");
+        let (transaction, _) = self.branch_transaction(name, start, force)?;
+        transaction.commit(&self.refs)?;
+        self.track_start(name, start)
+    }
+
+    /// The transaction creating the branch `name` at `start`, or resetting it with `force`,
+    /// along with the commit it will point to
+    pub(crate) fn branch_transaction(
+        &self,
+        name: &str,
+        start: &str,
+        force: bool,
+    ) -> Result<(RefTransaction, ObjectId)> {
+        let full_name = branch_ref(name)?;
+        let exists = self.refs.read(&full_name)?.is_some();
+        ensure!(force || !exists, "A branch named '{name}' already exists");
+        if exists {
+            ensure!(
+                !self.is_current_branch(&full_name)?,
+                "Cannot force update the current branch"
+            );
+        }
+
+        let oid = self.resolve_commit(start)?;
+        let message = match exists {
+            true => format!("branch: Reset to {start}"),
+            false => format!("branch: Created from {start}"),
+        };
+        let mut transaction = self.ref_transaction(&message)?;
+        transaction.add(RefUpdate {
+            name: full_name,
+            change: RefChange::Set(RefValue::Direct(oid)),
+            old: (!force).then_some(None),
+            deref: false,
+        });
+        Ok((transaction, oid))
+    }
+
+    /// Set `start` as the upstream of the new branch `name` if it's a remote-tracking branch
+    pub(crate) fn track_start(&self, name: &str, start: &str) -> Result<()> {
+        if let Some((start_ref, _)) = self.dwim_ref(start)? {
+            if start_ref.starts_with("refs/remotes/") {
+                self.set_upstream(name, start)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a branch, and its config. Unless `force`, the branch must be merged in its
+    /// upstream, or in HEAD if it doesn't have one.
+    pub fn delete_branch(&self, name: &str, force: bool) -> Result<()> {
+        let full_name = branch_ref(name)?;
+        let oid = self
+            .refs
+            .resolve(&full_name)?
+            .ok_or_else(|| anyhow!("Branch '{name}' not found"))?;
+        ensure!(
+            !self.is_current_branch(&full_name)?,
+            "Cannot delete branch '{name}': it is checked out"
+        );
+
+        if !force {
+            let merged_into = match self.upstream(&full_name) {
+                Ok(upstream) if self.refs.resolve(&upstream)?.is_some() => upstream,
+                _ => "HEAD".to_string(),
+            };
+            let target = self.refs.resolve(&merged_into)?;
+            let merged = match target {
+                Some(target) => self.ancestors(&[target])?.contains(&oid),
+                None => false,
+            };
+            ensure!(
+                merged,
+                "The branch '{name}' is not fully merged.\n\
+                 If you are sure you want to delete it, run 'branch -D {name}'"
+            );
+        }
+
+        let mut transaction = self.ref_transaction("")?;
+        transaction.add(RefUpdate {
+            name: full_name,
+            change: RefChange::Delete,
+            old: Some(Some(oid)),
+            deref: false,
+        });
+        transaction.commit(&self.refs)?;
+
+        let mut config = self.local_config_file()?;
+        if config.remove_section(&format!("branch.{name}"))? {
+            config.write()?;
+        }
+
+        let short = self.abbreviate(oid, self.abbrev_len()?)?;
+        println!("Deleted branch {name} (was {short}).");
+        Ok(())
+    }
+
+    /// Rename a branch (the current one if `old_name` is `None`) along with its reflog and
+    /// config. An existing branch named `new_name` is only replaced with `force`.
+    pub fn rename_branch(&self, old_name: Option<&str>, new_name: &str, force: bool) -> Result<()> {
+        let old_ref = match old_name {
+            Some(old_name) => branch_ref(old_name)?,
+            None => match self.head()? {
+                Head::Branch(branch, _) => branch,
+                Head::Detached(_) => bail!("Cannot rename the current branch while not on any"),
+            },
+        };
+        let new_ref = branch_ref(new_name)?;
+        let oid = self.refs.resolve(&old_ref)?;
+        let is_current = self.is_current_branch(&old_ref)?;
+        // The current branch may not have any commit yet
+        ensure!(
+            oid.is_some() || is_current,
+            "No branch named '{}'",
+            short_name(&old_ref)
+        );
+        ensure!(
+            force || self.refs.read(&new_ref)?.is_none(),
+            "A branch named '{new_name}' already exists"
+        );
+
+        if let Some(oid) = oid {
+            let message = format!("Branch: renamed {old_ref} to {new_ref}");
+            let mut transaction = self.ref_transaction(&message)?;
+            transaction.add(RefUpdate {
+                name: old_ref.clone(),
+                change: RefChange::Delete,
+                old: Some(Some(oid)),
+                deref: false,
+            });
+            transaction.add(RefUpdate {
+                name: new_ref.clone(),
+                change: RefChange::Set(RefValue::Direct(oid)),
+                old: None,
+                deref: false,
+            });
+            if is_current {
+                transaction.add(RefUpdate {
+                    name: "HEAD".to_string(),
+                    change: RefChange::Set(RefValue::Symbolic(new_ref.clone())),
+                    old: None,
+                    deref: false,
+                });
+            }
+
+            // The history of the branch follows it
+            self.refs.rename_reflog(&old_ref, &new_ref)?;
+            if let Err(e) = transaction.commit(&self.refs) {
+                self.refs.rename_reflog(&new_ref, &old_ref)?;
+                return Err(e);
+            }
+        } else {
+            self.refs
+                .write("HEAD", &RefValue::Symbolic(new_ref.clone()))?;
+        }
+
+        let mut config = self.local_config_file()?;
+        let old_section = format!("branch.{}", short_name(&old_ref));
+        if config.rename_section(&old_section, &format!("branch.{new_name}"))? {
+            config.write()?;
+        }
+        Ok(())
+    }
+
+    /// Make a branch track `upstream`, a remote-tracking branch like `origin/master` or another
+    /// local branch, by setting `branch.<name>.remote` and `branch.<name>.merge`
+    pub fn set_upstream(&self, name: &str, upstream: &str) -> Result<()> {
+        let full_name = branch_ref(name)?;
+        ensure!(
+            self.refs.read(&full_name)?.is_some(),
+            "Branch '{name}' does not exist"
+        );
+        let (upstream_ref, _) = self
+            .dwim_ref(upstream)?
+            .ok_or_else(|| anyhow!("The requested upstream branch '{upstream}' does not exist"))?;
+
+        let (remote, merge) = if let Some(branch) = upstream_ref.strip_prefix("refs/heads/") {
+            (".".to_string(), format!("refs/heads/{branch}"))
+        } else if let Some(remote_branch) = upstream_ref.strip_prefix("refs/remotes/") {
+            let Some((remote, branch)) = remote_branch.split_once('/') else {
+                bail!("Cannot set up tracking information: '{upstream}' is not a branch");
+            };
+            (remote.to_string(), format!("refs/heads/{branch}"))
+        } else {
+            bail!("Cannot set up tracking information: '{upstream}' is not a branch");
+        };
+
+        let mut config = self.local_config_file()?;
+        config.set(&format!("branch.{name}.remote"), &remote)?;
+        config.set(&format!("branch.{name}.merge"), &merge)?;
+        config.write()?;
+
+        println!(
+            "branch '{name}' set up to track '{}'.",
+            short_name(&upstream_ref)
+        );
+        Ok(())
+    }
+
+    fn is_current_branch(&self, full_name: &str) -> Result<bool> {
+        Ok(matches!(self.head()?, Head::Branch(branch, _) if branch == full_name))
+    }
+
+    fn local_config_file(&self) -> Result<ConfigFile> {
+        ConfigFile::open(config_file_path(ConfigScope::Local, &self.git_dir)?)
+    }
+}
+
+/// Full name of a branch, e.g `refs/heads/master` for `master`
+pub(crate) fn branch_ref(name: &str) -> Result<String> {
+    let full_name = format!("refs/heads/{name}");
+    ensure!(
+        name != "HEAD" && !name.starts_with('-') && check_ref_format(&full_name),
+        "'{name}' is not a valid branch name"
+    );
+    Ok(full_name)
+}
+
+/// Name of a branch as shown to users: `master` or `origin/master`
+pub fn short_name(full_name: &str) -> &str {
+    full_name
+        .strip_prefix("refs/heads/")
+        .or_else(|| full_name.strip_prefix("refs/remotes/"))
+        .unwrap_or(full_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branch_ref() {
+        assert_eq!(branch_ref("feature/x").unwrap(), "refs/heads/feature/x");
+        assert!(branch_ref("HEAD").is_err());
+        assert!(branch_ref("-d").is_err());
+        assert!(branch_ref("a..b").is_err());
+        assert_eq!(short_name("refs/remotes/origin/master"), "origin/master");
+    }
+}
@@ -0,0 +1,550 @@
+//! Module to switch between branches and commits, updating the work tree and the index
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::branch::{branch_ref, short_name};
+use crate::index::{Entry, Stat};
+use crate::refs::{RefChange, RefUpdate, RefValue};
+use crate::status::{is_gitlink, Change};
+use crate::{is_in_dir, GitRepo, Head, ObjectId, ObjectType};
+
+impl GitRepo {
+    /// Check out a branch, or detach HEAD at a commit if `target` isn't a branch or `detach` is
+    /// set. The commit can be given by id, or by a tag or any other ref. A branch that only
+    /// exists on a single remote is created to track it. With `new_branch`, a branch of that
+    /// name is created at `target` (HEAD by default) and checked out.
+    ///
+    /// Local changes are kept, unless they would be overwritten, which is an error unless
+    /// `force` is set, in which case they are thrown away. Returns the new state of HEAD.
+    pub fn checkout(
+        &self,
+        target: Option<&str>,
+        new_branch: Option<&str>,
+        detach: bool,
+        force: bool,
+    ) -> Result<Head> {
+        let old_head = self.head()?;
+        // The new branch and its start point
+        let mut new_branch =
+            new_branch.map(|name| (name.to_string(), target.unwrap_or("HEAD").to_string()));
+        let target = match (&new_branch, target) {
+            (Some((name, _)), _) => name.clone(),
+            (None, Some(target)) => target.to_string(),
+            (None, None) => bail!("Nothing to check out"),
+        };
+        let target = target.as_str();
+        if new_branch.is_none() && !detach && !self.is_branch(target)? {
+            new_branch = self
+                .guess_remote_branch(target)?
+                .map(|start| (target.to_string(), start));
+        }
+
+        // Refs are locked and checked before touching the work tree
+        let mut branch_transaction = None;
+        let (value, commit) = match &new_branch {
+            Some((name, start)) => {
+                let (transaction, commit) = self.branch_transaction(name, start, false)?;
+                branch_transaction = Some(transaction);
+                (RefValue::Symbolic(branch_ref(name)?), commit)
+            }
+            None => {
+                let commit = self.resolve_commit(target)?;
+                match !detach && self.is_branch(target)? {
+                    true => (RefValue::Symbolic(format!("refs/heads/{target}")), commit),
+                    false => (RefValue::Direct(commit), commit),
+                }
+            }
+        };
+        let from = match &old_head {
+            Head::Branch(name, _) => short_name(name).to_string(),
+            Head::Detached(oid) => oid.to_string(),
+        };
+        let message = format!("checkout: moving from {from} to {target}");
+        let mut transaction = self.ref_transaction(&message)?;
+        transaction.add(RefUpdate {
+            name: "HEAD".to_string(),
+            change: RefChange::Set(value),
+            old: Some(old_head.commit()),
+            deref: false,
+        });
+        let branch_transaction = match branch_transaction {
+            Some(transaction) => Some(transaction.prepare(&self.refs)?),
+            None => None,
+        };
+        let transaction = transaction.prepare(&self.refs)?;
+
+        let old_tree = match old_head.commit() {
+            Some(old) => Some(self.peel(old, ObjectType::Tree)?),
+            None => None,
+        };
+        let new_tree = self.peel(commit, ObjectType::Tree)?;
+        let carried_over = self.update_work_tree(old_tree, new_tree, force)?;
+
+        if let (Some(branch_transaction), Some((name, start))) = (branch_transaction, &new_branch) {
+            branch_transaction.commit()?;
+            self.track_start(name, start)?;
+        }
+        transaction.commit()?;
+
+        for (change, path) in carried_over {
+            println!("{}\t{path}", change.code());
+        }
+
+        let head = self.head()?;
+        match &head {
+            Head::Branch(name, _) if matches!(&old_head, Head::Branch(old, _) if old == name) => {
+                println!("Already on '{}'", short_name(name));
+            }
+            Head::Branch(name, _) if new_branch.is_some() => {
+                println!("Switched to a new branch '{}'", short_name(name));
+            }
+            Head::Branch(name, _) => println!("Switched to branch '{}'", short_name(name)),
+            Head::Detached(oid) => {
+                let commit = self
+                    .get_object(*oid)?
+                    .as_commit()
+                    .ok_or_else(|| anyhow!("HEAD is not at a commit"))?;
+                let message = String::from_utf8_lossy(&commit.message);
+                let summary = message.lines().next().unwrap_or_default();
+                let short = self.abbreviate(*oid, self.abbrev_len()?)?;
+                println!("HEAD is now at {short} {summary}");
+            }
+        }
+        Ok(head)
+    }
+
+    /// Like [`checkout`](Self::checkout), but a commit that isn't a branch is only checked out
+    /// with `detach`
+    pub fn switch(
+        &self,
+        target: Option<&str>,
+        new_branch: Option<&str>,
+        detach: bool,
+        force: bool,
+    ) -> Result<Head> {
+        if let (Some(target), None, false) = (target, new_branch, detach) {
+            ensure!(
+                self.is_branch(target)? || self.guess_remote_branch(target)?.is_some(),
+                "a branch is expected, got '{target}'"
+            );
+        }
+        self.checkout(target, new_branch, detach, force)
+    }
+
+    /// Populate the work tree and the index from HEAD, throwing away what is there, e.g right
+    /// after a clone
+    pub fn checkout_head(&self) -> Result<()> {
+        let tree = self.peel(self.resolve_head()?, ObjectType::Tree)?;
+        self.update_work_tree(None, tree, true)?;
+        Ok(())
+    }
+
+    fn is_branch(&self, name: &str) -> Result<bool> {
+        Ok(self.refs.read(&format!("refs/heads/{name}"))?.is_some())
+    }
+
+    /// The remote-tracking branch named `<remote>/<name>`, if there is exactly one
+    fn guess_remote_branch(&self, name: &str) -> Result<Option<String>> {
+        let mut matching = self
+            .refs
+            .list("refs/remotes/")?
+            .into_iter()
+            .map(|r| short_name(&r.name).to_string())
+            .filter(|branch| branch.split_once('/').is_some_and(|(_, b)| b == name));
+        Ok(match (matching.next(), matching.next()) {
+            (Some(branch), None) => Some(branch),
+            _ => None,
+        })
+    }
+
+    /// Update the work tree and the index from `old_tree`, the tree of HEAD, to `new_tree`:
+    /// files that are the same in both keep their local changes, while the other ones are
+    /// updated if they don't have any, deleted if they aren't in `new_tree` anymore. Ignored
+    /// files are overwritten. Returns the local changes that were kept, by path.
+    fn update_work_tree(
+        &self,
+        old_tree: Option<ObjectId>,
+        new_tree: ObjectId,
+        force: bool,
+    ) -> Result<Vec<(Change, String)>> {
+        let work_tree = self.work_tree()?;
+        let old = match old_tree {
+            Some(tree) => self.flatten_tree(tree)?,
+            None => Default::default(),
+        };
+        let new = self.flatten_tree(new_tree)?;
+        let mut index = self.read_index()?;
+        ensure!(
+            force || index.entries.iter().all(|e| e.stage == 0),
+            "You need to resolve your current index first"
+        );
+        let status = self.status()?;
+        let changes = status
+            .entries
+            .iter()
+            .map(|e| (e.path.as_str(), e))
+            .collect::<HashMap<_, _>>();
+
+        let paths = old
+            .keys()
+            .chain(new.keys())
+            .cloned()
+            .chain(index.entries.iter().map(|e| e.path_str().into_owned()))
+            .collect::<BTreeSet<_>>();
+        // Whether an untracked file is at `path`, in the directory at `path`, or where one of its
+        // parent directories would go. Directories are listed with a trailing `/`.
+        let is_untracked = |path: &str| {
+            status.untracked.iter().any(|untracked| {
+                let untracked_path = untracked.trim_end_matches('/');
+                untracked_path == path
+                    || is_in_dir(path, untracked_path)
+                    || is_in_dir(untracked_path, path)
+            })
+        };
+        let mut updates = Vec::new();
+        let mut carried_over = Vec::new();
+        let mut local_changes = Vec::new();
+        let mut untracked = Vec::new();
+        for path in paths {
+            let target = new.get(&path).copied();
+            let changed = old.get(&path).copied() != target;
+            let change = changes.get(path.as_str());
+            // Local changes to a file that doesn't change are kept
+            if !changed && (change.is_none() || !force) {
+                if let Some(change) = change {
+                    let staged = change.staged;
+                    let code = if staged == Change::Unmodified {
+                        change.unstaged
+                    } else {
+                        staged
+                    };
+                    carried_over.push((code, path));
+                }
+                continue;
+            }
+            if !force {
+                match change {
+                    // Already staged as it is in the new tree, so only unstaged changes remain
+                    Some(change) if change.index == target => {
+                        if change.unstaged != Change::Unmodified {
+                            carried_over.push((change.unstaged, path));
+                        }
+                        continue;
+                    }
+                    Some(_) => local_changes.push(path.clone()),
+                    None if !old.contains_key(&path) && is_untracked(&path) => {
+                        untracked.push(path.clone())
+                    }
+                    None => {}
+                }
+            }
+            updates.push((path, target));
+        }
+        ensure!(
+            local_changes.is_empty(),
+            "Your local changes to the following files would be overwritten by checkout:\n\t{}\n\
+             Please commit your changes or stash them before you switch branches.",
+            local_changes.join("\n\t")
+        );
+        ensure!(
+            untracked.is_empty(),
+            "The following untracked working tree files would be overwritten by checkout:\n\t{}\n\
+             Please move or remove them before you switch branches.",
+            untracked.join("\n\t")
+        );
+
+        // Delete files first, so that directories can replace them and the other way around
+        for (path, _) in updates.iter().filter(|(_, target)| target.is_none()) {
+            let full_path = work_tree.join(path);
+            match full_path.symlink_metadata() {
+                // The directory of a submodule, only removed if it's empty
+                Ok(metadata) if metadata.is_dir() => {
+                    let _ = fs::remove_dir(&full_path);
+                }
+                Ok(_) => fs::remove_file(&full_path)?,
+                Err(_) => {}
+            }
+            self.remove_empty_parents(&full_path)?;
+            index.remove(path);
+        }
+        for (path, target) in &updates {
+            if let Some((mode, oid)) = target {
+                let stat = self.checkout_file(path, *mode, *oid)?;
+                index.add(Entry::new(path.to_string(), *mode, *oid, stat));
+            }
+        }
+        self.write_index(&index)?;
+        Ok(carried_over)
+    }
+
+    /// Write a blob into the work tree at `path`, replacing what is there: a symlink to the
+    /// content of the blob for mode 120000, or a file, executable for mode 100755. Submodules
+    /// (mode 160000) aren't checked out, only their empty directory is created. Returns the stat
+    /// data of the new file, for the index.
+    fn checkout_file(&self, path: &str, mode: u32, oid: ObjectId) -> Result<Stat> {
+        let work_tree = self.work_tree()?;
+        let full_path = work_tree.join(path);
+        // Anything still in the way is ignored, or has been checked to be overwritable, including
+        // files where the parent directories go
+        let mut dir = work_tree.to_path_buf();
+        let components = path.split('/').collect::<Vec<_>>();
+        for component in &components[..components.len() - 1] {
+            dir.push(component);
+            if dir.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+                fs::remove_file(&dir)?;
+            }
+        }
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match full_path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&full_path)?,
+            Ok(_) => fs::remove_file(&full_path)?,
+            Err(_) => {}
+        }
+        if is_gitlink(mode) {
+            // The commit is in the repository of the submodule, not this one
+            fs::create_dir(&full_path)?;
+            return Ok(Stat::from_metadata(&full_path.symlink_metadata()?));
+        }
+        let blob = self.get_object(oid)?;
+        match mode {
+            0o120000 => symlink(OsStr::from_bytes(&blob.content), &full_path)?,
+            _ => {
+                fs::write(&full_path, blob.content)?;
+                if mode == 0o100755 {
+                    let mut permissions = full_path.metadata()?.permissions();
+                    // Executable by whoever can read it
+                    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+                    fs::set_permissions(&full_path, permissions)?;
+                }
+            }
+        }
+        Ok(Stat::from_metadata(&full_path.symlink_metadata()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::tests::{index_paths, temp_repo};
+    use crate::Object;
+
+    /// Stage the whole work tree and commit it
+    fn commit_all(repo: &GitRepo, message: &str) -> ObjectId {
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        repo.add(&[dir]).unwrap();
+        repo.commit(message.to_string()).unwrap()
+    }
+
+    fn read(dir: &Path, path: &str) -> String {
+        fs::read_to_string(dir.join(path)).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_switch_branches() {
+        let repo = temp_repo("checkout-switch-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::create_dir(dir.join("dir")).unwrap();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::write(dir.join("dir/b"), "b\n").unwrap();
+        fs::write(dir.join("x"), "x\n").unwrap();
+        commit_all(&repo, "master");
+
+        let created = repo.checkout(None, Some("side"), false, false).unwrap();
+        // Replace the file `x` by a directory, and the directory `dir` by a file
+        fs::write(dir.join("a"), "a2\n").unwrap();
+        fs::remove_dir_all(dir.join("dir")).unwrap();
+        fs::write(dir.join("dir"), "dir\n").unwrap();
+        fs::remove_file(dir.join("x")).unwrap();
+        fs::create_dir(dir.join("x")).unwrap();
+        fs::write(dir.join("x/y"), "y\n").unwrap();
+        commit_all(&repo, "side");
+
+        let master = repo.checkout(Some("master"), None, false, false).unwrap();
+        let master_files = (read(&dir, "a"), read(&dir, "dir/b"), read(&dir, "x"));
+        let master_paths = index_paths(&repo);
+        let master_status = repo.status().unwrap();
+        let side = repo.switch(Some("side"), None, false, false).unwrap();
+        let side_files = (read(&dir, "a"), read(&dir, "dir"), read(&dir, "x/y"));
+        let side_paths = index_paths(&repo);
+        let side_status = repo.status().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(created, Head::Branch(name, _) if name == "refs/heads/side"));
+        assert!(matches!(master, Head::Branch(name, _) if name == "refs/heads/master"));
+        assert_eq!(master_files, ("a\n".into(), "b\n".into(), "x\n".into()));
+        assert_eq!(master_paths, ["a", "dir/b", "x"]);
+        assert!(master_status.entries.is_empty() && master_status.untracked.is_empty());
+        assert!(matches!(side, Head::Branch(name, _) if name == "refs/heads/side"));
+        assert_eq!(side_files, ("a2\n".into(), "dir\n".into(), "y\n".into()));
+        assert_eq!(side_paths, ["a", "dir", "x/y"]);
+        assert!(side_status.entries.is_empty() && side_status.untracked.is_empty());
+    }
+
+    #[test]
+    fn test_checkout_local_changes() {
+        let repo = temp_repo("checkout-changes-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::write(dir.join("b"), "b\n").unwrap();
+        commit_all(&repo, "master");
+        repo.checkout(None, Some("side"), false, false).unwrap();
+        fs::write(dir.join("a"), "a2\n").unwrap();
+        commit_all(&repo, "side");
+        repo.checkout(Some("master"), None, false, false).unwrap();
+
+        // `a` differs between the branches
+        fs::write(dir.join("a"), "local\n").unwrap();
+        let refused = repo.checkout(Some("side"), None, false, false);
+        let refused_head = repo.head().unwrap();
+        // `b` is the same in both, so its changes are carried over
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::write(dir.join("b"), "local\n").unwrap();
+        let carried = repo.checkout(Some("side"), None, false, false);
+        let carried_status = repo.status().unwrap();
+        let carried_files = (read(&dir, "a"), read(&dir, "b"));
+        // Forcing throws all the local changes away
+        fs::write(dir.join("a"), "local\n").unwrap();
+        let forced = repo.checkout(Some("master"), None, false, true);
+        let forced_files = (read(&dir, "a"), read(&dir, "b"));
+        let forced_status = repo.status().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = refused.unwrap_err().to_string();
+        assert!(error.contains("Your local changes"), "{error}");
+        assert!(error.contains("\ta\n"), "{error}");
+        assert!(matches!(refused_head, Head::Branch(name, _) if name == "refs/heads/master"));
+        assert!(carried.is_ok());
+        let paths = carried_status.entries.iter().map(|e| e.path.as_str());
+        assert_eq!(paths.collect::<Vec<_>>(), ["b"]);
+        assert_eq!(carried_status.entries[0].unstaged, Change::Modified);
+        assert_eq!(carried_files, ("a2\n".into(), "local\n".into()));
+        assert!(forced.is_ok());
+        assert_eq!(forced_files, ("a\n".into(), "b\n".into()));
+        assert!(forced_status.entries.is_empty());
+    }
+
+    #[test]
+    fn test_checkout_untracked() {
+        let repo = temp_repo("checkout-untracked-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        commit_all(&repo, "master");
+        repo.checkout(None, Some("side"), false, false).unwrap();
+        fs::create_dir(dir.join("c")).unwrap();
+        fs::write(dir.join("c/d"), "d\n").unwrap();
+        fs::write(dir.join("debug.log"), "side\n").unwrap();
+        repo.add(&[dir.join("c"), dir.join("debug.log")]).unwrap();
+        repo.commit("side".to_string()).unwrap();
+        repo.checkout(Some("master"), None, false, false).unwrap();
+        let cleaned = !dir.join("c").exists() && !dir.join("debug.log").exists();
+
+        // An untracked file where a tracked one goes, or where its directory goes
+        fs::create_dir(dir.join("c")).unwrap();
+        fs::write(dir.join("c/d"), "untracked\n").unwrap();
+        let over_file = repo.checkout(Some("side"), None, false, false);
+        fs::remove_dir_all(dir.join("c")).unwrap();
+        fs::write(dir.join("c"), "untracked\n").unwrap();
+        let over_dir = repo.checkout(Some("side"), None, false, false);
+        fs::remove_file(dir.join("c")).unwrap();
+        // Ignored files are expendable
+        fs::write(dir.join("debug.log"), "ignored\n").unwrap();
+        let ignored = repo.checkout(Some("side"), None, false, false);
+        let files = (read(&dir, "c/d"), read(&dir, "debug.log"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(cleaned);
+        let error = over_file.unwrap_err().to_string();
+        assert!(error.contains("untracked working tree files"), "{error}");
+        assert!(error.contains("\tc/d\n"), "{error}");
+        assert!(!error.contains("debug.log"), "{error}");
+        let error = over_dir.unwrap_err().to_string();
+        assert!(error.contains("untracked working tree files"), "{error}");
+        assert!(ignored.is_ok());
+        assert_eq!(files, ("d\n".into(), "side\n".into()));
+    }
+
+    #[test]
+    fn test_checkout_targets() {
+        let repo = temp_repo("checkout-targets-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        let commit = commit_all(&repo, "master");
+        let remote = RefValue::Direct(commit);
+        repo.refs
+            .write("refs/remotes/origin/feature", &remote)
+            .unwrap();
+
+        // A branch that only exists on a remote is created to track it
+        let feature = repo.checkout(Some("feature"), None, false, false).unwrap();
+        let config = repo.config().unwrap();
+        let upstream = (
+            config.get("branch.feature.remote").map(str::to_string),
+            config.get("branch.feature.merge").map(str::to_string),
+        );
+        let existing = repo.checkout(Some("HEAD"), Some("master"), false, false);
+        let not_branch = repo.switch(Some(&commit.to_string()), None, false, false);
+        let detached = repo.switch(Some("master"), None, true, false).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(feature, Head::Branch(name, _) if name == "refs/heads/feature"));
+        assert_eq!(upstream.0.as_deref(), Some("origin"));
+        assert_eq!(upstream.1.as_deref(), Some("refs/heads/feature"));
+        assert!(existing.unwrap_err().to_string().contains("already exists"));
+        let error = not_branch.unwrap_err().to_string();
+        assert!(error.contains("a branch is expected"), "{error}");
+        assert_eq!(detached, Head::Detached(commit));
+    }
+
+    #[test]
+    fn test_checkout_gitlinks_and_locks() {
+        let repo = temp_repo("checkout-gitlinks-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        let master = commit_all(&repo, "master");
+        repo.checkout(None, Some("side"), false, false).unwrap();
+        // A submodule that isn't checked out, whose commit isn't in the repository
+        fs::create_dir(dir.join("sub")).unwrap();
+        let mut index = repo.read_index().unwrap();
+        let submodule = Object::blob(b"submodule".to_vec()).id();
+        index.add(Entry::new("sub", 0o160000, submodule, Stat::default()));
+        repo.write_index(&index).unwrap();
+        repo.commit("side".to_string()).unwrap();
+
+        repo.checkout(Some("master"), None, false, false).unwrap();
+        let removed = dir.join("sub").exists();
+        repo.checkout(Some("side"), None, false, false).unwrap();
+        let created = dir.join("sub").is_dir();
+        let status = repo.status().unwrap();
+        // Nothing changes if HEAD can't be locked
+        let lock = repo.git_dir.join("HEAD.lock");
+        fs::write(&lock, "").unwrap();
+        let locked = repo.checkout(Some("master"), None, false, false);
+        let kept = dir.join("sub").is_dir();
+        let locked_branch = repo.checkout(Some("master"), Some("new"), false, false);
+        let new_branch = repo.refs.read("refs/heads/new").unwrap();
+        fs::remove_file(&lock).unwrap();
+        let unlocked = repo.checkout(Some(&master.to_string()), None, false, false);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!removed);
+        assert!(created);
+        assert!(status.entries.is_empty() && status.untracked.is_empty());
+        let error = format!("{:#}", locked.unwrap_err());
+        assert!(error.contains("Cannot lock ref 'HEAD'"), "{error}");
+        assert!(kept);
+        assert!(locked_branch.is_err());
+        assert_eq!(new_branch, None);
+        assert_eq!(unlocked.unwrap(), Head::Detached(master));
+    }
+}
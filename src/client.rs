@@ -76,16 +76,22 @@ impl GitClient {
         Ok((capabilities_set, advertised))
     }
 
-    pub fn request_pack(&self, oid: ObjectId) -> Result<Bytes> {
+    pub fn request_pack(&self, wants: &[ObjectId]) -> Result<Bytes> {
         // TODO: implement protocol v2
-        let msg = vec![
-            // capabilities: include 'side-band-64k' to get progress info, but don't include
-            // 'ofs_delta' to simplify things.
-            // TODO: support `ofs_delta`
-            Pkt::data(format!("want {oid} multi_ack side-band-64k\n")),
-            Pkt::Flush,
-            Pkt::data("done\n"),
-        ];
+        let mut msg = Vec::new();
+        for (i, oid) in wants.iter().enumerate() {
+            if i == 0 {
+                // capabilities: include 'side-band-64k' to get progress info, and 'ofs-delta' to
+                // get smaller packs.
+                msg.push(Pkt::data(format!(
+                    "want {oid} multi_ack side-band-64k ofs-delta\n"
+                )));
+            } else {
+                msg.push(Pkt::data(format!("want {oid}\n")));
+            }
+        }
+        msg.push(Pkt::Flush);
+        msg.push(Pkt::data("done\n"));
 
         let mut buf = BytesMut::new();
         for pkt in msg {
@@ -0,0 +1,993 @@
+//! Module to read and write git config files
+//!
+//! Values are read from all the config files ([Config]), and written to a single one
+//! ([ConfigFile]) while preserving its formatting and comments.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::refs::LockFile;
+
+/// Maximum depth of nested includes, to protect against include loops
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Where a config value comes from, from lowest to highest precedence
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigScope {
+    /// `/etc/gitconfig`
+    System,
+    /// `~/.gitconfig` and `~/.config/git/config`
+    Global,
+    /// `.git/config`
+    Local,
+    /// `GIT_CONFIG_COUNT`, `GIT_CONFIG_KEY_<n>` and `GIT_CONFIG_VALUE_<n>`
+    Env,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    /// Section name, in lowercase
+    pub section: String,
+    /// Subsection name, which is case sensitive
+    pub subsection: Option<String>,
+    /// Key name, in lowercase
+    pub key: String,
+    /// Value, or `None` for a key without `=`, which is a true boolean
+    pub value: Option<String>,
+    pub scope: ConfigScope,
+}
+
+impl ConfigEntry {
+    /// Full name of the entry, e.g `remote.origin.url`
+    pub fn name(&self) -> String {
+        match &self.subsection {
+            Some(subsection) => format!("{}.{subsection}.{}", self.section, self.key),
+            None => format!("{}.{}", self.section, self.key),
+        }
+    }
+
+    fn matches(&self, section: &str, subsection: Option<&str>, key: &str) -> bool {
+        self.section == section && self.subsection.as_deref() == subsection && self.key == key
+    }
+}
+
+/// Config values from all the config files, in order of increasing precedence
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    /// Load the system, global and environment config, and the config of the repo in `git_dir`
+    /// if there is one.
+    pub fn load(git_dir: Option<&Path>) -> Result<Self> {
+        let mut config = Self::default();
+
+        if !env_bool("GIT_CONFIG_NOSYSTEM") {
+            let system = env::var_os("GIT_CONFIG_SYSTEM")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("/etc/gitconfig"));
+            config.add_file(&system, ConfigScope::System, git_dir, 0)?;
+        }
+        for global in global_config_files() {
+            config.add_file(&global, ConfigScope::Global, git_dir, 0)?;
+        }
+        if let Some(git_dir) = git_dir {
+            config.add_file(
+                &crate::common_dir(git_dir).join("config"),
+                ConfigScope::Local,
+                Some(git_dir),
+                0,
+            )?;
+        }
+        config.add_env()?;
+
+        Ok(config)
+    }
+
+    /// Load a single config file
+    pub fn from_file(path: &Path, scope: ConfigScope) -> Result<Self> {
+        let mut config = Self::default();
+        config.add_file(path, scope, None, 0)?;
+        Ok(config)
+    }
+
+    /// Last value of the given key, e.g `user.name`. A key without value is an empty string.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_entries(name)
+            .last()
+            .map(|e| e.value.as_deref().unwrap_or_default())
+    }
+
+    /// All the values of a multi-valued key, in order of precedence
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.get_entries(name)
+            .iter()
+            .map(|e| e.value.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>> {
+        let Some(entry) = self.get_entries(name).last().copied() else {
+            return Ok(None);
+        };
+        match &entry.value {
+            None => Ok(Some(true)),
+            Some(value) => parse_bool(value)
+                .map(Some)
+                .ok_or_else(|| anyhow!("Bad boolean config value '{value}' for '{name}'")),
+        }
+    }
+
+    /// Integer value, with an optional `k`, `m` or `g` suffix
+    pub fn get_int(&self, name: &str) -> Result<Option<i64>> {
+        let Some(value) = self.get(name) else {
+            return Ok(None);
+        };
+        parse_int(value)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Bad numeric config value '{value}' for '{name}'"))
+    }
+
+    /// Path value, where a leading `~/` is the home directory
+    pub fn get_path(&self, name: &str) -> Option<PathBuf> {
+        self.get(name).map(expand_path)
+    }
+
+    fn get_entries(&self, name: &str) -> Vec<&ConfigEntry> {
+        let Ok((section, subsection, key)) = split_name(name) else {
+            return Vec::new();
+        };
+        self.entries
+            .iter()
+            .filter(|e| e.matches(&section, subsection.as_deref(), &key))
+            .collect()
+    }
+
+    fn add_file(
+        &mut self,
+        path: &Path,
+        scope: ConfigScope,
+        git_dir: Option<&Path>,
+        depth: usize,
+    ) -> Result<()> {
+        ensure!(
+            depth <= MAX_INCLUDE_DEPTH,
+            "Exceeded maximum include depth while including {}",
+            path.display()
+        );
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(format!("Reading {}", path.display())),
+        };
+        let events = parse(&text).with_context(|| format!("In {}", path.display()))?;
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for entry in entries(events) {
+            let include = match (entry.section.as_str(), &entry.subsection, &entry.value) {
+                ("include", None, Some(path)) if entry.key == "path" => Some(path),
+                ("includeif", Some(condition), Some(path)) if entry.key == "path" => {
+                    include_condition(condition, dir, git_dir).then_some(path)
+                }
+                _ => None,
+            };
+            if let Some(include) = include {
+                // Relative paths are relative to the including file
+                let include = dir.join(expand_path(include));
+                self.add_file(&include, scope, git_dir, depth + 1)?;
+            }
+            self.entries.push(ConfigEntry { scope, ..entry });
+        }
+
+        Ok(())
+    }
+
+    fn add_env(&mut self) -> Result<()> {
+        let Ok(count) = env::var("GIT_CONFIG_COUNT") else {
+            return Ok(());
+        };
+        let count: usize = count
+            .parse()
+            .map_err(|_| anyhow!("Bogus count in GIT_CONFIG_COUNT"))?;
+        for i in 0..count {
+            let name = env::var(format!("GIT_CONFIG_KEY_{i}"))
+                .map_err(|_| anyhow!("Missing config key GIT_CONFIG_KEY_{i}"))?;
+            let value = env::var(format!("GIT_CONFIG_VALUE_{i}"))
+                .map_err(|_| anyhow!("Missing config value GIT_CONFIG_VALUE_{i}"))?;
+            let (section, subsection, key) = split_name(&name)?;
+            self.entries.push(ConfigEntry {
+                section,
+                subsection,
+                key,
+                value: Some(value),
+                scope: ConfigScope::Env,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Global config files, in the order they're read
+pub fn global_config_files() -> Vec<PathBuf> {
+    if let Some(global) = env::var_os("GIT_CONFIG_GLOBAL") {
+        return vec![global.into()];
+    }
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    xdg.map(|xdg| xdg.join("git/config"))
+        .into_iter()
+        .chain(home.map(|home| home.join(".gitconfig")))
+        .collect()
+}
+
+/// File to write to for the given scope. For the local scope, `git_dir` is the repo.
+pub fn config_file_path(scope: ConfigScope, git_dir: &Path) -> Result<PathBuf> {
+    match scope {
+        ConfigScope::System => Ok(env::var_os("GIT_CONFIG_SYSTEM")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/etc/gitconfig"))),
+        ConfigScope::Global => {
+            // Prefer `~/.gitconfig`, unless only the XDG file exists
+            let files = global_config_files();
+            let file = files
+                .iter()
+                .rev()
+                .find(|f| f.exists())
+                .or(files.last())
+                .ok_or_else(|| anyhow!("No global config file: $HOME is not set"))?;
+            Ok(file.clone())
+        }
+        ConfigScope::Local => Ok(crate::common_dir(git_dir).join("config")),
+        ConfigScope::Env => bail!("Can't write to the environment"),
+    }
+}
+
+/// A single config file, which can be modified while preserving its formatting and comments
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    text: String,
+}
+
+impl ConfigFile {
+    /// Open a config file, which doesn't need to exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(format!("Reading {}", path.display())),
+        };
+        parse(&text).with_context(|| format!("In {}", path.display()))?;
+        Ok(Self { path, text })
+    }
+
+    /// Set the value of a key, replacing its last value if it's already set
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let (section, subsection, key) = split_name(name)?;
+        let original_key = name.rsplit('.').next().unwrap_or(&key);
+        let spans = self.find_entries(&section, subsection.as_deref(), &key);
+        match spans.last() {
+            Some(span) => {
+                // The span starts after the indentation of the line, which is kept
+                let line = format_entry(original_key, value);
+                self.text
+                    .replace_range(span.clone(), line.trim_start_matches('\t'));
+                Ok(())
+            }
+            None => self.add(name, value),
+        }
+    }
+
+    /// Add a value to a key, keeping the existing ones
+    pub fn add(&mut self, name: &str, value: &str) -> Result<()> {
+        let (section, subsection, key) = split_name(name)?;
+        let original_key = name.rsplit('.').next().unwrap_or(&key);
+        let line = format_entry(original_key, value);
+
+        // Add the value at the end of the last matching section
+        let mut end = None;
+        let mut in_section = false;
+        for event in parse(&self.text)? {
+            match event.kind {
+                EventKind::Section {
+                    section: s,
+                    subsection: sub,
+                } => {
+                    in_section = s == section && sub == subsection;
+                    if in_section {
+                        end = Some(line_end(&self.text, event.span.end));
+                    }
+                }
+                EventKind::Entry { .. } if in_section => end = Some(event.span.end),
+                EventKind::Entry { .. } => {}
+            }
+        }
+
+        match end {
+            Some(end) => {
+                let prefix = if self.text[..end].ends_with('\n') {
+                    ""
+                } else {
+                    "\n"
+                };
+                self.text.insert_str(end, &format!("{prefix}{line}"));
+            }
+            None => {
+                if !self.text.is_empty() && !self.text.ends_with('\n') {
+                    self.text.push('\n');
+                }
+                self.text
+                    .push_str(&format_section(&section, subsection.as_deref()));
+                self.text.push('\n');
+                self.text.push_str(&line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the value of a key. Fails if it has several values.
+    pub fn unset(&mut self, name: &str) -> Result<bool> {
+        let (section, subsection, key) = split_name(name)?;
+        let spans = self.find_entries(&section, subsection.as_deref(), &key);
+        ensure!(spans.len() <= 1, "{name} has multiple values");
+        let found = !spans.is_empty();
+        self.remove_spans(spans);
+        Ok(found)
+    }
+
+    /// Remove all the values of a key, and return how many there were
+    pub fn unset_all(&mut self, name: &str) -> Result<usize> {
+        let (section, subsection, key) = split_name(name)?;
+        let spans = self.find_entries(&section, subsection.as_deref(), &key);
+        let count = spans.len();
+        self.remove_spans(spans);
+        Ok(count)
+    }
+
+    /// Remove a section and all its keys, e.g `branch.main`
+    pub fn remove_section(&mut self, name: &str) -> Result<bool> {
+        let (section, subsection) = split_section(name)?;
+        let mut spans = Vec::new();
+        let mut current: Option<Range<usize>> = None;
+        for event in parse(&self.text)? {
+            match event.kind {
+                EventKind::Section {
+                    section: s,
+                    subsection: sub,
+                } => {
+                    spans.extend(current.take());
+                    if s == section && sub == subsection {
+                        current = Some(event.span.start..line_end(&self.text, event.span.end));
+                    }
+                }
+                EventKind::Entry { .. } => {
+                    if let Some(current) = &mut current {
+                        current.end = event.span.end;
+                    }
+                }
+            }
+        }
+        spans.extend(current);
+        let found = !spans.is_empty();
+        self.remove_spans(spans);
+        Ok(found)
+    }
+
+    /// Rename a section, e.g from `branch.main` to `branch.trunk`
+    pub fn rename_section(&mut self, old_name: &str, new_name: &str) -> Result<bool> {
+        let (section, subsection) = split_section(old_name)?;
+        let (new_section, new_subsection) = split_section(new_name)?;
+        let header = format_section(&new_section, new_subsection.as_deref());
+        let mut spans = Vec::new();
+        for event in parse(&self.text)? {
+            if let EventKind::Section {
+                section: s,
+                subsection: sub,
+            } = event.kind
+            {
+                if s == section && sub == subsection {
+                    spans.push(event.span);
+                }
+            }
+        }
+        let found = !spans.is_empty();
+        for span in spans.into_iter().rev() {
+            self.text.replace_range(span, &header);
+        }
+        Ok(found)
+    }
+
+    /// Write the file, going through a lock file so that readers never see a partial file
+    pub fn write(&self) -> Result<()> {
+        let mut lock = LockFile::acquire(&self.path)
+            .with_context(|| format!("Unable to lock {}", self.path.display()))?;
+        lock.write(self.text.as_bytes())?;
+        lock.commit()?;
+        Ok(())
+    }
+
+    /// Spans of the entries for the given key
+    fn find_entries(
+        &self,
+        section: &str,
+        subsection: Option<&str>,
+        key: &str,
+    ) -> Vec<Range<usize>> {
+        let events = parse(&self.text).expect("config to have been parsed already");
+        let mut spans = Vec::new();
+        let mut in_section = false;
+        for event in events {
+            match event.kind {
+                EventKind::Section {
+                    section: s,
+                    subsection: sub,
+                } => in_section = s == section && sub.as_deref() == subsection,
+                EventKind::Entry { key: k, .. } if in_section && k == key => spans.push(event.span),
+                EventKind::Entry { .. } => {}
+            }
+        }
+        spans
+    }
+
+    fn remove_spans(&mut self, spans: Vec<Range<usize>>) {
+        for span in spans.into_iter().rev() {
+            // Remove the indentation as well
+            let start = self.text[..span.start].trim_end_matches([' ', '\t']).len();
+            let start = if start == 0 || self.text[..start].ends_with('\n') {
+                start
+            } else {
+                span.start
+            };
+            self.text.replace_range(start..span.end, "");
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Split a key name like `remote.origin.url` into section, subsection and key. The section and
+/// key are lowercased.
+fn split_name(name: &str) -> Result<(String, Option<String>, String)> {
+    let (section, key) = name
+        .rsplit_once('.')
+        .ok_or_else(|| anyhow!("Key does not contain a section: {name}"))?;
+    ensure!(
+        key.starts_with(|c: char| c.is_ascii_alphabetic())
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+        "Invalid key: {name}"
+    );
+    let (section, subsection) = split_section(section)?;
+    Ok((section, subsection, key.to_ascii_lowercase()))
+}
+
+/// Split a section name like `remote.origin` into section and subsection
+fn split_section(name: &str) -> Result<(String, Option<String>)> {
+    let (section, subsection) = match name.split_once('.') {
+        Some((section, subsection)) => (section, Some(subsection.to_string())),
+        None => (name, None),
+    };
+    ensure!(
+        !section.is_empty()
+            && section
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-'),
+        "Invalid section name: {name}"
+    );
+    Ok((section.to_ascii_lowercase(), subsection))
+}
+
+fn format_section(section: &str, subsection: Option<&str>) -> String {
+    match subsection {
+        Some(subsection) => {
+            let escaped = subsection.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("[{section} \"{escaped}\"]")
+        }
+        None => format!("[{section}]"),
+    }
+}
+
+fn format_entry(key: &str, value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    let needs_quotes = value.starts_with(' ') || value.ends_with(' ') || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\t{key} = \"{escaped}\"\n")
+    } else {
+        format!("\t{key} = {escaped}\n")
+    }
+}
+
+/// Position right after the end of the line containing `pos`
+fn line_end(text: &str, pos: usize) -> usize {
+    text[pos..].find('\n').map_or(text.len(), |i| pos + i + 1)
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        value => parse_int(value).map(|i| i != 0),
+    }
+}
+
+pub fn parse_int(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (digits, factor) = match value.chars().last()?.to_ascii_lowercase() {
+        'k' => (&value[..value.len() - 1], 1 << 10),
+        'm' => (&value[..value.len() - 1], 1 << 20),
+        'g' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<i64>().ok()?.checked_mul(factor)
+}
+
+/// Expand a leading `~/` to the home directory
+fn expand_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn env_bool(name: &str) -> bool {
+    env::var(name).is_ok_and(|v| parse_bool(&v) == Some(true))
+}
+
+/// Whether the condition of an `includeIf` section is true. `dir` is the directory of the
+/// config file.
+fn include_condition(condition: &str, dir: &Path, git_dir: Option<&Path>) -> bool {
+    let Some(git_dir) = git_dir else {
+        return false;
+    };
+    let (pattern, ignore_case) = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+        (pattern, false)
+    } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+        (pattern, true)
+    } else if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let Ok(head) = fs::read_to_string(git_dir.join("HEAD")) else {
+            return false;
+        };
+        let Some(branch) = head.trim().strip_prefix("ref: refs/heads/") else {
+            return false;
+        };
+        let pattern = if pattern.ends_with('/') {
+            format!("{pattern}**")
+        } else {
+            pattern.to_string()
+        };
+        return glob_match(&pattern, branch, false);
+    } else {
+        return false;
+    };
+
+    let mut pattern = if let Some(rest) = pattern.strip_prefix("./") {
+        format!("{}/{rest}", dir.display())
+    } else {
+        expand_path(pattern).to_string_lossy().into_owned()
+    };
+    if !pattern.starts_with('/') {
+        pattern = format!("**/{pattern}");
+    }
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+
+    let canonical = git_dir.canonicalize().ok();
+    [Some(git_dir.to_owned()), canonical]
+        .into_iter()
+        .flatten()
+        .any(|dir| glob_match(&pattern, &dir.to_string_lossy(), ignore_case))
+}
+
+/// Match a path against a glob pattern, where `*` and `?` don't match `/`, but `**` does
+pub fn glob_match(pattern: &str, text: &str, ignore_case: bool) -> bool {
+    let (pattern, text) = if ignore_case {
+        (pattern.to_lowercase(), text.to_lowercase())
+    } else {
+        (pattern.to_string(), text.to_string())
+    };
+    match_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn match_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            let rest_after_slash = rest.strip_prefix(b"/");
+            (0..=text.len()).any(|i| match_bytes(rest, &text[i..]))
+                || rest_after_slash.is_some_and(|r| match_bytes(r, text))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|i| *i == 0 || text[i - 1] != b'/')
+            .any(|i| match_bytes(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(c) if *c != b'/') && match_bytes(rest, &text[1..])
+        }
+        [b'[', rest @ ..] => {
+            let Some(end) = rest.iter().skip(1).position(|c| *c == b']').map(|i| i + 1) else {
+                return text.first() == Some(&b'[') && match_bytes(rest, &text[1..]);
+            };
+            let Some(&c) = text.first() else {
+                return false;
+            };
+            let (negate, class) = match &rest[..end] {
+                [b'!' | b'^', class @ ..] => (true, class),
+                class => (false, class),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            found != negate && c != b'/' && match_bytes(&rest[end + 1..], &text[1..])
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            text.first() == Some(c) && match_bytes(rest, &text[1..])
+        }
+    }
+}
+
+/// A section header or a key/value pair in a config file, along with its position
+struct Event {
+    kind: EventKind,
+    /// For a section, the header between `[` and `]`. For an entry, the key and value up to the
+    /// end of the line.
+    span: Range<usize>,
+}
+
+enum EventKind {
+    Section {
+        section: String,
+        subsection: Option<String>,
+    },
+    Entry {
+        key: String,
+        value: Option<String>,
+    },
+}
+
+/// Turn the events of a file into entries
+fn entries(events: Vec<Event>) -> Vec<ConfigEntry> {
+    let mut entries = Vec::new();
+    let mut current = None;
+    for event in events {
+        match event.kind {
+            EventKind::Section {
+                section,
+                subsection,
+            } => current = Some((section, subsection)),
+            EventKind::Entry { key, value } => {
+                // `parse` makes sure entries are in a section
+                let (section, subsection) = current.clone().expect("entry to be in a section");
+                entries.push(ConfigEntry {
+                    section,
+                    subsection,
+                    key,
+                    value,
+                    scope: ConfigScope::Local,
+                });
+            }
+        }
+    }
+    entries
+}
+
+fn parse(text: &str) -> Result<Vec<Event>> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    if text.starts_with('\u{feff}') {
+        parser.pos = 3;
+    }
+
+    let mut events = Vec::new();
+    let mut in_section = false;
+    loop {
+        parser.skip_blanks();
+        let start = parser.pos;
+        let kind = match parser.peek() {
+            None => break,
+            Some(b'\n') => {
+                parser.next();
+                continue;
+            }
+            Some(b'#' | b';') => {
+                parser.skip_line();
+                continue;
+            }
+            Some(b'[') => {
+                in_section = true;
+                parser.section_header()
+            }
+            Some(c) if c.is_ascii_alphabetic() && in_section => parser.entry(),
+            _ => None,
+        };
+        let Some(kind) = kind else {
+            bail!("Bad config line {}", parser.line);
+        };
+        events.push(Event {
+            kind,
+            span: start..parser.pos,
+        });
+    }
+    Ok(events)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn name(&mut self, extra: &[u8]) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'-' || extra.contains(&c))
+        {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.text[start..self.pos]).to_ascii_lowercase()
+    }
+
+    /// `[section]`, `[section "subsection"]` or the deprecated `[section.subsection]`
+    fn section_header(&mut self) -> Option<EventKind> {
+        self.next();
+        let name = self.name(b".");
+        if name.is_empty() {
+            return None;
+        }
+        if self.peek() == Some(b']') {
+            self.next();
+            let (section, subsection) = match name.split_once('.') {
+                Some((section, subsection)) => (section.to_string(), Some(subsection.to_string())),
+                None => (name, None),
+            };
+            return Some(EventKind::Section {
+                section,
+                subsection,
+            });
+        }
+        if name.contains('.') {
+            return None;
+        }
+
+        self.skip_blanks();
+        if self.next()? != b'"' {
+            return None;
+        }
+        let mut subsection = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\n' => return None,
+                b'\\' => subsection.push(self.next()?),
+                c => subsection.push(c),
+            }
+        }
+        if self.next()? != b']' {
+            return None;
+        }
+        Some(EventKind::Section {
+            section: name,
+            subsection: Some(String::from_utf8_lossy(&subsection).into_owned()),
+        })
+    }
+
+    /// `key = value`, or just `key`
+    fn entry(&mut self) -> Option<EventKind> {
+        let key = self.name(b"");
+        self.skip_blanks();
+        let value = match self.peek() {
+            None => None,
+            Some(b'\n') => {
+                self.next();
+                None
+            }
+            Some(b'#' | b';') => {
+                self.skip_line();
+                None
+            }
+            Some(b'=') => {
+                self.next();
+                Some(self.value()?)
+            }
+            Some(_) => return None,
+        };
+        Some(EventKind::Entry { key, value })
+    }
+
+    /// Value up to the end of the line, with quotes and escapes removed, and leading and trailing
+    /// whitespace trimmed
+    fn value(&mut self) -> Option<String> {
+        self.skip_blanks();
+        let mut value = Vec::new();
+        let mut quoted = false;
+        let mut spaces = 0;
+        loop {
+            let Some(c) = self.next() else {
+                if quoted {
+                    return None;
+                }
+                break;
+            };
+            match c {
+                b'\n' if quoted => return None,
+                b'\n' => break,
+                b' ' | b'\t' | b'\r' if !quoted => {
+                    if !value.is_empty() {
+                        spaces += 1;
+                    }
+                    continue;
+                }
+                b'#' | b';' if !quoted => {
+                    self.skip_line();
+                    break;
+                }
+                _ => {}
+            }
+
+            value.extend(std::iter::repeat_n(b' ', spaces));
+            spaces = 0;
+            match c {
+                b'"' => quoted = !quoted,
+                b'\\' => match self.next()? {
+                    // line continuation
+                    b'\n' => {}
+                    b'n' => value.push(b'\n'),
+                    b't' => value.push(b'\t'),
+                    b'b' => value.push(0x08),
+                    c @ (b'\\' | b'"') => value.push(c),
+                    _ => return None,
+                },
+                c => value.push(c),
+            }
+        }
+        Some(String::from_utf8_lossy(&value).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"# comment
+[core]
+	bare = false
+	; another comment
+	filemode
+[remote "origin"]
+	url = https://example.com/repo.git  # trailing comment
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+[Branch "Main"]
+	merge = "refs/heads/ma\"in" \
+continued
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config {
+            entries: entries(parse(SAMPLE).unwrap()),
+        };
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+        assert_eq!(config.get_bool("core.filemode").unwrap(), Some(true));
+        assert_eq!(
+            config.get("remote.origin.url"),
+            Some("https://example.com/repo.git")
+        );
+        assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+        assert_eq!(
+            config.get("branch.Main.merge"),
+            Some("refs/heads/ma\"in continued")
+        );
+        assert_eq!(config.get("branch.main.merge"), None);
+        assert!(parse("[core]\nkey = \"unterminated\n").is_err());
+        assert_eq!(parse_int("2k"), Some(2048));
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut file = ConfigFile {
+            path: PathBuf::new(),
+            text: SAMPLE.to_string(),
+        };
+        file.set("core.bare", "true").unwrap();
+        file.add("remote.origin.fetch", "+refs/notes/*:refs/notes/*")
+            .unwrap();
+        assert_eq!(file.unset_all("remote.origin.url").unwrap(), 1);
+        file.set("user.name", "A # B").unwrap();
+        file.rename_section("branch.Main", "branch.trunk").unwrap();
+        assert!(file.remove_section("core").unwrap());
+
+        assert_eq!(
+            file.to_string(),
+            r#"# comment
+[remote "origin"]
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+	fetch = +refs/notes/*:refs/notes/*
+[branch "trunk"]
+	merge = "refs/heads/ma\"in" \
+continued
+[user]
+	name = "A # B"
+"#
+        );
+    }
+
+    #[test]
+    fn test_set_keeps_layout() {
+        let mut file = ConfigFile {
+            path: PathBuf::new(),
+            text: SAMPLE.to_string(),
+        };
+        file.set("core.bare", "true").unwrap();
+        file.set("remote.origin.fetch", "+refs/*:refs/*").unwrap();
+
+        let expected = SAMPLE
+            .replace("bare = false", "bare = true")
+            .replace("fetch = +refs/tags/*:refs/tags/*", "fetch = +refs/*:refs/*");
+        assert_eq!(file.to_string(), expected);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("**/work/**", "/home/me/work/repo/.git", false));
+        assert!(glob_match("/home/*/repo/.git", "/home/me/repo/.git", false));
+        assert!(!glob_match("/home/*/.git", "/home/me/repo/.git", false));
+        assert!(glob_match("feature/[a-c]?", "feature/b1", false));
+        assert!(glob_match("**/REPO/.git", "/x/repo/.git", true));
+    }
+}
@@ -0,0 +1,547 @@
+//! Module to parse dates and find the offset of the local timezone
+//!
+//! Timezone offsets are in minutes east of UTC, as in [Signature](crate::Signature).
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Buf;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Parse a date as accepted in `GIT_AUTHOR_DATE` and `GIT_COMMITTER_DATE`, and return the
+/// timestamp along with the timezone offset.
+///
+/// Supported formats are git's internal format (`<timestamp> <+hhmm>`, optionally prefixed by
+/// `@`), ISO 8601 (`2005-04-07T22:13:13+02:00`) and RFC 2822 (`Thu, 07 Apr 2005 22:13:13 +0200`).
+/// Dates without a timezone are in local time.
+pub fn parse_date(date: &str) -> Result<(i64, i32)> {
+    let date = date.trim();
+    parse_raw(date)
+        .or_else(|| parse_iso8601(date))
+        .or_else(|| parse_rfc2822(date))
+        .ok_or_else(|| anyhow!("Invalid date format: {date}"))
+}
+
+/// Parse an expiry date such as `90.days.ago`, `2 weeks ago`, `now`, `all` or `never`, or any
+/// date accepted by [`parse_date`]. Returns the timestamp before which things expire.
+pub fn parse_expiry(date: &str, now: i64) -> Result<i64> {
+    let date = date.trim();
+    match date {
+        "now" => return Ok(now),
+        "all" => return Ok(i64::MAX),
+        "never" | "false" => return Ok(0),
+        _ => {}
+    }
+
+    let words = date.split(['.', ' ']).collect::<Vec<_>>();
+    if let [count, unit, "ago"] = words[..] {
+        let count: i64 = count
+            .parse()
+            .with_context(|| format!("Invalid expiry date: {date}"))?;
+        let seconds = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 3600,
+            "day" => 86400,
+            "week" => 7 * 86400,
+            "month" => 30 * 86400,
+            "year" => 365 * 86400,
+            _ => bail!("Invalid expiry date: {date}"),
+        };
+        return Ok(now - count * seconds);
+    }
+
+    Ok(parse_date(date)?.0)
+}
+
+/// Format a date like git does by default, e.g `Thu Apr 7 22:13:13 2005 +0200`
+pub fn format_date(timestamp: i64, tz_offset: i32) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let local = timestamp + tz_offset as i64 * 60;
+    let days = local.div_euclid(86400);
+    let seconds = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let month = MONTHS[month as usize - 1];
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[(days + 4).rem_euclid(7) as usize];
+    let sign = if tz_offset < 0 { '-' } else { '+' };
+    format!(
+        "{weekday} {}{} {day} {:02}:{:02}:{:02} {year} {sign}{:02}{:02}",
+        month[..1].to_uppercase(),
+        &month[1..],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        tz_offset.abs() / 60,
+        tz_offset.abs() % 60,
+    )
+}
+
+/// Offset of the local timezone at the given time, according to `TZ` or `/etc/localtime`.
+/// Defaults to UTC if the timezone can't be determined.
+pub fn local_tz_offset(timestamp: i64) -> i32 {
+    let tz = env::var("TZ").ok();
+    let tz = tz.as_deref().map(|tz| tz.strip_prefix(':').unwrap_or(tz));
+    let offset = match tz {
+        None | Some("") => tzif_offset(Path::new("/etc/localtime"), timestamp).ok(),
+        Some(tz) if tz.starts_with('/') => tzif_offset(Path::new(tz), timestamp).ok(),
+        Some(tz) => {
+            let path = Path::new("/usr/share/zoneinfo").join(tz);
+            if !tz.contains("..") && path.is_file() {
+                tzif_offset(&path, timestamp).ok()
+            } else {
+                PosixTz::parse(tz).map(|tz| tz.offset_at(timestamp))
+            }
+        }
+    };
+
+    offset.unwrap_or(0) / 60
+}
+
+/// `<timestamp> [<+hhmm>]`, optionally prefixed by `@`
+fn parse_raw(date: &str) -> Option<(i64, i32)> {
+    let date = date.strip_prefix('@').unwrap_or(date);
+    let (timestamp, tz) = match date.split_once(' ') {
+        Some((timestamp, tz)) => (timestamp, Some(parse_tz(tz.trim())?)),
+        None => (date, None),
+    };
+    if !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let timestamp = timestamp.parse().ok()?;
+    Some((timestamp, tz.unwrap_or_else(|| local_tz_offset(timestamp))))
+}
+
+/// `YYYY-MM-DD[T ]hh:mm[:ss[.fff]][Z|<+hh:mm>|<+hhmm>]`
+fn parse_iso8601(date: &str) -> Option<(i64, i32)> {
+    let (day, time) = date.split_once(['T', ' '])?;
+    let mut parts = day.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let (time, tz) = if let Some(time) = time.strip_suffix('Z') {
+        (time, Some(0))
+    } else if let Some(idx) = time.find(['+', '-']) {
+        (
+            time[..idx].trim(),
+            Some(parse_tz(&time[idx..].replace(':', ""))?),
+        )
+    } else if let Some((time, tz)) = time.split_once(' ') {
+        (time, Some(parse_tz(tz)?))
+    } else {
+        (time, None)
+    };
+    // Ignore fractions of seconds
+    let time = time.split('.').next()?;
+    let seconds = parse_time(time)?;
+
+    make_timestamp(year, month, day, seconds, tz)
+}
+
+/// `[Thu, ]07 Apr 2005 22:13:13 +0200`
+fn parse_rfc2822(date: &str) -> Option<(i64, i32)> {
+    let date = match date.split_once(',') {
+        Some((_weekday, rest)) => rest.trim(),
+        None => date,
+    };
+    let mut parts = date.split_whitespace();
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? as u32 + 1;
+    let year = parts.next()?.parse().ok()?;
+    let seconds = parse_time(parts.next()?)?;
+    let tz = match parts.next() {
+        Some(tz) => Some(parse_tz(tz)?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    make_timestamp(year, month, day, seconds, tz)
+}
+
+/// `hh:mm[:ss]`, as a number of seconds
+fn parse_time(time: &str) -> Option<i64> {
+    let mut parts = time.split(':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: i64 = match parts.next() {
+        Some(s) => s.parse().ok()?,
+        None => 0,
+    };
+    if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// `+hhmm` or `-hhmm`, as minutes
+fn parse_tz(tz: &str) -> Option<i32> {
+    let sign = match tz.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits = &tz[1..];
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// Convert a date and time to a timestamp. If there is no timezone, the time is in local time.
+fn make_timestamp(
+    year: i64,
+    month: u32,
+    day: u32,
+    seconds: i64,
+    tz: Option<i32>,
+) -> Option<(i64, i32)> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let local = days_from_civil(year, month, day) * 86400 + seconds;
+    let tz = tz.unwrap_or_else(|| local_tz_offset(local));
+    Some((local - tz as i64 * 60, tz))
+}
+
+/// Number of days since 1970-01-01 of the given date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date (year, month, day) of the given number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    // January and February belong to the next year
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Offset in seconds at the given time, according to a TZif file (see `man 5 tzfile`)
+fn tzif_offset(path: &Path, timestamp: i64) -> Result<i32> {
+    let data = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut buf = &data[..];
+
+    let (mut counts, version) = read_tzif_header(&mut buf)?;
+    let mut time_size = 4;
+    if version >= b'2' {
+        // Skip the version 1 data, and use the 64-bit data that follows
+        buf.advance(counts.data_len(4));
+        (counts, _) = read_tzif_header(&mut buf)?;
+        time_size = 8;
+    }
+    ensure!(
+        buf.remaining() >= counts.data_len(time_size),
+        "Truncated TZif file"
+    );
+
+    let transitions = (0..counts.time)
+        .map(|_| {
+            if time_size == 8 {
+                buf.get_i64()
+            } else {
+                buf.get_i32() as i64
+            }
+        })
+        .collect::<Vec<_>>();
+    let types = (0..counts.time).map(|_| buf.get_u8()).collect::<Vec<_>>();
+    let offsets = (0..counts.types)
+        .map(|_| {
+            let utoff = buf.get_i32();
+            buf.advance(2);
+            utoff
+        })
+        .collect::<Vec<_>>();
+    buf.advance(counts.data_len(time_size) - counts.time * (time_size + 1) - counts.types * 6);
+    // The footer describes the timezone after the last transition
+    let footer = if version >= b'2' {
+        std::str::from_utf8(buf)
+            .ok()
+            .and_then(|f| PosixTz::parse(f.trim()))
+    } else {
+        None
+    };
+
+    let idx = transitions.partition_point(|t| *t <= timestamp);
+    if idx == transitions.len() {
+        if let Some(tz) = footer {
+            return Ok(tz.offset_at(timestamp));
+        }
+    }
+    let ttype = match idx {
+        0 => 0,
+        idx => types[idx - 1] as usize,
+    };
+    offsets
+        .get(ttype)
+        .copied()
+        .ok_or_else(|| anyhow!("Invalid TZif file"))
+}
+
+struct TzifCounts {
+    isut: usize,
+    isstd: usize,
+    leap: usize,
+    time: usize,
+    types: usize,
+    chars: usize,
+}
+
+impl TzifCounts {
+    /// Size of the data block following the header
+    fn data_len(&self, time_size: usize) -> usize {
+        self.time * (time_size + 1)
+            + self.types * 6
+            + self.chars
+            + self.leap * (time_size + 4)
+            + self.isstd
+            + self.isut
+    }
+}
+
+fn read_tzif_header(buf: &mut &[u8]) -> Result<(TzifCounts, u8)> {
+    ensure!(buf.remaining() >= 44, "Truncated TZif file");
+    if &buf[..4] != b"TZif" {
+        bail!("Invalid TZif file");
+    }
+    let version = buf[4];
+    buf.advance(20);
+    let mut count = || buf.get_u32() as usize;
+    let counts = TzifCounts {
+        isut: count(),
+        isstd: count(),
+        leap: count(),
+        time: count(),
+        types: count(),
+        chars: count(),
+    };
+    Ok((counts, version))
+}
+
+/// A timezone described by a POSIX `TZ` string, e.g `CET-1CEST,M3.5.0,M10.5.0/3`
+struct PosixTz {
+    /// Offset of standard time, in seconds
+    std_offset: i32,
+    /// Offset of daylight saving time, and when it starts and ends
+    dst: Option<(i32, DstRule, DstRule)>,
+}
+
+/// `Mm.w.d[/time]`: day `d` (0 is Sunday) of week `w` (5 is the last one) of month `m`
+struct DstRule {
+    month: u32,
+    week: u32,
+    weekday: u32,
+    /// Local time of the change, in seconds
+    time: i64,
+}
+
+impl PosixTz {
+    fn parse(tz: &str) -> Option<Self> {
+        let rest = skip_tz_name(tz)?;
+        let (std_offset, rest) = parse_posix_offset(rest)?;
+        // POSIX offsets are west of UTC
+        let std_offset = -std_offset as i32;
+        if rest.is_empty() {
+            return Some(Self {
+                std_offset,
+                dst: None,
+            });
+        }
+
+        let rest = skip_tz_name(rest)?;
+        let (dst_offset, rest) = match parse_posix_offset(rest) {
+            Some((offset, rest)) => (-offset as i32, rest),
+            None => (std_offset + 3600, rest),
+        };
+        let mut rules = rest.strip_prefix(',')?.split(',');
+        let start = DstRule::parse(rules.next()?)?;
+        let end = DstRule::parse(rules.next()?)?;
+
+        Some(Self {
+            std_offset,
+            dst: Some((dst_offset, start, end)),
+        })
+    }
+
+    fn offset_at(&self, timestamp: i64) -> i32 {
+        let Some((dst_offset, start, end)) = &self.dst else {
+            return self.std_offset;
+        };
+        let (year, ..) = civil_from_days((timestamp + self.std_offset as i64).div_euclid(86400));
+        // DST starts at a local time in standard time, and ends at a local time in DST
+        let start = start.local_time(year) - self.std_offset as i64;
+        let end = end.local_time(year) - *dst_offset as i64;
+        let is_dst = if start < end {
+            start <= timestamp && timestamp < end
+        } else {
+            // southern hemisphere
+            timestamp >= start || timestamp < end
+        };
+
+        if is_dst {
+            *dst_offset
+        } else {
+            self.std_offset
+        }
+    }
+}
+
+impl DstRule {
+    fn parse(rule: &str) -> Option<Self> {
+        let (date, time) = match rule.split_once('/') {
+            Some((date, time)) => (date, parse_posix_offset(time)?.0),
+            None => (rule, 7200),
+        };
+        // Only the `M` format is supported, as it's the only one in use
+        let mut parts = date.strip_prefix('M')?.split('.');
+        let month = parts.next()?.parse().ok()?;
+        let week = parts.next()?.parse().ok()?;
+        let weekday = parts.next()?.parse().ok()?;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+            return None;
+        }
+        Some(Self {
+            month,
+            week,
+            weekday,
+            time,
+        })
+    }
+
+    /// Local time of the change in the given year, as seconds since the epoch
+    fn local_time(&self, year: i64) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let next_month = if self.month == 12 {
+            days_from_civil(year + 1, 1, 1)
+        } else {
+            days_from_civil(year, self.month + 1, 1)
+        };
+        // 1970-01-01 was a Thursday
+        let first_weekday = (first + 4).rem_euclid(7);
+        let mut day = first + (self.weekday as i64 - first_weekday).rem_euclid(7);
+        day += (self.week as i64 - 1) * 7;
+        while day >= next_month {
+            day -= 7;
+        }
+        day * 86400 + self.time
+    }
+}
+
+/// Skip a timezone name, either alphabetic or between `<` and `>`
+fn skip_tz_name(tz: &str) -> Option<&str> {
+    if let Some(rest) = tz.strip_prefix('<') {
+        return Some(&rest[rest.find('>')? + 1..]);
+    }
+    let len = tz.bytes().take_while(|b| b.is_ascii_alphabetic()).count();
+    (len >= 3).then(|| &tz[len..])
+}
+
+/// `[+-]hh[:mm[:ss]]`, as seconds
+fn parse_posix_offset(s: &str) -> Option<(i64, &str)> {
+    let (sign, s) = match s.as_bytes().first()? {
+        b'-' => (-1, &s[1..]),
+        b'+' => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let len = s
+        .bytes()
+        .take_while(|b| b.is_ascii_digit() || *b == b':')
+        .count();
+    if len == 0 {
+        return None;
+    }
+    let mut seconds = 0;
+    for (part, unit) in s[..len].split(':').zip([3600, 60, 1]) {
+        seconds += part.parse::<i64>().ok()? * unit;
+    }
+    Some((sign * seconds, &s[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let expected = (1112904793, 120);
+        assert_eq!(parse_date("1112904793 +0200").unwrap(), expected);
+        assert_eq!(parse_date("@1112904793 +0200").unwrap(), expected);
+        assert_eq!(parse_date("2005-04-07T22:13:13+02:00").unwrap(), expected);
+        assert_eq!(parse_date("2005-04-07 22:13:13 +0200").unwrap(), expected);
+        assert_eq!(
+            parse_date("Thu, 07 Apr 2005 22:13:13 +0200").unwrap(),
+            expected
+        );
+        assert_eq!(parse_date("2005-04-07T20:13:13Z").unwrap(), (1112904793, 0));
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            format_date(1112904793, 120),
+            "Thu Apr 7 22:13:13 2005 +0200"
+        );
+        assert_eq!(format_date(0, -90), "Wed Dec 31 22:30:00 1969 -0130");
+        let (timestamp, tz) = parse_date("2024-02-29T12:00:00+05:30").unwrap();
+        assert_eq!(format_date(timestamp, tz), "Thu Feb 29 12:00:00 2024 +0530");
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        let now = 1700000000;
+        assert_eq!(parse_expiry("90.days.ago", now).unwrap(), now - 90 * 86400);
+        assert_eq!(parse_expiry("1 week ago", now).unwrap(), now - 7 * 86400);
+        assert_eq!(parse_expiry("never", now).unwrap(), 0);
+        assert_eq!(parse_expiry("@1600000000 +0000", now).unwrap(), 1600000000);
+        assert!(parse_expiry("3.fortnights.ago", now).is_err());
+    }
+
+    #[test]
+    fn test_posix_tz() {
+        let tz = PosixTz::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        // 2024-03-31T00:59:59Z and 2024-03-31T01:00:00Z
+        assert_eq!(tz.offset_at(1711846799), 3600);
+        assert_eq!(tz.offset_at(1711846800), 7200);
+        // 2024-10-27T00:59:59Z and 2024-10-27T01:00:00Z
+        assert_eq!(tz.offset_at(1729990799), 7200);
+        assert_eq!(tz.offset_at(1729990800), 3600);
+
+        let tz = PosixTz::parse("<+1030>-10:30<+11>-11,M10.1.0,M4.1.0").unwrap();
+        // 2024-01-01T00:00:00Z
+        assert_eq!(tz.offset_at(1704067200), 11 * 3600);
+        // 2024-07-01T00:00:00Z
+        assert_eq!(tz.offset_at(1719792000), 10 * 3600 + 1800);
+    }
+}
@@ -0,0 +1,194 @@
+//! Module to read and write pack index files (`.idx`, version 2)
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use sha1::{Digest, Sha1};
+
+use crate::ObjectId;
+
+const IDX_MAGIC: &[u8; 4] = b"\xfftOc";
+const IDX_VERSION: u32 = 2;
+/// Offsets with this bit set are an index into the table of 64-bit offsets
+const LARGE_OFFSET_FLAG: u32 = 0x8000_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub oid: ObjectId,
+    /// CRC32 of the packed (compressed) object data, including its header
+    pub crc32: u32,
+    /// Offset of the object from the start of the pack
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackIndex {
+    /// Entries, sorted by object id
+    pub entries: Vec<IndexEntry>,
+    /// Checksum of the pack file this index belongs to
+    pub pack_checksum: ObjectId,
+}
+
+impl PackIndex {
+    pub fn new(mut entries: Vec<IndexEntry>, pack_checksum: ObjectId) -> Self {
+        entries.sort_by_key(|e| e.oid);
+        Self {
+            entries,
+            pack_checksum,
+        }
+    }
+
+    pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        // The whole index is covered by a trailing checksum, so work on a contiguous copy
+        let bytes = bytes.copy_to_bytes(bytes.remaining());
+        ensure!(bytes.len() >= 8 + 256 * 4 + 40, "Truncated pack index");
+        let (content, checksum) = bytes.split_at(bytes.len() - 20);
+        ensure!(
+            Sha1::digest(content).as_slice() == checksum,
+            "Pack index checksum mismatch"
+        );
+
+        let mut buf = bytes.slice(..bytes.len() - 20);
+        let mut magic = [0; 4];
+        buf.copy_to_slice(&mut magic);
+        if &magic != IDX_MAGIC {
+            bail!("Unsupported pack index format");
+        }
+        let version = buf.get_u32();
+        if version != IDX_VERSION {
+            bail!("Unsupported pack index version: {version}");
+        }
+
+        let mut fanout = [0u32; 256];
+        for f in fanout.iter_mut() {
+            *f = buf.get_u32();
+        }
+        let count = fanout[255] as usize;
+        ensure!(
+            buf.remaining() >= count * (20 + 4 + 4) + 20,
+            "Truncated pack index"
+        );
+
+        let mut oids = Vec::with_capacity(count);
+        for _ in 0..count {
+            oids.push(ObjectId::from_bytes(buf.copy_to_bytes(20))?);
+        }
+        let crcs = (0..count).map(|_| buf.get_u32()).collect::<Vec<_>>();
+        let small_offsets = (0..count).map(|_| buf.get_u32()).collect::<Vec<_>>();
+        let num_large = small_offsets
+            .iter()
+            .filter(|o| *o & LARGE_OFFSET_FLAG != 0)
+            .count();
+        ensure!(
+            buf.remaining() == num_large * 8 + 20,
+            "Invalid size for pack index"
+        );
+        let large_offsets = (0..num_large).map(|_| buf.get_u64()).collect::<Vec<_>>();
+        let pack_checksum = ObjectId::from_bytes(buf.copy_to_bytes(20))?;
+
+        let mut entries = Vec::with_capacity(count);
+        for ((oid, crc32), offset) in oids.into_iter().zip(crcs).zip(small_offsets) {
+            let offset = if offset & LARGE_OFFSET_FLAG != 0 {
+                let idx = (offset & !LARGE_OFFSET_FLAG) as usize;
+                *large_offsets
+                    .get(idx)
+                    .ok_or_else(|| anyhow!("Invalid large offset index {idx}"))?
+            } else {
+                offset as u64
+            };
+            entries.push(IndexEntry { oid, crc32, offset });
+        }
+
+        Ok(Self {
+            entries,
+            pack_checksum,
+        })
+    }
+
+    /// Find the offset in the pack of the given object, if it's in there
+    pub fn find_offset(&self, oid: ObjectId) -> Option<u64> {
+        self.entries
+            .binary_search_by_key(&oid, |e| e.oid)
+            .ok()
+            .map(|idx| self.entries[idx].offset)
+    }
+
+    /// Ids of the objects in the pack whose hex form starts with `prefix`, which must be in
+    /// lowercase
+    pub fn find_by_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = ObjectId> + 'a {
+        let start = match ObjectId::from_str(&format!("{prefix:0<40}")) {
+            Ok(lowest) => self.entries.partition_point(|e| e.oid < lowest),
+            Err(_) => self.entries.len(),
+        };
+        self.entries[start..]
+            .iter()
+            .map(|e| e.oid)
+            .take_while(move |oid| oid.to_string().starts_with(prefix))
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put(&IDX_MAGIC[..]);
+        buf.put_u32(IDX_VERSION);
+
+        // Fanout table: entry N is the number of objects whose first byte is <= N
+        let mut fanout = [0u32; 256];
+        for entry in &self.entries {
+            fanout[entry.oid.as_bytes()[0] as usize] += 1;
+        }
+        let mut total = 0;
+        for f in fanout.iter_mut() {
+            total += *f;
+            *f = total;
+        }
+        for f in fanout {
+            buf.put_u32(f);
+        }
+
+        for entry in &self.entries {
+            buf.put(entry.oid.as_bytes());
+        }
+        for entry in &self.entries {
+            buf.put_u32(entry.crc32);
+        }
+        // Offsets that don't fit in 31 bits go in a separate table of 64-bit offsets
+        let mut large_offsets = Vec::new();
+        for entry in &self.entries {
+            if entry.offset < LARGE_OFFSET_FLAG as u64 {
+                buf.put_u32(entry.offset as u32);
+            } else {
+                buf.put_u32(LARGE_OFFSET_FLAG | large_offsets.len() as u32);
+                large_offsets.push(entry.offset);
+            }
+        }
+        for offset in large_offsets {
+            buf.put_u64(offset);
+        }
+
+        buf.put(self.pack_checksum.as_bytes());
+        let checksum = Sha1::digest(&buf);
+        buf.put(checksum.as_slice());
+
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/ofs-delta.idx"
+        ))
+        .unwrap();
+        let index = PackIndex::parse(&mut &data[..]).unwrap();
+
+        assert_eq!(index.entries.len(), 15);
+        assert_eq!(index.entries[0].offset, 1285);
+        assert_eq!(index.as_bytes(), data);
+    }
+}
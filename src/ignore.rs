@@ -0,0 +1,173 @@
+//! Module to decide which untracked files are ignored, from `.gitignore` files,
+//! `.git/info/exclude` and `core.excludesFile`
+//!
+//! See <https://git-scm.com/docs/gitignore>
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::glob_match;
+use crate::GitRepo;
+
+/// A line of an ignore file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    glob: String,
+    /// Directory of the ignore file, in index format with a trailing `/`, or empty for the root
+    base: String,
+    /// Whether the pattern re-includes paths (`!` prefix)
+    negated: bool,
+    /// Whether the pattern only matches directories (`/` suffix)
+    dir_only: bool,
+    /// Whether the pattern matches the path relative to `base`, rather than any file name
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str, base: &str) -> Option<Self> {
+        // Trailing spaces are ignored, unless escaped with a backslash
+        let trimmed = line.trim_end_matches(' ');
+        let line = match trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            true => &line[..trimmed.len() + 1],
+            false => trimmed,
+        };
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            glob: line.strip_prefix('/').unwrap_or(line).to_string(),
+            base: base.to_string(),
+            negated,
+            dir_only,
+            anchored: line.contains('/'),
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let Some(rel_path) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.glob, rel_path, false)
+        } else {
+            let name = rel_path.rsplit('/').next().unwrap_or(rel_path);
+            glob_match(&self.glob, name, false)
+        }
+    }
+}
+
+/// Ignore patterns, by increasing precedence: the last one matching a path decides whether it's
+/// ignored
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    /// Patterns of `core.excludesFile` and `.git/info/exclude`. The patterns of `.gitignore` files
+    /// are added while walking the work tree, as they only apply to their directory.
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let mut rules = Self::default();
+        let excludes_file = repo
+            .config()?
+            .get_path("core.excludesfile")
+            .or_else(default_excludes_file);
+        if let Some(path) = excludes_file {
+            rules.add_file(&path, "")?;
+        }
+        rules.add_file(&repo.git_dir().join("info/exclude"), "")?;
+        Ok(rules)
+    }
+
+    /// Add the patterns of the ignore file at `path`, which apply to the directory `base` (in
+    /// index format, with a trailing `/`). Returns the number of patterns added, which is 0 if
+    /// the file doesn't exist.
+    pub fn add_file(&mut self, path: &Path, base: &str) -> Result<usize> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        let len = self.patterns.len();
+        let content = String::from_utf8_lossy(&content);
+        self.patterns.extend(
+            content
+                .lines()
+                .filter_map(|line| Pattern::parse(line, base)),
+        );
+        Ok(self.patterns.len() - len)
+    }
+
+    /// Remove the last `count` patterns, e.g the ones of a directory once it has been walked
+    pub fn pop(&mut self, count: usize) {
+        self.patterns.truncate(self.patterns.len() - count);
+    }
+
+    /// Whether the file or directory at `path` (in index format) is ignored
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/git/ignore`, or `~/.config/git/ignore`
+fn default_excludes_file() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config| config.join("git/ignore"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ignored() {
+        let mut rules = IgnoreRules::default();
+        let root = "# comment\n*.o\n!keep.o\n/build\ntarget/\ndoc/**/*.html\n\\#hash\nspace\\ \n";
+        let sub = "*.txt\n";
+        rules
+            .patterns
+            .extend(root.lines().filter_map(|l| Pattern::parse(l, "")));
+        rules
+            .patterns
+            .extend(sub.lines().filter_map(|l| Pattern::parse(l, "sub/")));
+
+        assert!(rules.is_ignored("a.o", false));
+        assert!(rules.is_ignored("sub/dir/a.o", false));
+        assert!(!rules.is_ignored("keep.o", false));
+        assert!(rules.is_ignored("build", true));
+        assert!(!rules.is_ignored("sub/build", true));
+        assert!(rules.is_ignored("sub/target", true));
+        assert!(!rules.is_ignored("target", false));
+        assert!(rules.is_ignored("doc/a/b/c.html", false));
+        assert!(rules.is_ignored("#hash", false));
+        assert!(rules.is_ignored("space ", false));
+        assert!(rules.is_ignored("sub/a.txt", false));
+        assert!(!rules.is_ignored("a.txt", false));
+        assert!(!rules.is_ignored("comment", false));
+    }
+}
@@ -0,0 +1,442 @@
+//! Module to read and write the index (a.k.a staging area), i.e `.git/index`
+//!
+//! See <https://git-scm.com/docs/index-format>
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use sha1::{Digest, Sha1};
+
+use crate::pack::{read_ofs_offset, write_ofs_offset};
+use crate::ObjectId;
+
+const INDEX_SIGNATURE: &[u8; 4] = b"DIRC";
+const DEFAULT_VERSION: u32 = 2;
+
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+const EXT_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const EXT_FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+/// The index
+///
+/// Optional extensions, like the `TREE` cache or the `REUC` resolve-undo data, are dropped: they
+/// aren't kept up to date as entries change, so they aren't written back. Git rebuilds the caches
+/// when it needs them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub version: u32,
+    /// Entries, sorted by path then stage
+    pub entries: Vec<Entry>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            version: DEFAULT_VERSION,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Index {
+    pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        // The whole index is covered by a trailing checksum, so work on a contiguous copy
+        let bytes = bytes.copy_to_bytes(bytes.remaining());
+        ensure!(bytes.len() >= 12 + 20, "Truncated index");
+        let (content, checksum) = bytes.split_at(bytes.len() - 20);
+        ensure!(
+            Sha1::digest(content).as_slice() == checksum,
+            "Index checksum mismatch"
+        );
+
+        let mut buf = bytes.slice(..bytes.len() - 20);
+        let mut sig = [0; 4];
+        buf.copy_to_slice(&mut sig);
+        if &sig != INDEX_SIGNATURE {
+            bail!("Invalid index file");
+        }
+        let version = buf.get_u32();
+        if !(2..=4).contains(&version) {
+            bail!("Unsupported index version: {version}");
+        }
+        let num_entries = buf.get_u32();
+
+        let mut entries = Vec::with_capacity(num_entries as usize);
+        let mut previous_path = Vec::new();
+        for _ in 0..num_entries {
+            let entry = Entry::parse(&mut buf, version, &previous_path)?;
+            previous_path.clone_from(&entry.path);
+            entries.push(entry);
+        }
+
+        // Extensions. Optional ones start with an uppercase letter and are skipped, while the
+        // others are required to understand the index.
+        while buf.has_remaining() {
+            ensure!(buf.remaining() >= 8, "Truncated index extension");
+            let mut sig = [0; 4];
+            buf.copy_to_slice(&mut sig);
+            let size = buf.get_u32() as usize;
+            ensure!(buf.remaining() >= size, "Truncated index extension");
+            if !sig[0].is_ascii_uppercase() {
+                bail!(
+                    "Unsupported index extension: {}",
+                    String::from_utf8_lossy(&sig)
+                );
+            }
+            buf.advance(size);
+        }
+
+        Ok(Self { version, entries })
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        // Extended flags need at least version 3
+        let version = if self.version == 2 && self.entries.iter().any(|e| e.needs_extended_flags())
+        {
+            3
+        } else {
+            self.version
+        };
+
+        let mut buf = BytesMut::new();
+        buf.put(&INDEX_SIGNATURE[..]);
+        buf.put_u32(version);
+        buf.put_u32(self.entries.len() as u32);
+        let mut previous_path = &[][..];
+        for entry in &self.entries {
+            entry.write(&mut buf, version, previous_path);
+            previous_path = &entry.path;
+        }
+
+        let checksum = Sha1::digest(&buf);
+        buf.put(checksum.as_slice());
+
+        buf.freeze()
+    }
+
+    /// Find the entry at stage 0 for the given path
+    pub fn get(&self, path: impl AsRef<[u8]>) -> Option<&Entry> {
+        self.find(path.as_ref(), 0)
+            .ok()
+            .map(|idx| &self.entries[idx])
+    }
+
+    /// Add an entry, replacing any existing entry with the same path (at any stage), along with
+    /// the entries it conflicts with: files in a directory at its path, and files at the path
+    /// of one of its parent directories
+    pub fn add(&mut self, entry: Entry) {
+        self.remove(&entry.path);
+        let dir = [&entry.path[..], b"/"].concat();
+        self.entries.retain(|e| {
+            let is_parent = entry
+                .path
+                .strip_prefix(&e.path[..])
+                .is_some_and(|rest| rest.starts_with(b"/"));
+            !e.path.starts_with(&dir) && !is_parent
+        });
+        let idx = self
+            .find(&entry.path, entry.stage)
+            .expect_err("entry to have been removed");
+        self.entries.insert(idx, entry);
+    }
+
+    /// Remove all the entries with the given path. Returns `true` if any was removed.
+    pub fn remove(&mut self, path: impl AsRef<[u8]>) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.path != path.as_ref());
+        self.entries.len() != len
+    }
+
+    fn find(&self, path: &[u8], stage: u8) -> std::result::Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| (&e.path[..], e.stage).cmp(&(path, stage)))
+    }
+}
+
+/// Filesystem metadata of a file, used to detect changes without re-hashing it
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub ctime: u32,
+    pub ctime_nsec: u32,
+    pub mtime: u32,
+    pub mtime_nsec: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Size of the file, truncated to 32 bits
+    pub size: u32,
+}
+
+impl Stat {
+    /// All the fields are truncated to 32 bits, as they are in the index
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            ctime: metadata.ctime() as u32,
+            ctime_nsec: metadata.ctime_nsec() as u32,
+            mtime: metadata.mtime() as u32,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+        }
+    }
+}
+
+/// Mode of a file in the work tree, as it is stored in the index. `metadata` must come from
+/// `symlink_metadata()`.
+pub fn mode_from_metadata(metadata: &Metadata) -> u32 {
+    if metadata.is_symlink() {
+        0o120000
+    } else if metadata.is_dir() {
+        // A directory where the index has a file: only possible for submodules
+        0o160000
+    } else if metadata.permissions().mode() & 0o100 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub stat: Stat,
+    /// Mode of the file, e.g 0o100644
+    pub mode: u32,
+    pub oid: ObjectId,
+    pub assume_valid: bool,
+    /// Merge stage, from 0 to 3
+    pub stage: u8,
+    pub skip_worktree: bool,
+    pub intent_to_add: bool,
+    /// Path relative to the root of the work tree, with `/` as separator. Like in git, it doesn't
+    /// have to be UTF-8.
+    pub path: Vec<u8>,
+}
+
+impl Entry {
+    pub fn new(path: impl Into<Vec<u8>>, mode: u32, oid: ObjectId, stat: Stat) -> Self {
+        Self {
+            stat,
+            mode,
+            oid,
+            assume_valid: false,
+            stage: 0,
+            skip_worktree: false,
+            intent_to_add: false,
+            path: path.into(),
+        }
+    }
+
+    /// The path, decoded lossily if it's not UTF-8
+    pub fn path_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.path)
+    }
+
+    /// The path, relative to the root of the work tree
+    pub fn fs_path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.path))
+    }
+
+    fn parse(buf: &mut Bytes, version: u32, previous_path: &[u8]) -> Result<Self> {
+        ensure!(buf.remaining() >= 62, "Truncated index entry");
+        let start_len = buf.remaining();
+        let ctime = buf.get_u32();
+        let ctime_nsec = buf.get_u32();
+        let mtime = buf.get_u32();
+        let mtime_nsec = buf.get_u32();
+        let dev = buf.get_u32();
+        let ino = buf.get_u32();
+        let mode = buf.get_u32();
+        let uid = buf.get_u32();
+        let gid = buf.get_u32();
+        let size = buf.get_u32();
+        let stat = Stat {
+            ctime,
+            ctime_nsec,
+            mtime,
+            mtime_nsec,
+            dev,
+            ino,
+            uid,
+            gid,
+            size,
+        };
+        let oid = ObjectId::from_bytes(buf.copy_to_bytes(20))?;
+        let flags = buf.get_u16();
+        let extended_flags = if flags & FLAG_EXTENDED != 0 {
+            ensure!(version >= 3, "Extended flags in a version {version} index");
+            ensure!(buf.remaining() >= 2, "Truncated index entry");
+            buf.get_u16()
+        } else {
+            0
+        };
+
+        let path = if version >= 4 {
+            // The path is stored as the number of bytes to remove from the end of the previous
+            // path, followed by the suffix to append to it
+            let strip = read_ofs_offset(buf)? as usize;
+            ensure!(
+                strip <= previous_path.len(),
+                "Invalid path prefix in index entry"
+            );
+            let suffix = read_nul_terminated(buf)?;
+            let mut path = previous_path[..previous_path.len() - strip].to_vec();
+            path.extend_from_slice(&suffix);
+            path
+        } else {
+            let path = read_nul_terminated(buf)?;
+            // Entries are padded with 1 to 8 NUL bytes to a multiple of 8 bytes
+            let entry_len = start_len - buf.remaining();
+            let padding = (8 - entry_len % 8) % 8;
+            ensure!(buf.remaining() >= padding, "Truncated index entry");
+            buf.advance(padding);
+            path.to_vec()
+        };
+
+        Ok(Self {
+            stat,
+            mode,
+            oid,
+            assume_valid: flags & FLAG_ASSUME_VALID != 0,
+            stage: ((flags & FLAG_STAGE_MASK) >> 12) as u8,
+            skip_worktree: extended_flags & EXT_FLAG_SKIP_WORKTREE != 0,
+            intent_to_add: extended_flags & EXT_FLAG_INTENT_TO_ADD != 0,
+            path,
+        })
+    }
+
+    fn write(&self, buf: &mut BytesMut, version: u32, previous_path: &[u8]) {
+        let start_len = buf.len();
+        buf.put_u32(self.stat.ctime);
+        buf.put_u32(self.stat.ctime_nsec);
+        buf.put_u32(self.stat.mtime);
+        buf.put_u32(self.stat.mtime_nsec);
+        buf.put_u32(self.stat.dev);
+        buf.put_u32(self.stat.ino);
+        buf.put_u32(self.mode);
+        buf.put_u32(self.stat.uid);
+        buf.put_u32(self.stat.gid);
+        buf.put_u32(self.stat.size);
+        buf.put(self.oid.as_bytes());
+
+        let mut flags = (self.path.len().min(FLAG_NAME_MASK as usize) as u16)
+            | ((self.stage as u16) << 12) & FLAG_STAGE_MASK;
+        if self.assume_valid {
+            flags |= FLAG_ASSUME_VALID;
+        }
+        if self.needs_extended_flags() {
+            flags |= FLAG_EXTENDED;
+        }
+        buf.put_u16(flags);
+        if self.needs_extended_flags() {
+            let mut extended_flags = 0;
+            if self.skip_worktree {
+                extended_flags |= EXT_FLAG_SKIP_WORKTREE;
+            }
+            if self.intent_to_add {
+                extended_flags |= EXT_FLAG_INTENT_TO_ADD;
+            }
+            buf.put_u16(extended_flags);
+        }
+
+        if version >= 4 {
+            let common = previous_path
+                .iter()
+                .zip(&self.path)
+                .take_while(|(a, b)| a == b)
+                .count();
+            write_ofs_offset(buf, (previous_path.len() - common) as u64);
+            buf.put(&self.path[common..]);
+            buf.put_u8(0);
+        } else {
+            buf.put(&self.path[..]);
+            // Pad with 1 to 8 NUL bytes to a multiple of 8 bytes
+            let entry_len = buf.len() - start_len;
+            buf.put_bytes(0, 8 - entry_len % 8);
+        }
+    }
+
+    fn needs_extended_flags(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
+
+fn read_nul_terminated(buf: &mut Bytes) -> Result<Bytes> {
+    let len = buf
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!("Missing NUL terminator in index entry path"))?;
+    let data = buf.split_to(len);
+    buf.advance(1);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for (fixture, version) in [("index-v3", 3), ("index-v4", 4)] {
+            let path = format!("{}/tests/fixtures/{fixture}", env!("CARGO_MANIFEST_DIR"));
+            let data = std::fs::read(path).unwrap();
+            let index = Index::parse(&mut &data[..]).unwrap();
+
+            assert_eq!(index.version, version);
+            assert_eq!(index.entries.len(), 6);
+            assert_eq!(index.entries[0].path, b"a/b/c/four");
+            assert_eq!(index.entries[0].mode, 0o100644);
+            assert!(index.get("six").unwrap().intent_to_add);
+            assert_eq!(index.as_bytes(), data);
+        }
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut index = Index::default();
+        let oid = ObjectId::from_bytes([0; 20]).unwrap();
+        for path in ["b", "a/c", "a/d/e", "a-b", "c"] {
+            index.add(Entry::new(path.to_string(), 0o100644, oid, Stat::default()));
+        }
+        index.add(Entry::new("a".to_string(), 0o100755, oid, Stat::default()));
+        assert!(index.remove("b"));
+        assert!(!index.remove("b"));
+        // A directory replacing a file
+        index.add(Entry::new(
+            "c/d".to_string(),
+            0o100644,
+            oid,
+            Stat::default(),
+        ));
+
+        let paths = index
+            .entries
+            .iter()
+            .map(|e| e.path_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a", "a-b", "c/d"]);
+        assert_eq!(index.get("a").unwrap().mode, 0o100755);
+
+        let version_2 = Index::parse(&mut index.as_bytes()).unwrap();
+        assert_eq!(version_2, index);
+
+        // Paths don't have to be UTF-8
+        index.add(Entry::new(&b"caf\xe9"[..], 0o100644, oid, Stat::default()));
+        index.version = 4;
+        let latin1 = Index::parse(&mut index.as_bytes()).unwrap();
+        assert_eq!(latin1.get(b"caf\xe9").unwrap().path_str(), "caf\u{fffd}");
+        assert_eq!(latin1, index);
+    }
+}
@@ -1,8 +1,14 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
 use std::fmt::Display;
 use std::fs::{self, create_dir, File};
 use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::prelude::PermissionsExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -12,12 +18,44 @@ use flate2::Compression;
 use reqwest::Url;
 use sha1::{Digest, Sha1};
 
+pub mod branch;
+pub mod checkout;
 pub mod client;
+pub mod config;
+pub mod date;
+pub mod idx;
+pub mod ignore;
+pub mod index;
 pub mod pack;
 pub mod pkt;
+pub mod reflog;
+pub mod refs;
+pub mod revision;
+pub mod status;
+pub mod tag;
 
 use crate::client::GitClient;
-use crate::pack::PackFile;
+use crate::config::{Config, ConfigFile, ConfigScope};
+use crate::index::{Index, Stat};
+use crate::pack::{apply_delta, Pack, PackFile, PackedObject, MAX_DELTA_DEPTH};
+use crate::refs::{
+    check_ref_format, dwim_names, LockFile, RefChange, RefStore, RefTransaction, RefUpdate,
+    RefValue,
+};
+use crate::status::{hash_file, Status};
+
+/// Template copied into new repos when none is given
+const DEFAULT_TEMPLATE_DIR: &str = "/usr/share/git-core/templates";
+const DEFAULT_DESCRIPTION: &str =
+    "Unnamed repository; edit this file 'description' to name the repository.\n";
+const DEFAULT_EXCLUDE: &str = "\
+# git ls-files --others --exclude-from=.git/info/exclude
+# Lines that start with '#' are comments.
+# For a project mostly in C, the following would be a good set of
+# exclude patterns (uncomment them if you want to use them):
+# *.[oa]
+# *~
+";
 
 #[derive(Debug, thiserror::Error)]
 pub enum GitError {
@@ -28,38 +66,182 @@ pub enum GitError {
 }
 
 pub struct GitRepo {
-    path: PathBuf,
+    /// Root of the work tree, or `None` for a bare repo
+    work_tree: Option<PathBuf>,
     git_dir: PathBuf,
     object_dir: PathBuf,
-    refs_dir: PathBuf,
-    tags_dir: PathBuf,
+    refs: RefStore,
+    /// Pack files of the repo, loaded lazily
+    packs: RefCell<Vec<Rc<Pack>>>,
 }
 
 impl GitRepo {
     pub fn new<P: AsRef<Path>>(dir: P) -> Self {
-        let git_dir = dir.as_ref().join(".git");
-        let object_dir = git_dir.join("objects");
-        let refs_dir = git_dir.join("refs");
-        let tags_dir = git_dir.join("tags");
+        Self::with_git_dir(Some(dir.as_ref().to_owned()), dir.as_ref().join(".git"))
+    }
+
+    /// Bare repo, i.e without work tree, in `dir`
+    pub fn new_bare<P: AsRef<Path>>(dir: P) -> Self {
+        Self::with_git_dir(None, dir.as_ref().to_owned())
+    }
+
+    fn with_git_dir(work_tree: Option<PathBuf>, git_dir: PathBuf) -> Self {
+        let common_dir = common_dir(&git_dir);
+        let object_dir = common_dir.join("objects");
+        let refs = RefStore::new(git_dir.clone(), common_dir);
         Self {
-            path: dir.as_ref().to_owned(),
+            work_tree,
             git_dir,
             object_dir,
-            refs_dir,
-            tags_dir,
+            refs,
+            packs: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Find the repo containing `dir`, by looking for a `.git` directory in it and its parents.
+    /// `GIT_DIR`, `GIT_WORK_TREE` and `GIT_CEILING_DIRECTORIES` are taken into account.
+    pub fn discover<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::discover_with_env(dir.as_ref(), |name| env::var_os(name))
+    }
+
+    /// [`discover`](Self::discover), with environment variables looked up by `var`
+    fn discover_with_env(dir: &Path, var: impl Fn(&str) -> Option<OsString>) -> Result<Self> {
+        let dir = normalize_path(&env::current_dir()?.join(dir));
+        let work_tree = var("GIT_WORK_TREE").map(|w| normalize_path(&dir.join(w)));
+
+        if let Some(git_dir) = var("GIT_DIR") {
+            let git_dir = normalize_path(&dir.join(git_dir));
+            ensure!(
+                is_git_dir(&git_dir),
+                "Not a git repository: '{}'",
+                git_dir.display()
+            );
+            // Without GIT_WORK_TREE, the current directory is the root of the work tree, unless
+            // the repo is bare
+            let bare = Config::load(Some(&git_dir))?.get_bool("core.bare")? == Some(true);
+            let work_tree = work_tree.or_else(|| (!bare).then_some(dir));
+            return Ok(Self::with_git_dir(work_tree, git_dir));
+        }
+
+        let ceilings = ceiling_dirs(var("GIT_CEILING_DIRECTORIES"));
+        let mut current = Some(dir.as_path());
+        while let Some(d) = current {
+            if let Some(git_dir) = find_git_dir(d)? {
+                let work_tree = work_tree.unwrap_or_else(|| d.to_owned());
+                return Ok(Self::with_git_dir(Some(work_tree), git_dir));
+            }
+            // Bare repo, or inside a `.git` directory
+            if is_git_dir(d) {
+                return Ok(Self::with_git_dir(work_tree, d.to_owned()));
+            }
+            // Never go up into a ceiling directory
+            current = d.parent().filter(|p| !ceilings.iter().any(|c| c == p));
         }
+
+        bail!("Not a git repository (or any of the parent directories): .git")
+    }
+
+    /// Root of the work tree, or an error for bare repos
+    pub fn work_tree(&self) -> Result<&Path> {
+        self.work_tree
+            .as_deref()
+            .ok_or_else(|| anyhow!("This operation must be run in a work tree"))
+    }
+
+    pub fn refs(&self) -> &RefStore {
+        &self.refs
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.work_tree.is_none()
     }
 
-    pub fn init(&self) -> Result<()> {
-        fs::create_dir(&self.git_dir).context("Creating .git directory")?;
-        fs::create_dir(&self.object_dir).context("Creating .git/objects directory")?;
-        fs::create_dir(&self.refs_dir).context("Creating .git/refs directory")?;
-        fs::create_dir(self.refs_dir.join("heads"))
-            .context("Creating .git/refs/heads directory")?;
-        fs::create_dir(&self.tags_dir).context("Creating .git/tags directory")?;
-        fs::write(self.git_dir.join("HEAD"), "ref: refs/heads/master\n")
-            .context("creating .git/HEAD file")?;
-        println!("Initialized git directory");
+    /// Create the repo, or re-initialize it if it already exists, which only adds what's missing.
+    /// The files of the template directory are copied into the git directory.
+    pub fn init(&self, initial_branch: Option<&str>, template: Option<&Path>) -> Result<()> {
+        let reinit = is_git_dir(&self.git_dir);
+        let config = Config::load(Some(&self.git_dir))?;
+        let branch = match initial_branch {
+            Some(branch) => branch,
+            None => config.get("init.defaultbranch").unwrap_or("master"),
+        };
+        ensure!(
+            check_ref_format(&format!("refs/heads/{branch}")),
+            "Invalid initial branch name: '{branch}'"
+        );
+
+        fs::create_dir_all(&self.git_dir).context("Creating git directory")?;
+        let template = template
+            .map(Path::to_owned)
+            .or_else(|| env::var_os("GIT_TEMPLATE_DIR").map(PathBuf::from))
+            .or_else(|| config.get_path("init.templatedir"))
+            .or_else(|| {
+                let default = Path::new(DEFAULT_TEMPLATE_DIR);
+                default.is_dir().then(|| default.to_owned())
+            });
+        if let Some(template) = template {
+            copy_template(&template, &self.git_dir)
+                .with_context(|| format!("Copying template {}", template.display()))?;
+        }
+
+        for dir in [
+            "objects/info",
+            "objects/pack",
+            "refs/heads",
+            "refs/tags",
+            "info",
+            "hooks",
+        ] {
+            fs::create_dir_all(self.git_dir.join(dir))
+                .with_context(|| format!("Creating {dir} directory"))?;
+        }
+        for (file, content) in [
+            ("description", DEFAULT_DESCRIPTION),
+            ("info/exclude", DEFAULT_EXCLUDE),
+        ] {
+            let path = self.git_dir.join(file);
+            if !path.exists() {
+                fs::write(path, content).with_context(|| format!("Creating {file}"))?;
+            }
+        }
+
+        if reinit {
+            if let Some(branch) = initial_branch {
+                eprintln!("warning: re-init: ignored --initial-branch={branch}");
+            }
+        } else {
+            self.refs
+                .write("HEAD", &RefValue::Symbolic(format!("refs/heads/{branch}")))
+                .context("Creating HEAD")?;
+        }
+
+        let mut config = ConfigFile::open(self.git_dir.join("config"))?;
+        if reinit {
+            if Config::from_file(&config.path, ConfigScope::Local)?
+                .get("core.repositoryformatversion")
+                .is_none()
+            {
+                config.set("core.repositoryformatversion", "0")?;
+            }
+        } else {
+            config.set("core.repositoryformatversion", "0")?;
+            config.set("core.filemode", "true")?;
+            config.set("core.bare", &self.is_bare().to_string())?;
+            if !self.is_bare() {
+                config.set("core.logallrefupdates", "true")?;
+            }
+        }
+        config.write()?;
+
+        let git_dir = normalize_path(&env::current_dir()?.join(&self.git_dir));
+        if reinit {
+            println!(
+                "Reinitialized existing Git repository in {}/",
+                git_dir.display()
+            );
+        } else {
+            println!("Initialized empty Git repository in {}/", git_dir.display());
+        }
 
         Ok(())
     }
@@ -88,7 +270,9 @@ impl GitRepo {
         Ok(())
     }
 
-    pub fn read_tree(&self, oid: ObjectId, names_only: bool) -> Result<()> {
+    /// Print the entries of a tree, with object ids abbreviated to at least `abbrev` hex digits
+    /// if given
+    pub fn read_tree(&self, oid: ObjectId, names_only: bool, abbrev: Option<usize>) -> Result<()> {
         let object = self.get_object(oid)?;
 
         ensure!(
@@ -102,6 +286,10 @@ impl GitRepo {
         for entry in tree.entries {
             if names_only {
                 println!("{}", entry.name);
+            } else if let Some(abbrev) = abbrev {
+                let short = self.abbreviate(entry.sha1, abbrev)?;
+                let (mode, object_type) = (&entry.mode, entry.object_type);
+                println!("{mode:0>6} {object_type} {short}\t{}", entry.name);
             } else {
                 println!("{entry}");
             }
@@ -110,8 +298,200 @@ impl GitRepo {
         Ok(())
     }
 
+    /// Add the content of the given files to the index. Directories are added recursively, and
+    /// files that were deleted from the work tree are removed from the index.
+    pub fn add<P: AsRef<Path>>(&self, paths: &[P]) -> Result<()> {
+        let mut index = self.read_index()?;
+        for path in paths {
+            let rel_path = self.relative_path(path.as_ref())?;
+            let full_path = self.work_tree()?.join(&rel_path);
+            if full_path.symlink_metadata().is_err() {
+                // Deleted file: stage its removal
+                let removed = self.remove_from_index(&mut index, &rel_path);
+                ensure!(
+                    removed > 0,
+                    "pathspec '{}' did not match any files",
+                    path.as_ref().display()
+                );
+                continue;
+            }
+            self.add_path_to_index(&mut index, &rel_path, &full_path)?;
+        }
+        self.write_index(&index)
+    }
+
+    fn add_path_to_index(&self, index: &mut Index, rel_path: &str, full_path: &Path) -> Result<()> {
+        let metadata = full_path.symlink_metadata()?;
+        if metadata.is_dir() {
+            // Entries of the index that are no longer in the directory have been deleted, and a
+            // file at the path of the directory has been replaced by it
+            let work_tree = self.work_tree()?;
+            let deleted = index
+                .entries
+                .iter()
+                .filter(|e| e.path == rel_path.as_bytes() || is_in_dir(&e.path, rel_path))
+                .filter(|e| {
+                    e.path == rel_path.as_bytes()
+                        || work_tree.join(e.fs_path()).symlink_metadata().is_err()
+                })
+                .map(|e| e.path.clone())
+                .collect::<Vec<_>>();
+            for path in deleted {
+                index.remove(&path);
+            }
+
+            for e in fs::read_dir(full_path)? {
+                let e = e?;
+                // ignore `.git/` directory
+                if e.file_name() == ".git" {
+                    continue;
+                }
+                let name = e.file_name().to_string_lossy().to_string();
+                let child = if rel_path.is_empty() {
+                    name
+                } else {
+                    format!("{rel_path}/{name}")
+                };
+                self.add_path_to_index(index, &child, &e.path())?;
+            }
+            return Ok(());
+        }
+
+        let content = if metadata.is_symlink() {
+            // The content of a symlink is its target
+            let target = fs::read_link(full_path)?;
+            target.as_os_str().as_bytes().to_vec()
+        } else {
+            fs::read(full_path)?
+        };
+        let oid = self.store_object(Object::blob(content))?;
+        index.add(index::Entry::new(
+            rel_path.to_string(),
+            index::mode_from_metadata(&metadata),
+            oid,
+            Stat::from_metadata(&metadata),
+        ));
+
+        Ok(())
+    }
+
+    /// Remove the given files from the index, and from the work tree unless `cached` is set.
+    pub fn rm<P: AsRef<Path>>(&self, paths: &[P], cached: bool) -> Result<()> {
+        let mut index = self.read_index()?;
+        let mut removed = Vec::new();
+        for path in paths {
+            let rel_path = self.relative_path(path.as_ref())?;
+            let matching = index
+                .entries
+                .iter()
+                .filter(|e| e.path == rel_path.as_bytes() || is_in_dir(&e.path, &rel_path))
+                .collect::<Vec<_>>();
+            ensure!(
+                !matching.is_empty(),
+                "pathspec '{}' did not match any files",
+                path.as_ref().display()
+            );
+            for entry in matching {
+                if !cached {
+                    // Don't lose changes that were never staged
+                    let full_path = self.work_tree()?.join(entry.fs_path());
+                    let metadata = full_path.symlink_metadata();
+                    if let Ok(oid) = metadata.and_then(|m| hash_file(&full_path, m.is_symlink())) {
+                        ensure!(
+                            oid == entry.oid,
+                            "'{}' has local modifications (use --cached to keep the file)",
+                            entry.path_str()
+                        );
+                    }
+                }
+                removed.push(entry.clone());
+            }
+        }
+
+        for entry in &removed {
+            index.remove(&entry.path);
+            if !cached {
+                let full_path = self.work_tree()?.join(entry.fs_path());
+                if full_path.symlink_metadata().is_ok() {
+                    fs::remove_file(&full_path)?;
+                }
+                self.remove_empty_parents(&full_path)?;
+            }
+            println!("rm '{}'", entry.path_str());
+        }
+        self.write_index(&index)
+    }
+
+    fn remove_from_index(&self, index: &mut Index, rel_path: &str) -> usize {
+        let before = index.entries.len();
+        index
+            .entries
+            .retain(|e| e.path != rel_path.as_bytes() && !is_in_dir(&e.path, rel_path));
+        before - index.entries.len()
+    }
+
+    /// Remove the parent directories of `path` that are now empty, up to the root of the work
+    /// tree
+    fn remove_empty_parents(&self, path: &Path) -> Result<()> {
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            if Some(d) == self.work_tree.as_deref() || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+        Ok(())
+    }
+
+    /// Convert a path relative to the current directory into a path relative to the root of the
+    /// work tree, with `/` as separator (i.e the format used in the index)
+    fn relative_path(&self, path: &Path) -> Result<String> {
+        let cwd = std::env::current_dir()?;
+        let full_path = normalize_path(&cwd.join(path));
+        let root = normalize_path(&cwd.join(self.work_tree()?));
+        let rel_path = full_path
+            .strip_prefix(&root)
+            .map_err(|_| anyhow!("'{}' is outside repository", path.display()))?;
+        ensure!(
+            !rel_path.starts_with(".git"),
+            "'{}' is inside the .git directory",
+            path.display()
+        );
+
+        Ok(rel_path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"))
+    }
+
+    /// Compare HEAD, the index and the work tree
+    pub fn status(&self) -> Result<Status> {
+        Status::compute(self)
+    }
+
+    /// List the entries of the index
+    pub fn ls_files(&self, stage: bool) -> Result<()> {
+        let index = self.read_index()?;
+        for entry in index.entries {
+            if stage {
+                println!(
+                    "{:06o} {} {}\t{}",
+                    entry.mode,
+                    entry.oid,
+                    entry.stage,
+                    entry.path_str()
+                );
+            } else {
+                println!("{}", entry.path_str());
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn write_tree(&self) -> Result<()> {
-        let sha = self.write_tree_dir(&self.path)?;
+        let sha = self.write_tree_dir(self.work_tree()?)?;
 
         println!("{sha}");
 
@@ -142,10 +522,10 @@ impl GitRepo {
                 let perms = e.metadata()?.permissions().mode();
                 let mode = if (perms & 0o100) != 0 {
                     // executable
-                    "00755"
+                    "100755"
                 } else {
                     // regular file
-                    "00644"
+                    "100644"
                 };
                 let mut file = BufReader::new(File::open(e.path())?);
                 let mut buf = Vec::new();
@@ -158,111 +538,485 @@ impl GitRepo {
                     sha1: file_sha,
                     name: e.file_name().to_string_lossy().to_string(),
                 });
-            } else {
-                // symlink
-                unimplemented!()
+            } else if ft.is_symlink() {
+                // The content of a symlink is its target
+                let target = fs::read_link(e.path())?;
+                let object = Object::blob(target.as_os_str().as_bytes().to_vec());
+                let link_sha = self.store_object(object)?;
+                tree_entries.push(TreeEntry {
+                    object_type: ObjectType::Blob,
+                    mode: "120000".to_string(),
+                    sha1: link_sha,
+                    name: e.file_name().to_string_lossy().to_string(),
+                });
             }
         }
 
-        // Sort entries by name
-        tree_entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let tree_object = Object::tree(Tree::new(tree_entries).as_bytes().to_vec());
+        let sha1 = self.store_object(tree_object)?;
 
-        // Prepare content of the tree object
-        let mut buf = BytesMut::new();
-        for entry in tree_entries.into_iter() {
-            // Each tree entry as the following format:
-            // `[mode] [Object name]\0[SHA-1 in binary format]`
-            if entry.object_type == ObjectType::Blob {
-                buf.put_u8(b'1')
+        Ok(sha1)
+    }
+
+    /// Write a tree object for the content of the index, along with all its sub-trees
+    pub fn write_tree_from_index(&self, index: &Index) -> Result<ObjectId> {
+        ensure!(
+            index.entries.iter().all(|e| e.stage == 0),
+            "Cannot write a tree from an index with unmerged entries"
+        );
+        let files = index
+            .entries
+            .iter()
+            .filter(|e| !e.intent_to_add)
+            .map(|e| {
+                let path = std::str::from_utf8(&e.path)
+                    .with_context(|| format!("Path '{}' is not valid UTF-8", e.path_str()))?;
+                Ok((path, e.mode, e.oid))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.write_tree_from_files(&files)
+    }
+
+    /// Write a tree for the given files, sorted by path, and whose paths are relative to the tree
+    fn write_tree_from_files(&self, files: &[(&str, u32, ObjectId)]) -> Result<ObjectId> {
+        let mut tree_entries = Vec::new();
+        let mut i = 0;
+        while i < files.len() {
+            let (path, mode, oid) = files[i];
+            match path.split_once('/') {
+                None => {
+                    tree_entries.push(TreeEntry {
+                        mode: format!("{mode:o}"),
+                        object_type: ObjectType::Blob,
+                        name: path.to_string(),
+                        sha1: oid,
+                    });
+                    i += 1;
+                }
+                Some((dir, _)) => {
+                    // All the files in that directory are next to each other
+                    let prefix = format!("{dir}/");
+                    let children = files[i..]
+                        .iter()
+                        .take_while(|(p, _, _)| p.starts_with(&prefix))
+                        .map(|(p, mode, oid)| (&p[prefix.len()..], *mode, *oid))
+                        .collect::<Vec<_>>();
+                    i += children.len();
+                    tree_entries.push(TreeEntry {
+                        mode: "40000".to_string(),
+                        object_type: ObjectType::Tree,
+                        name: dir.to_string(),
+                        sha1: self.write_tree_from_files(&children)?,
+                    });
+                }
+            }
+        }
+
+        let tree_object = Object::tree(Tree::new(tree_entries).as_bytes().to_vec());
+        self.store_object(tree_object)
+    }
+
+    /// Commit the content of the index, and advance the current branch to the new commit
+    pub fn commit(&self, message: String) -> Result<ObjectId> {
+        let index = self.read_index()?;
+        let tree = self.write_tree_from_index(&index)?;
+        let parent = self.head_commit()?;
+        match parent {
+            Some(parent) => {
+                let Some(parent_commit) = self.get_object(parent)?.as_commit() else {
+                    bail!("HEAD doesn't point to a commit");
+                };
+                ensure!(parent_commit.tree != tree, "nothing to commit");
+            }
+            None => ensure!(!index.entries.is_empty(), "nothing to commit"),
+        }
+
+        let oid = self.write_commit(tree, parent.into_iter().collect(), format!("{message}\n"))?;
+        let head_ref = self.head_ref()?;
+        let subject = message.lines().next().unwrap_or_default();
+        let reflog_message = match parent {
+            Some(_) => format!("commit: {subject}"),
+            None => format!("commit (initial): {subject}"),
+        };
+        // Don't overwrite a commit made concurrently
+        let mut transaction = self.ref_transaction(&reflog_message)?;
+        transaction.add(RefUpdate {
+            name: head_ref.clone(),
+            change: RefChange::Set(RefValue::Direct(oid)),
+            old: Some(parent),
+            deref: false,
+        });
+        transaction.commit(&self.refs)?;
+
+        let branch = match head_ref.strip_prefix("refs/heads/") {
+            Some(branch) => branch,
+            None if head_ref == "HEAD" => "detached HEAD",
+            None => &head_ref,
+        };
+        let root = if parent.is_none() {
+            " (root-commit)"
+        } else {
+            ""
+        };
+        let summary = message.lines().next().unwrap_or_default();
+        let short = self.abbreviate(oid, self.abbrev_len()?)?;
+        println!("[{branch}{root} {short}] {summary}");
+
+        Ok(oid)
+    }
+
+    /// Create a commit object for the given tree. `message` is used as is.
+    pub fn commit_tree(
+        &self,
+        tree_oid: ObjectId,
+        parents: Vec<ObjectId>,
+        message: String,
+    ) -> Result<()> {
+        let sha = self.write_commit(tree_oid, parents, message)?;
+
+        println!("{sha}");
+
+        Ok(())
+    }
+
+    fn write_commit(
+        &self,
+        tree: ObjectId,
+        parents: Vec<ObjectId>,
+        message: String,
+    ) -> Result<ObjectId> {
+        let commit = Commit {
+            tree,
+            parents,
+            author: self.identity("AUTHOR", "author")?,
+            committer: self.identity("COMMITTER", "committer")?,
+            headers: Vec::new(),
+            message: message.into(),
+        };
+
+        let object = Object::commit(commit.as_bytes().to_vec());
+        self.store_object(object)
+    }
+
+    /// Identity to use as author (`kind` is `AUTHOR`) or committer (`kind` is `COMMITTER`), taken
+    /// from the `GIT_<kind>_NAME`, `GIT_<kind>_EMAIL` and `GIT_<kind>_DATE` environment
+    /// variables, or the config.
+    fn identity(&self, kind: &str, section: &str) -> Result<Signature> {
+        let config = self.config()?;
+        let lookup = |key: &str| {
+            env::var(format!("GIT_{kind}_{}", key.to_uppercase()))
+                .ok()
+                .or_else(|| config.get(&format!("{section}.{key}")).map(str::to_string))
+                .or_else(|| config.get(&format!("user.{key}")).map(str::to_string))
+        };
+        let user = env::var("USER").or_else(|_| env::var("LOGNAME")).ok();
+
+        let Some(name) = lookup("name").or_else(|| user.clone()) else {
+            bail!(
+                "{} identity unknown: please set user.name",
+                kind.to_lowercase()
+            );
+        };
+        let email = match lookup("email").or_else(|| env::var("EMAIL").ok()) {
+            Some(email) => email,
+            None => {
+                let hostname = fs::read_to_string("/etc/hostname")
+                    .map(|h| h.trim().to_string())
+                    .or_else(|_| env::var("HOSTNAME"))
+                    .unwrap_or_else(|_| "localhost".to_string());
+                format!("{}@{hostname}", user.unwrap_or_default())
+            }
+        };
+        let (timestamp, tz_offset) = match env::var(format!("GIT_{kind}_DATE")) {
+            Ok(date) => date::parse_date(&date)?,
+            Err(_) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                (now, date::local_tz_offset(now))
+            }
+        };
+
+        Ok(Signature::new(name, email, timestamp, tz_offset))
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// Config of the repo, including the system and global config
+    pub fn config(&self) -> Result<Config> {
+        Config::load(Some(&self.git_dir))
+    }
+
+    /// New ref transaction, whose updates are recorded in the reflogs with `message`
+    fn ref_transaction(&self, message: &str) -> Result<RefTransaction> {
+        // Unlike for commits, an unknown identity doesn't prevent updating refs
+        let committer = match self.identity("COMMITTER", "committer") {
+            Ok(committer) => committer,
+            Err(_) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                Signature::new(
+                    String::new(),
+                    String::new(),
+                    now,
+                    date::local_tz_offset(now),
+                )
+            }
+        };
+        let mut transaction = RefTransaction::new();
+        transaction.set_reflog(committer, message);
+        Ok(transaction)
+    }
+
+    /// Update a ref (or the ref it points to, unless `no_deref`) to `new`, or delete it if `new`
+    /// is `None`. If `old` is given, the ref must currently have that value, and the zero id
+    /// means it must not exist.
+    pub fn update_ref(
+        &self,
+        name: &str,
+        new: Option<&str>,
+        old: Option<&str>,
+        no_deref: bool,
+        message: Option<&str>,
+    ) -> Result<()> {
+        let change = match new {
+            Some(new) => match self.parse_ref_value(new)? {
+                Some(oid) => RefChange::Set(RefValue::Direct(oid)),
+                None => RefChange::Delete,
+            },
+            None => RefChange::Delete,
+        };
+        let mut transaction = self.ref_transaction(message.unwrap_or_default())?;
+        transaction.add(RefUpdate {
+            name: name.to_string(),
+            change,
+            old: old.map(|old| self.parse_ref_value(old)).transpose()?,
+            deref: !no_deref,
+        });
+        transaction.commit(&self.refs)
+    }
+
+    /// Apply the ref updates given in the format of `git update-ref --stdin`, all at once. With
+    /// `nul_terminated`, arguments are terminated by NUL characters rather than separated by
+    /// spaces, and commands are not terminated by new lines.
+    pub fn update_refs_from_stdin(
+        &self,
+        input: &str,
+        nul_terminated: bool,
+        message: Option<&str>,
+    ) -> Result<()> {
+        let new_transaction = || self.ref_transaction(message.unwrap_or_default());
+        let mut transaction = new_transaction()?;
+        let mut no_deref = false;
+
+        let mut fields: Box<dyn Iterator<Item = &str>> = if nul_terminated {
+            Box::new(input.split_terminator('\0'))
+        } else {
+            Box::new(input.lines().filter(|l| !l.is_empty()))
+        };
+        while let Some(line) = fields.next() {
+            let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+            // Arguments following the ref: on the same line, or in the following fields
+            let num_args = match command {
+                "update" => 2,
+                "create" | "delete" | "verify" => 1,
+                _ => 0,
             };
-            buf.put(entry.mode.as_bytes());
-            buf.put_u8(b' ');
-            buf.put(entry.name.as_bytes());
-            buf.put_u8(0);
-            buf.put(entry.sha1.as_bytes());
+            let (name, args) = if nul_terminated {
+                let args = (0..num_args)
+                    .map(|_| fields.next().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow!("{command}: missing arguments"))?;
+                (rest.to_string(), args)
+            } else {
+                let mut parts = rest.split(' ').map(str::to_string);
+                let name = parts.next().unwrap_or_default();
+                (name, parts.collect())
+            };
+            let arg = |i: usize| args.get(i).map(String::as_str).filter(|a| !a.is_empty());
+            let old = |i: usize| arg(i).map(|old| self.parse_ref_value(old)).transpose();
+
+            let (change, old) = match command {
+                "update" => {
+                    let new = arg(0).ok_or_else(|| anyhow!("update {name}: missing <new-oid>"))?;
+                    let change = match self.parse_ref_value(new)? {
+                        Some(oid) => RefChange::Set(RefValue::Direct(oid)),
+                        None => RefChange::Delete,
+                    };
+                    (change, old(1)?)
+                }
+                "create" => {
+                    let new = arg(0).ok_or_else(|| anyhow!("create {name}: missing <new-oid>"))?;
+                    let Some(oid) = self.parse_ref_value(new)? else {
+                        bail!("create {name}: zero <new-oid>");
+                    };
+                    (RefChange::Set(RefValue::Direct(oid)), Some(None))
+                }
+                "delete" => (RefChange::Delete, old(0)?),
+                // A missing old value means the ref must not exist
+                "verify" => (RefChange::Verify, Some(old(0)?.flatten())),
+                "option" => {
+                    ensure!(name == "no-deref", "option unknown: {name}");
+                    no_deref = true;
+                    continue;
+                }
+                "start" | "prepare" => continue,
+                "commit" => {
+                    std::mem::replace(&mut transaction, new_transaction()?).commit(&self.refs)?;
+                    continue;
+                }
+                "abort" => {
+                    transaction = new_transaction()?;
+                    continue;
+                }
+                _ => bail!("Unknown command: {line}"),
+            };
+            transaction.add(RefUpdate {
+                name,
+                change,
+                old,
+                deref: !no_deref,
+            });
+            // The option only applies to the next command
+            no_deref = false;
         }
 
-        let tree_object = Object::tree(buf.into());
-        let sha1 = self.store_object(tree_object)?;
+        transaction.commit(&self.refs)
+    }
 
-        Ok(sha1)
+    /// Value given to `update-ref`: an object id or any revision. The zero id is `None`.
+    fn parse_ref_value(&self, value: &str) -> Result<Option<ObjectId>> {
+        let oid = self
+            .resolve_revision(value)
+            .with_context(|| format!("{value}: not a valid SHA1"))?;
+        Ok((oid != ObjectId::ZERO).then_some(oid))
     }
 
-    pub fn commit_tree(&self, tree_oid: ObjectId, parent: ObjectId, message: String) -> Result<()> {
-        let mut buf = String::new();
-        let now = SystemTime::now();
-        let now_seconds = now.duration_since(UNIX_EPOCH)?.as_secs();
+    /// Full name of the ref meant by `name` in reflog commands, e.g `refs/heads/master` for
+    /// `master`
+    fn reflog_ref(&self, name: &str) -> Result<String> {
+        for full_name in dwim_names(name) {
+            if self.refs.has_reflog(&full_name) || self.refs.read(&full_name)?.is_some() {
+                return Ok(full_name);
+            }
+        }
+        bail!("No such ref: {name}")
+    }
 
-        buf.push_str(&format!("tree {tree_oid}\n"));
-        buf.push_str(&format!("parent {parent}\n"));
-        buf.push_str(&format!(
-            "author {} <{}> {} {}\n",
-            "Joe Author", "joe.author@example.com", now_seconds, "+1000",
-        ));
-        buf.push_str(&format!(
-            "committer {} <{}> {} {}\n",
-            "Bob Committer", "bob.committer@example.com", now_seconds, "+1000",
-        ));
-        buf.push('\n');
-        buf.push_str(&message);
-        buf.push('\n');
+    /// Print the reflog of a ref (HEAD by default), most recent entry first
+    pub fn reflog_show(&self, name: Option<&str>) -> Result<()> {
+        let name = name.unwrap_or("HEAD");
+        let entries = self.refs.read_reflog(&self.reflog_ref(name)?)?;
+        let abbrev = self.abbrev_len()?;
+        for (i, entry) in entries.iter().rev().enumerate() {
+            let short = self.abbreviate(entry.new, abbrev)?;
+            println!("{short} {name}@{{{i}}}: {}", entry.message);
+        }
+        Ok(())
+    }
 
-        let object = Object::commit(buf.as_bytes().to_vec());
-        let sha = self.store_object(object)?;
+    /// Remove the entries older than `expire` (`gc.reflogExpire`, or 90 days by default) from the
+    /// reflogs of the given refs, or of all the refs with `all`
+    pub fn reflog_expire(&self, names: &[String], all: bool, expire: Option<&str>) -> Result<()> {
+        let config = self.config()?;
+        let expire = expire
+            .or_else(|| config.get("gc.reflogexpire"))
+            .unwrap_or("90.days.ago");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let threshold = date::parse_expiry(expire, now)?;
+
+        let names = if all {
+            self.refs.reflog_names()?
+        } else {
+            names
+                .iter()
+                .map(|name| self.reflog_ref(name))
+                .collect::<Result<_>>()?
+        };
+        for name in names {
+            let mut entries = self.refs.read_reflog(&name)?;
+            let len = entries.len();
+            entries.retain(|e| e.committer.timestamp >= threshold);
+            if entries.len() != len {
+                self.refs.write_reflog(&name, &entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete reflog entries given as `<ref>@{<n>}`, `<n>` counting from the most recent entry
+    pub fn reflog_delete(&self, specs: &[String]) -> Result<()> {
+        for spec in specs {
+            let invalid = || anyhow!("Not a reflog entry: {spec}");
+            let (name, index) = spec.rsplit_once("@{").ok_or_else(invalid)?;
+            let index: usize = index
+                .strip_suffix('}')
+                .and_then(|i| i.parse().ok())
+                .ok_or_else(invalid)?;
+
+            let name = self.reflog_ref(name)?;
+            let mut entries = self.refs.read_reflog(&name)?;
+            ensure!(index < entries.len(), "Reflog entry {spec} not found");
+            entries.remove(entries.len() - 1 - index);
+            self.refs.write_reflog(&name, &entries)?;
+        }
+        Ok(())
+    }
+
+    /// Validate a tag object and store it
+    pub fn mktag(&self, content: Vec<u8>) -> Result<()> {
+        let object = Object::tag(content);
+        let Some(tag) = object.as_tag() else {
+            bail!("Invalid tag object");
+        };
+        let target = self.get_object(tag.object)?;
+        ensure!(
+            target.object_type == tag.object_type,
+            "Tagged object {} is a {}, not a {}",
+            tag.object,
+            target.object_type,
+            tag.object_type
+        );
 
+        let sha = self.store_object(object)?;
         println!("{sha}");
 
         Ok(())
     }
 
-    pub fn clone<P: AsRef<Path>>(url: Url, dir: P) -> Result<Self> {
+    /// Clone the repo at `url` into `dir`. A bare clone has no work tree, and its branches are
+    /// the ones of the remote. A mirror clone is a bare clone of all the refs of the remote.
+    pub fn clone<P: AsRef<Path>>(url: Url, dir: P, bare: bool, mirror: bool) -> Result<Self> {
+        let bare = bare || mirror;
         println!("Cloning {url} into {}", dir.as_ref().display());
-        let client = GitClient::new(url);
+        let client = GitClient::new(url.clone());
 
         // Discover refs
         println!("Discovering refs...");
         let (_capabilities, advertised) = client.discover_refs()?;
-        // For now only ask for the first ref, which should be HEAD
-        // TODO: ask for all the refs
-        let reference = advertised.first().expect("At least 1 ref to be advertised");
+        // Ask for all the refs, so that we also get the objects of annotated tags
+        let mut wants = advertised
+            .iter()
+            .filter(|r| !r.is_peeled_tag())
+            .map(|r| r.oid)
+            .collect::<Vec<_>>();
+        wants.sort();
+        wants.dedup();
+        ensure!(!wants.is_empty(), "The remote didn't advertise any refs");
 
         // Fetch packfile
-        let mut pack_data = client.request_pack(reference.oid)?;
-        let pack_file = PackFile::parse(&mut pack_data)?;
-
+        let pack_data = client.request_pack(&wants)?;
         // create the requested directory and run `git init`
         let dir = dir.as_ref();
         create_dir(dir)?;
-        let repo = GitRepo::new(dir);
-        repo.init()?;
-
-        // explode packfile into loose objects
-        pack_file.explode_into_repo(&repo)?;
+        let repo = if bare {
+            GitRepo::new_bare(dir)
+        } else {
+            GitRepo::new(dir)
+        };
+        repo.init(None, None)?;
 
-        // create references
-        println!("Creating refs:");
-        let tags_dir = repo.refs_dir.join("tags");
-        let branches_dir = dir.join(".git/refs/remotes/origin");
-        fs::create_dir_all(&tags_dir)?;
-        fs::create_dir_all(&branches_dir)?;
-        let (tags, branches) = advertised
-            .iter()
-            .filter(|r| !r.is_peeled_tag())
-            .partition::<Vec<_>, _>(|r| r.is_tag());
-        for tag in tags {
-            let parts = tag.name.split('/').collect::<Vec<_>>();
-            let tag_name = parts.last().expect("Invalid tag name");
-            println!("\tCreating tag {}", tag_name);
-            let mut file = File::create(tags_dir.join(tag_name))?;
-            file.write_all(format!("{}\n", tag.oid).as_bytes())?;
-        }
-        for branch in branches {
-            let parts = branch.name.split('/').collect::<Vec<_>>();
-            let branch_name = parts.last().expect("Invalid branch name");
-            println!("\tCreating branch {}", branch_name);
-            let mut file = File::create(branches_dir.join(branch_name))?;
-            file.write_all(format!("{}\n", branch.oid).as_bytes())?;
-        }
+        // store packfile and its index in the repo
+        PackFile::write_into_repo(&pack_data, &repo)?;
 
         // set HEAD ref to HEAD of remote
         let remote_head = advertised
@@ -271,137 +1025,525 @@ impl GitRepo {
             .ok_or(anyhow!("The remote didn't send us a HEAD reference"))?;
         let remote_head_target = advertised
             .iter()
-            .find(|r| r.name != "HEAD" && r.oid == remote_head.oid)
+            .find(|r| r.name.starts_with("refs/heads/") && r.oid == remote_head.oid)
             .ok_or(anyhow!("No ref found as target of remote HEAD"))?;
-        // Create local branch for HEAD to point to
-        fs::write(
-            repo.git_dir.join(&remote_head_target.name),
-            format!("{}\n", remote_head_target.oid),
-        )?;
-        // Point HEAD to that local branch
-        fs::write(
-            repo.git_dir.join("HEAD"),
-            format!("ref: {}\n", remote_head_target.name),
-        )?;
-
-        // checkout HEAD
-        repo.checkout_head()?;
+        // Point HEAD to the local branch before creating it, so that HEAD's reflog starts too
+        repo.refs
+            .write("HEAD", &RefValue::Symbolic(remote_head_target.name.clone()))?;
+
+        // create references
+        println!("Creating refs:");
+        let mut transaction = repo.ref_transaction(&format!("clone: from {url}"))?;
+        let mut create_ref = |name: &str, oid: ObjectId| {
+            println!("\tCreating {name}");
+            transaction.add(RefUpdate {
+                name: name.to_string(),
+                change: RefChange::Set(RefValue::Direct(oid)),
+                old: None,
+                deref: false,
+            });
+        };
+        for r in advertised
+            .iter()
+            .filter(|r| !r.is_peeled_tag() && r.name != "HEAD")
+        {
+            let name = match r.name.strip_prefix("refs/heads/") {
+                _ if mirror => r.name.clone(),
+                Some(branch) if !bare => format!("refs/remotes/origin/{branch}"),
+                Some(_) => r.name.clone(),
+                None if r.is_tag() => r.name.clone(),
+                None => continue,
+            };
+            create_ref(&name, r.oid);
+        }
+        // Create local branch for HEAD to point to (bare clones already have it)
+        if !bare {
+            create_ref(&remote_head_target.name, remote_head_target.oid);
+        }
+        transaction.commit(&repo.refs)?;
+        if !bare {
+            if let Some(branch) = remote_head_target.name.strip_prefix("refs/heads/") {
+                repo.refs.write(
+                    "refs/remotes/origin/HEAD",
+                    &RefValue::Symbolic(format!("refs/remotes/origin/{branch}")),
+                )?;
+            }
+        }
+
+        // Remember where we cloned from, and make the local branch track the remote one
+        let mut config = ConfigFile::open(repo.git_dir.join("config"))?;
+        config.set("remote.origin.url", url.as_str())?;
+        if mirror {
+            config.set("remote.origin.fetch", "+refs/*:refs/*")?;
+            config.set("remote.origin.mirror", "true")?;
+        } else if !bare {
+            config.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+            if let Some(branch) = remote_head_target.name.strip_prefix("refs/heads/") {
+                config.set(&format!("branch.{branch}.remote"), "origin")?;
+                config.set(&format!("branch.{branch}.merge"), &remote_head_target.name)?;
+            }
+        }
+        config.write()?;
+
+        if !bare {
+            repo.checkout_head()?;
+        }
 
         Ok(repo)
     }
 
-    pub fn checkout_head(&self) -> Result<()> {
-        let head = self.resolve_head()?;
+    /// Print the commits reachable from the given revisions (HEAD by default), most recent first
+    pub fn log(&self, revisions: &[String], max_count: Option<usize>, oneline: bool) -> Result<()> {
+        let abbrev = self.abbrev_len()?;
+        let commits = self
+            .rev_list(revisions)?
+            .take(max_count.unwrap_or(usize::MAX));
+        for (i, commit) in commits.enumerate() {
+            let (oid, commit) = commit?;
+            let message = String::from_utf8_lossy(&commit.message);
+            if oneline {
+                let summary = message.lines().next().unwrap_or_default();
+                println!("{} {summary}", self.abbreviate(oid, abbrev)?);
+                continue;
+            }
 
-        let Some(target_commit) = self.get_object(head)?.as_commit() else {
-            bail!("HEAD doesn't point to a commit");
-        };
-        dbg!(&target_commit);
+            if i > 0 {
+                println!();
+            }
+            println!("commit {oid}");
+            if commit.parents.len() > 1 {
+                let parents = commit
+                    .parents
+                    .iter()
+                    .map(|parent| self.abbreviate(*parent, abbrev))
+                    .collect::<Result<Vec<_>>>()?;
+                println!("Merge: {}", parents.join(" "));
+            }
+            let author = &commit.author;
+            println!("Author: {} <{}>", author.name, author.email);
+            let date = date::format_date(author.timestamp, author.tz_offset);
+            println!("Date:   {date}\n");
+            for line in message.lines() {
+                println!("    {line}");
+            }
+        }
+        Ok(())
+    }
 
-        self.checkout_tree_in_dir(target_commit.tree, &self.path)?;
+    fn resolve_head(&self) -> Result<ObjectId> {
+        self.head_commit()?
+            .ok_or_else(|| anyhow!("HEAD doesn't point to any commit yet"))
+    }
 
-        Ok(())
+    /// State of HEAD: on a branch, or detached at a commit
+    pub fn head(&self) -> Result<Head> {
+        match self.refs.read("HEAD")? {
+            Some(RefValue::Symbolic(target)) => {
+                let (name, oid) = self.refs.resolve_symbolic(&target)?;
+                Ok(Head::Branch(name, oid))
+            }
+            Some(RefValue::Direct(oid)) => Ok(Head::Detached(oid)),
+            None => bail!("HEAD not found"),
+        }
+    }
+
+    /// Name of the branch HEAD points to, e.g `refs/heads/master`, or `HEAD` itself if it's
+    /// detached
+    fn head_ref(&self) -> Result<String> {
+        Ok(self.refs.resolve_symbolic("HEAD")?.0)
+    }
+
+    /// Resolve HEAD, or return `None` if the current branch doesn't have any commit yet
+    fn head_commit(&self) -> Result<Option<ObjectId>> {
+        self.refs.resolve("HEAD")
+    }
+
+    /// List all the files in a tree and its sub-trees, by path
+    pub fn flatten_tree(&self, tree: ObjectId) -> Result<BTreeMap<String, (u32, ObjectId)>> {
+        let mut files = BTreeMap::new();
+        self.flatten_tree_into(tree, "", &mut files)?;
+        Ok(files)
     }
 
-    fn checkout_tree_in_dir<P: AsRef<Path>>(&self, tree: ObjectId, dir: P) -> Result<()> {
+    fn flatten_tree_into(
+        &self,
+        tree: ObjectId,
+        prefix: &str,
+        files: &mut BTreeMap<String, (u32, ObjectId)>,
+    ) -> Result<()> {
         let Some(tree) = self.get_object(tree)?.as_tree() else {
-            bail!("Trying to checkout an object that's not a tree");
+            bail!("Object {tree} is not a tree");
         };
-
         for entry in tree.entries {
+            let path = format!("{prefix}{}", entry.name);
             if entry.object_type == ObjectType::Tree {
-                // directory
-                let new_dir = dir.as_ref().join(entry.name);
-                fs::create_dir_all(&new_dir)?;
-                self.checkout_tree_in_dir(entry.sha1, &new_dir)?;
+                self.flatten_tree_into(entry.sha1, &format!("{path}/"), files)?;
             } else {
-                // file
-                let file = dir.as_ref().join(entry.name);
-                let blob = self.get_object(entry.sha1)?;
-                fs::write(file, blob.content)?;
+                let mode = u32::from_str_radix(&entry.mode, 8)
+                    .with_context(|| format!("Invalid mode for {path}: {}", entry.mode))?;
+                files.insert(path, (mode, entry.sha1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn store_object(&self, object: Object) -> Result<ObjectId> {
+        let header = object.header();
+        let oid = object.id();
+
+        let path = self.get_object_path(oid);
+        let dir = path.parent().expect("object path to have a parent");
+        // Create parent directory if needed
+        fs::create_dir_all(dir)?;
+        // Create objectfile
+        let mut object_file = fs::File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        // Wrap object file in zlib encoder
+        let mut writer = flate2::write::ZlibEncoder::new(&mut object_file, Compression::fast());
+
+        // write header
+        writer.write_all(header.as_bytes())?;
+        // write content
+        writer.write_all(&object.content)?;
+
+        Ok(oid)
+    }
+
+    pub fn get_object(&self, oid: ObjectId) -> Result<Object> {
+        // A packed object can be a delta of an object in another pack, or of a loose one
+        let mut deltas = Vec::new();
+        let mut next = oid;
+        let mut object = loop {
+            ensure!(
+                deltas.len() <= MAX_DELTA_DEPTH,
+                "Delta chain of {oid} is too long"
+            );
+            let path = self.get_object_path(next);
+            if path.exists() {
+                break self.read_loose_object(&path)?;
+            }
+            match self.get_packed_object(next)? {
+                Some(PackedObject::Full(object)) => break object,
+                Some(PackedObject::Deltas {
+                    base,
+                    deltas: chain,
+                }) => {
+                    deltas.extend(chain);
+                    next = base;
+                }
+                None => bail!("Object {next} not found"),
+            }
+        };
+        for delta in deltas.into_iter().rev() {
+            object.content = apply_delta(&object.content, delta)?;
+        }
+        Ok(object)
+    }
+
+    fn read_loose_object(&self, path: &Path) -> Result<Object> {
+        let file = fs::File::open(path)?;
+        let file = BufReader::new(file);
+        let reader = flate2::bufread::ZlibDecoder::new(file);
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+
+        // read header
+        reader.read_until(0u8, &mut buf)?;
+        let obj_type = buf
+            .split(|b| *b == b' ')
+            .next()
+            .and_then(|t| std::str::from_utf8(t).ok())
+            .ok_or(GitError::InvalidObjectType)?
+            .parse::<ObjectType>()?;
+
+        buf.clear();
+        let _ = reader.read_to_end(&mut buf)?;
+
+        Ok(Object {
+            object_type: obj_type,
+            content: buf.into(),
+        })
+    }
+
+    /// Look for the given object in the pack files of the repo
+    fn get_packed_object(&self, oid: ObjectId) -> Result<Option<PackedObject>> {
+        if let Some(object) = self.find_in_packs(oid)? {
+            return Ok(Some(object));
+        }
+        // A new pack may have been added since we last looked
+        self.reload_packs()?;
+        self.find_in_packs(oid)
+    }
+
+    fn find_in_packs(&self, oid: ObjectId) -> Result<Option<PackedObject>> {
+        for pack in self.packs.borrow().iter() {
+            if let Some(object) = pack.read_object(oid)? {
+                return Ok(Some(object));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Refresh the list of pack files, keeping the ones that were already loaded
+    fn reload_packs(&self) -> Result<()> {
+        let pack_dir = self.object_dir.join("pack");
+        if !pack_dir.is_dir() {
+            return Ok(());
+        }
+
+        let mut packs = Vec::new();
+        for entry in fs::read_dir(pack_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "idx") {
+                continue;
+            }
+            let pack_path = path.with_extension("pack");
+            let existing = self
+                .packs
+                .borrow()
+                .iter()
+                .find(|p| p.path == pack_path)
+                .cloned();
+            match existing {
+                Some(pack) => packs.push(pack),
+                None => packs.push(Rc::new(Pack::open(&path)?)),
+            }
+        }
+        *self.packs.borrow_mut() = packs;
+
+        Ok(())
+    }
+
+    /// Ids of the objects, loose or packed, whose hex form starts with `prefix`
+    pub fn find_objects_by_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let prefix = prefix.to_ascii_lowercase();
+        ensure!(
+            prefix.len() >= 2 && prefix.bytes().all(|b| b.is_ascii_hexdigit()),
+            "Invalid object id prefix: {prefix}"
+        );
+
+        // Loose objects are in a directory named after the first two hex digits
+        let (dirname, rest) = prefix.split_at(2);
+        let mut oids = Vec::new();
+        match fs::read_dir(self.object_dir.join(dirname)) {
+            Ok(entries) => {
+                for entry in entries {
+                    let filename = entry?.file_name().to_string_lossy().into_owned();
+                    if !filename.starts_with(rest) {
+                        continue;
+                    }
+                    if let Ok(oid) = ObjectId::from_str(&format!("{dirname}{filename}")) {
+                        oids.push(oid);
+                    }
+                }
             }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Reading objects"),
         }
 
-        Ok(())
+        self.reload_packs()?;
+        for pack in self.packs.borrow().iter() {
+            oids.extend(pack.index.find_by_prefix(&prefix));
+        }
+        oids.sort();
+        oids.dedup();
+        Ok(oids)
     }
 
-    fn resolve_head(&self) -> Result<ObjectId> {
-        let head = fs::read_to_string(self.git_dir.join("HEAD")).context("Failed to read HEAD")?;
-        let head_ref = head
-            .strip_prefix("ref: ")
-            .ok_or_else(|| anyhow!("Invalid symref: {head}"))?
-            .trim();
-        let target_ref =
-            fs::read_to_string(self.git_dir.join(head_ref)).context("Failed to read {head_ref}")?;
-        let target_ref = target_ref.trim().to_string();
+    /// The object whose id starts with `prefix`, if there's a single one
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Option<ObjectId>> {
+        let candidates = self.find_objects_by_prefix(prefix)?;
+        if candidates.len() <= 1 {
+            return Ok(candidates.first().copied());
+        }
+        let mut message = format!("Short object id {prefix} is ambiguous. The candidates are:");
+        let abbrev = self.abbrev_len()?;
+        for oid in candidates {
+            let short = self.abbreviate(oid, abbrev)?;
+            let object_type = match self.get_object(oid) {
+                Ok(object) => object.object_type.to_string(),
+                Err(_) => "bad object".to_string(),
+            };
+            message.push_str(&format!("\n  {short} {object_type}"));
+        }
+        bail!(message)
+    }
 
-        ObjectId::from_str(&target_ref)
+    /// Shortest abbreviation of an object id, of at least `min_len` hex digits, which doesn't
+    /// match any other object
+    pub fn abbreviate(&self, oid: ObjectId, min_len: usize) -> Result<String> {
+        let hex = oid.to_string();
+        let mut len = min_len.clamp(4, 40);
+        while len < 40
+            && self
+                .find_objects_by_prefix(&hex[..len])?
+                .iter()
+                .any(|other| *other != oid)
+        {
+            len += 1;
+        }
+        Ok(hex[..len].to_string())
     }
 
-    pub fn store_object(&self, object: Object) -> Result<ObjectId> {
-        let header = format!("{} {}\0", object.object_type, object.content.len());
+    /// Length of abbreviated object ids: `core.abbrev`, or by default enough hex digits for
+    /// the number of objects in the repo, and at least 7
+    pub fn abbrev_len(&self) -> Result<usize> {
+        let config = self.config()?;
+        match config.get("core.abbrev") {
+            Some(value) if value.eq_ignore_ascii_case("no") => return Ok(40),
+            Some(value) if !value.eq_ignore_ascii_case("auto") => {
+                let len = config.get_int("core.abbrev")?.unwrap_or_default();
+                ensure!((4..=40).contains(&len), "Invalid core.abbrev: {value}");
+                return Ok(len as usize);
+            }
+            _ => {}
+        }
 
-        // compute SHA1
-        let mut hasher = Sha1::new();
-        hasher.update(header.as_bytes());
-        hasher.update(&object.content);
-        let result = hasher.finalize();
-        let sha1 = hex::encode(result);
-        let oid = ObjectId::from_str(&sha1)?;
+        // Like git: n hex digits are enough to tell about 2^(2n) objects apart
+        let bits = usize::BITS - self.count_objects()?.leading_zeros();
+        Ok((bits as usize).div_ceil(2).max(7))
+    }
 
-        let path = self.get_object_path(oid);
-        let dir = path.parent().expect("object path to have a parent");
-        // Create parent directory if needed
-        fs::create_dir_all(dir)?;
-        // Create objectfile
-        let mut object_file = fs::File::options().create(true).write(true).open(path)?;
-        // Wrap object file in zlib encoder
-        let mut writer = flate2::write::ZlibEncoder::new(&mut object_file, Compression::fast());
+    /// Number of objects in the repo, loose or packed
+    fn count_objects(&self) -> Result<usize> {
+        let mut count = 0;
+        for i in 0..=0xff {
+            match fs::read_dir(self.object_dir.join(format!("{i:02x}"))) {
+                Ok(entries) => count += entries.count(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("Reading objects"),
+            }
+        }
+        self.reload_packs()?;
+        for pack in self.packs.borrow().iter() {
+            count += pack.index.entries.len();
+        }
+        Ok(count)
+    }
 
-        // write header
-        writer.write_all(header.as_bytes())?;
-        // write content
-        writer.write_all(&object.content)?;
+    pub fn get_object_path(&self, oid: ObjectId) -> PathBuf {
+        let sha = oid.to_string();
+        let (dirname, filename) = sha.split_at(2);
+        self.object_dir.join(dirname).join(filename)
+    }
 
-        Ok(oid)
+    /// Read `.git/index`. A missing index is the same as an empty one.
+    pub fn read_index(&self) -> Result<Index> {
+        let path = self.git_dir.join("index");
+        if !path.exists() {
+            return Ok(Index::default());
+        }
+        let mut data: Bytes = fs::read(path).context("Reading index")?.into();
+        Index::parse(&mut data).context("Parsing index")
     }
 
-    pub fn get_object(&self, oid: ObjectId) -> Result<Object> {
-        let path = self.get_object_path(oid);
+    /// Write `.git/index`, going through a lock file so that readers never see a partial index
+    pub fn write_index(&self, index: &Index) -> Result<()> {
+        let mut lock =
+            LockFile::acquire(&self.git_dir.join("index")).context("Unable to lock the index")?;
+        lock.write(&index.as_bytes())?;
+        lock.commit()?;
+        Ok(())
+    }
+}
 
-        let file = fs::File::open(path)?;
-        let file = BufReader::new(file);
-        let reader = flate2::bufread::ZlibDecoder::new(file);
-        let mut reader = BufReader::new(reader);
-        let mut buf = Vec::new();
+/// Whether `path` is inside the directory `dir` (both in index format). Everything is inside the
+/// root directory, i.e the empty path.
+fn is_in_dir(path: impl AsRef<[u8]>, dir: impl AsRef<[u8]>) -> bool {
+    let dir = dir.as_ref();
+    dir.is_empty()
+        || path
+            .as_ref()
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with(b"/"))
+}
 
-        // read header
-        reader.read_until(0u8, &mut buf)?;
-        let obj_type = if buf.starts_with(b"blob ") {
-            ObjectType::Blob
-        } else if buf.starts_with(b"tree ") {
-            ObjectType::Tree
-        } else if buf.starts_with(b"commit ") {
-            ObjectType::Commit
+/// Copy the content of a template directory, without overwriting existing files
+fn copy_template(template: &Path, dest: &Path) -> Result<()> {
+    for entry in fs::read_dir(template)? {
+        let entry = entry?;
+        let dest = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            fs::create_dir_all(&dest)?;
+            copy_template(&entry.path(), &dest)?;
+        } else if dest.symlink_metadata().is_ok() {
+            continue;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &dest)?;
         } else {
-            bail!("Invalid object type");
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Git directory of a work tree whose root is `dir`, if it's one
+fn find_git_dir(dir: &Path) -> Result<Option<PathBuf>> {
+    let dot_git = dir.join(".git");
+    if dot_git.is_file() {
+        // A `gitdir: <path>` pointer, as used by worktrees and submodules
+        let content = fs::read_to_string(&dot_git)?;
+        let Some(git_dir) = content.trim_end().strip_prefix("gitdir: ") else {
+            bail!("Invalid gitfile format: {}", dot_git.display());
         };
+        let git_dir = normalize_path(&dir.join(git_dir));
+        ensure!(
+            is_git_dir(&git_dir),
+            "Not a git repository: {}",
+            git_dir.display()
+        );
+        return Ok(Some(git_dir));
+    }
 
-        buf.clear();
-        let _ = reader.read_to_end(&mut buf)?;
+    Ok(is_git_dir(&dot_git).then_some(dot_git))
+}
 
-        Ok(Object {
-            object_type: obj_type,
-            content: buf.into(),
-        })
+/// Whether `dir` looks like a git directory
+fn is_git_dir(dir: &Path) -> bool {
+    let common_dir = common_dir(dir);
+    dir.join("HEAD").is_file()
+        && common_dir.join("objects").is_dir()
+        && common_dir.join("refs").is_dir()
+}
+
+/// Directory with the objects, refs and config of the repo. It's the git directory itself,
+/// except for linked worktrees, where a `commondir` file points to the main git directory.
+pub(crate) fn common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => normalize_path(&git_dir.join(common_dir.trim_end())),
+        Err(_) => git_dir.to_owned(),
     }
+}
 
-    pub fn get_object_path(&self, oid: ObjectId) -> PathBuf {
-        let sha = oid.to_string();
-        let (dirname, filename) = sha.split_at(2);
-        self.git_dir
-            .join(["objects", dirname, filename].iter().collect::<PathBuf>())
+/// Absolute paths in `ceilings`, the value of `GIT_CEILING_DIRECTORIES`
+fn ceiling_dirs(ceilings: Option<OsString>) -> Vec<PathBuf> {
+    let Some(ceilings) = ceilings else {
+        return Vec::new();
+    };
+    env::split_paths(&ceilings)
+        .filter(|c| c.is_absolute())
+        .map(|c| c.canonicalize().unwrap_or_else(|_| normalize_path(&c)))
+        .collect()
+}
+
+/// Lexically resolve `.` and `..` components, without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
     }
+    normalized
 }
 
 pub struct Object {
@@ -431,6 +1573,26 @@ impl Object {
         }
     }
 
+    /// Header of the object, as it is stored in loose object files and hashed
+    pub fn header(&self) -> String {
+        format!("{} {}\0", self.object_type, self.content.len())
+    }
+
+    /// Compute the SHA-1 of the object
+    pub fn id(&self) -> ObjectId {
+        let mut hasher = Sha1::new();
+        hasher.update(self.header().as_bytes());
+        hasher.update(&self.content);
+        ObjectId(hasher.finalize().into())
+    }
+
+    pub fn tag(content: Vec<u8>) -> Self {
+        Self {
+            object_type: ObjectType::Tag,
+            content: content.into(),
+        }
+    }
+
     pub fn as_commit(&self) -> Option<Commit> {
         if let ObjectType::Commit = self.object_type {
             let mut content = self.content.clone();
@@ -440,6 +1602,15 @@ impl Object {
         }
     }
 
+    pub fn as_tag(&self) -> Option<Tag> {
+        if let ObjectType::Tag = self.object_type {
+            let mut content = self.content.clone();
+            Tag::parse(&mut content).ok()
+        } else {
+            None
+        }
+    }
+
     pub fn as_tree(&self) -> Option<Tree> {
         if let ObjectType::Tree = self.object_type {
             let mut content = self.content.clone();
@@ -455,6 +1626,7 @@ pub enum ObjectType {
     Blob,
     Tree,
     Commit,
+    Tag,
 }
 
 impl Display for ObjectType {
@@ -463,6 +1635,7 @@ impl Display for ObjectType {
             ObjectType::Blob => "blob",
             ObjectType::Tree => "tree",
             ObjectType::Commit => "commit",
+            ObjectType::Tag => "tag",
         };
         write!(f, "{s}")
     }
@@ -476,34 +1649,362 @@ impl FromStr for ObjectType {
             "blob" => Ok(Self::Blob),
             "tree" => Ok(Self::Tree),
             "commit" => Ok(Self::Commit),
+            "tag" => Ok(Self::Tag),
             _ => Err(GitError::InvalidObjectType),
         }
     }
 }
 
+/// A commit
+///
+/// `tree`, `parent`, `author` and `committer` must come first, in that order, like git requires.
+/// The headers after them are kept in their original order, so that commits round-trip byte for
+/// byte.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Commit {
     pub tree: ObjectId,
-    // pub parent: Vec<Sha>,
-    // TODO author, commiter, message....
+    pub parents: Vec<ObjectId>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// Other headers (e.g `encoding`, `mergetag` or `gpgsig`), in order. Multi-line values are
+    /// joined with `\n`.
+    pub headers: Vec<(String, Bytes)>,
+    /// Raw message, in the `encoding` header if there is one
+    pub message: Bytes,
 }
 
 impl Commit {
     pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
-        let mut reader = bytes.reader();
-        let tree = read_prefixed_line(&mut reader, "tree ")?;
+        let content = bytes.copy_to_bytes(bytes.remaining());
+        let headers_end = content
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(|| anyhow!("Missing blank line after commit headers"))?;
+        let headers = &content[..headers_end];
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut other_headers = Vec::new();
+        for (key, value) in parse_headers(headers)? {
+            match key.as_str() {
+                "tree" if tree.is_none() => tree = Some(parse_oid(&value)?),
+                "parent" if tree.is_some() && author.is_none() => parents.push(parse_oid(&value)?),
+                "author" if tree.is_some() && author.is_none() => {
+                    author = Some(Signature::parse(&value)?)
+                }
+                "committer" if author.is_some() && committer.is_none() => {
+                    committer = Some(Signature::parse(&value)?)
+                }
+                _ if committer.is_none() => bail!("Unexpected '{key}' header in commit"),
+                _ => other_headers.push((key, value)),
+            }
+        }
+
+        Ok(Self {
+            tree: tree.ok_or_else(|| anyhow!("Missing tree in commit"))?,
+            parents,
+            author: author.ok_or_else(|| anyhow!("Missing author in commit"))?,
+            committer: committer.ok_or_else(|| anyhow!("Missing committer in commit"))?,
+            headers: other_headers,
+            message: content.slice(headers_end + 2..),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        push_header(&mut buf, "tree", self.tree.to_string().as_bytes());
+        for parent in &self.parents {
+            push_header(&mut buf, "parent", parent.to_string().as_bytes());
+        }
+        push_header(&mut buf, "author", &self.author.as_bytes());
+        push_header(&mut buf, "committer", &self.committer.as_bytes());
+        for (key, value) in &self.headers {
+            push_header(&mut buf, key, value);
+        }
+        buf.put_u8(b'\n');
+        buf.put(self.message.clone());
+        buf.freeze()
+    }
+
+    /// Value of the first `key` header after the committer, e.g `encoding` or `gpgsig`
+    pub fn header(&self, key: &str) -> Option<&Bytes> {
+        header(&self.headers, key)
+    }
+}
+
+fn header<'a>(headers: &'a [(String, Bytes)], key: &str) -> Option<&'a Bytes> {
+    headers.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Parse the headers of a commit or tag, joining continuation lines (which start with a space).
+/// Values are kept as bytes, as they are in the encoding of the object.
+fn parse_headers(headers: &[u8]) -> Result<Vec<(String, Bytes)>> {
+    let mut parsed: Vec<(String, BytesMut)> = Vec::new();
+    for line in headers.split(|b| *b == b'\n') {
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let Some((_, value)) = parsed.last_mut() else {
+                bail!(
+                    "Unexpected continuation line: {}",
+                    String::from_utf8_lossy(line)
+                );
+            };
+            value.put_u8(b'\n');
+            value.put(continuation);
+        } else {
+            let space = line
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or_else(|| anyhow!("Invalid header: {}", String::from_utf8_lossy(line)))?;
+            let key = String::from_utf8_lossy(&line[..space]).into_owned();
+            parsed.push((key, BytesMut::from(&line[space + 1..])));
+        }
+    }
+
+    Ok(parsed
+        .into_iter()
+        .map(|(key, value)| (key, value.freeze()))
+        .collect())
+}
+
+fn push_header(buf: &mut BytesMut, key: &str, value: &[u8]) {
+    buf.put(key.as_bytes());
+    buf.put_u8(b' ');
+    for (i, line) in value.split(|b| *b == b'\n').enumerate() {
+        if i > 0 {
+            buf.put(&b"\n "[..]);
+        }
+        buf.put(line);
+    }
+    buf.put_u8(b'\n');
+}
+
+/// Parse a hex object id from a header value
+fn parse_oid(value: &[u8]) -> Result<ObjectId> {
+    ObjectId::from_str(std::str::from_utf8(value).context("Invalid object id")?)
+}
+
+/// Identity of an author, committer or tagger, along with a timestamp
+///
+/// Signatures are compared by their fields only, not by the bytes they were parsed from.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the epoch
+    pub timestamp: i64,
+    /// Timezone offset from UTC, in minutes
+    pub tz_offset: i32,
+    /// The signature as it was parsed, which is written back as long as the fields still match
+    /// it, so that objects keep their id: the name may have extra spaces, or not be UTF-8, and
+    /// the timezone may be `-0000`
+    raw: Option<Bytes>,
+}
+
+impl Signature {
+    pub fn new(name: String, email: String, timestamp: i64, tz_offset: i32) -> Self {
+        Self {
+            name,
+            email,
+            timestamp,
+            tz_offset,
+            raw: None,
+        }
+    }
+
+    /// Parse `<name> <<email>> <timestamp> <tz>`. The name and email are decoded lossily if
+    /// they aren't UTF-8, but the original bytes are kept in `raw`.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let s = String::from_utf8_lossy(bytes);
+        let (name, rest) = s
+            .split_once('<')
+            .ok_or_else(|| anyhow!("Missing email in signature: {s}"))?;
+        let (email, date) = rest
+            .rsplit_once('>')
+            .ok_or_else(|| anyhow!("Missing email in signature: {s}"))?;
+        let (timestamp, tz) = date
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Missing date in signature: {s}"))?;
+        let timestamp = timestamp.parse()?;
+        ensure!(
+            tz.len() == 5 && (tz.starts_with('+') || tz.starts_with('-')),
+            "Invalid timezone in signature: {s}"
+        );
+        let hours: i32 = tz[1..3].parse()?;
+        let minutes: i32 = tz[3..5].parse()?;
+        let tz_offset = if tz.starts_with('-') { -1 } else { 1 } * (hours * 60 + minutes);
 
         Ok(Self {
-            tree: ObjectId::from_str(&tree)?,
+            name: name.trim_end().to_string(),
+            email: email.to_string(),
+            timestamp,
+            tz_offset,
+            raw: Some(Bytes::copy_from_slice(bytes)),
         })
     }
+
+    /// The signature as it appears in objects
+    pub fn as_bytes(&self) -> Bytes {
+        match self.raw() {
+            Some(raw) => raw.clone(),
+            None => self.to_string().into(),
+        }
+    }
+
+    /// The parsed signature, unless a field was changed since
+    fn raw(&self) -> Option<&Bytes> {
+        let raw = self.raw.as_ref()?;
+        let parsed = Self::parse(raw).ok()?;
+        (parsed == *self).then_some(raw)
+    }
+}
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.email == other.email
+            && self.timestamp == other.timestamp
+            && self.tz_offset == other.tz_offset
+    }
+}
+
+impl Eq for Signature {}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(raw) = self.raw() {
+            return write!(f, "{}", String::from_utf8_lossy(raw));
+        }
+        let sign = if self.tz_offset < 0 { '-' } else { '+' };
+        let offset = self.tz_offset.abs();
+        write!(
+            f,
+            "{} <{}> {} {}{:02}{:02}",
+            self.name,
+            self.email,
+            self.timestamp,
+            sign,
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::parse(s.as_bytes())
+    }
+}
+
+/// Markers for the start of a signature at the end of a tag message
+const SIGNATURE_MARKERS: [&str; 3] = [
+    "-----BEGIN PGP SIGNATURE-----",
+    "-----BEGIN PGP MESSAGE-----",
+    "-----BEGIN SSH SIGNATURE-----",
+];
+
+/// An annotated tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    /// The tagged object
+    pub object: ObjectId,
+    /// Type of the tagged object
+    pub object_type: ObjectType,
+    /// Name of the tag
+    pub tag: String,
+    /// Identity of the tagger. Very old tags don't have one.
+    pub tagger: Option<Signature>,
+    /// Headers after the `tagger`, in order
+    pub headers: Vec<(String, Bytes)>,
+    pub message: Bytes,
+    /// Signature appended to the message, for signed tags
+    pub signature: Option<Bytes>,
 }
 
-fn read_prefixed_line(r: &mut impl BufRead, prefix: &str) -> Result<String> {
-    let mut buf = String::new();
-    r.read_line(&mut buf)?;
-    let data = buf.strip_prefix(prefix).expect("invalid data").trim();
-    Ok(data.to_string())
+impl Tag {
+    pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        let content = bytes.copy_to_bytes(bytes.remaining());
+        let end = content
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(|| anyhow!("Missing blank line after tag headers"))?;
+        let (headers, body) = (&content[..end], content.slice(end + 2..));
+
+        let mut object = None;
+        let mut object_type = None;
+        let mut tag = None;
+        let mut tagger = None;
+        let mut other_headers = Vec::new();
+        for (key, value) in parse_headers(headers)? {
+            let text = || String::from_utf8(value.to_vec()).context("Invalid tag header");
+            match key.as_str() {
+                "object" if object.is_none() => object = Some(parse_oid(&value)?),
+                "type" if object.is_some() && object_type.is_none() => {
+                    object_type = Some(text()?.parse()?)
+                }
+                "tag" if object_type.is_some() && tag.is_none() => tag = Some(text()?),
+                "tagger" if tag.is_some() && tagger.is_none() && other_headers.is_empty() => {
+                    tagger = Some(Signature::parse(&value)?)
+                }
+                _ if tag.is_none() => bail!("Unexpected '{key}' header in tag"),
+                _ => other_headers.push((key, value)),
+            }
+        }
+
+        // The signature, if any, starts at the last marker line, like in git: the message may
+        // quote another signature
+        let sig_start = std::iter::once(0)
+            .chain(
+                body.iter()
+                    .enumerate()
+                    .filter(|(_, b)| **b == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .filter(|i| {
+                SIGNATURE_MARKERS
+                    .iter()
+                    .any(|m| body[*i..].starts_with(m.as_bytes()))
+            })
+            .last();
+        let (message, signature) = match sig_start {
+            Some(i) => (body.slice(..i), Some(body.slice(i..))),
+            None => (body, None),
+        };
+
+        Ok(Self {
+            object: object.ok_or_else(|| anyhow!("Missing object in tag"))?,
+            object_type: object_type.ok_or_else(|| anyhow!("Missing type in tag"))?,
+            tag: tag.ok_or_else(|| anyhow!("Missing tag name in tag"))?,
+            tagger,
+            headers: other_headers,
+            message,
+            signature,
+        })
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        push_header(&mut buf, "object", self.object.to_string().as_bytes());
+        push_header(&mut buf, "type", self.object_type.to_string().as_bytes());
+        push_header(&mut buf, "tag", self.tag.as_bytes());
+        if let Some(tagger) = &self.tagger {
+            push_header(&mut buf, "tagger", &tagger.as_bytes());
+        }
+        for (key, value) in &self.headers {
+            push_header(&mut buf, key, value);
+        }
+        buf.put_u8(b'\n');
+        buf.put(self.message.clone());
+        if let Some(signature) = &self.signature {
+            buf.put(signature.clone());
+        }
+
+        buf.freeze()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -512,6 +2013,19 @@ pub struct Tree {
 }
 
 impl Tree {
+    /// Create a tree from its entries, sorting them the way git expects
+    pub fn new(mut entries: Vec<TreeEntry>) -> Self {
+        // Trees are sorted as if their name ended with a `/`
+        entries.sort_by_cached_key(|e| {
+            let mut key = e.name.as_bytes().to_vec();
+            if e.object_type == ObjectType::Tree {
+                key.push(b'/');
+            }
+            key
+        });
+        Self { entries }
+    }
+
     pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
         let mut entries = Vec::new();
 
@@ -522,6 +2036,24 @@ impl Tree {
 
         Ok(Tree { entries })
     }
+
+    pub fn entries(&self) -> &[TreeEntry] {
+        &self.entries
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        for entry in &self.entries {
+            // Each tree entry as the following format:
+            // `[mode] [Object name]\0[SHA-1 in binary format]`
+            buf.put(entry.mode.as_bytes());
+            buf.put_u8(b' ');
+            buf.put(entry.name.as_bytes());
+            buf.put_u8(0);
+            buf.put(entry.sha1.as_bytes());
+        }
+        buf.freeze()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -568,24 +2100,19 @@ impl Display for TreeEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}{} {} {}\t{}",
-            if self.object_type == ObjectType::Blob {
-                "1"
-            } else {
-                "0"
-            },
-            self.mode,
-            self.object_type,
-            self.sha1,
-            self.name,
+            "{:0>6} {} {}\t{}",
+            self.mode, self.object_type, self.sha1, self.name,
         )
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ObjectId([u8; 20]);
 
 impl ObjectId {
+    /// The null id, used for refs that don't exist
+    pub const ZERO: ObjectId = ObjectId([0; 20]);
+
     pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self> {
         ensure!(bytes.as_ref().len() == 20);
         let b: [u8; 20] = bytes.as_ref().try_into()?;
@@ -614,6 +2141,38 @@ impl FromStr for ObjectId {
     }
 }
 
+/// What HEAD points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Head {
+    /// Name of the current branch, e.g `refs/heads/master`, and its commit unless the branch
+    /// doesn't have any yet
+    Branch(String, Option<ObjectId>),
+    /// HEAD contains a commit id rather than a branch name
+    Detached(ObjectId),
+}
+
+impl Head {
+    pub fn commit(&self) -> Option<ObjectId> {
+        match self {
+            Head::Branch(_, oid) => *oid,
+            Head::Detached(oid) => Some(*oid),
+        }
+    }
+}
+
+impl Display for Head {
+    /// As in the first line of `git status`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Head::Branch(name, _) => {
+                let branch = name.strip_prefix("refs/heads/").unwrap_or(name);
+                write!(f, "On branch {branch}")
+            }
+            Head::Detached(oid) => write!(f, "HEAD detached at {}", &oid.to_string()[..7]),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ref {
     pub oid: ObjectId,
@@ -629,3 +2188,445 @@ impl Ref {
         self.name.ends_with("^{}")
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// A new repo in a temporary directory named after `name`, with a known identity
+    pub(crate) fn temp_repo(name: &str) -> GitRepo {
+        let dir = env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = GitRepo::new(&dir);
+        repo.init(None, None).unwrap();
+        let mut config = ConfigFile::open(repo.git_dir.join("config")).unwrap();
+        config.set("user.name", "A U Thor").unwrap();
+        config.set("user.email", "author@example.com").unwrap();
+        config.write().unwrap();
+        repo
+    }
+
+    /// Paths in the index of `repo`
+    pub(crate) fn index_paths(repo: &GitRepo) -> Vec<String> {
+        let index = repo.read_index().unwrap();
+        index
+            .entries
+            .iter()
+            .map(|e| e.path_str().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_rm() {
+        let repo = temp_repo("add-rm-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::write(dir.join("a/b"), "b\n").unwrap();
+        fs::write(dir.join("c"), "c\n").unwrap();
+        fs::write(dir.join("e"), "e\n").unwrap();
+        symlink("e", dir.join("link")).unwrap();
+        repo.add(&[&dir]).unwrap();
+        let added = index_paths(&repo);
+
+        // Replace the directory `a` by a file, and the file `c` by a directory
+        fs::remove_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::remove_file(dir.join("c")).unwrap();
+        fs::create_dir(dir.join("c")).unwrap();
+        fs::write(dir.join("c/d"), "d\n").unwrap();
+        repo.add(&[dir.join("a"), dir.join("c")]).unwrap();
+        let swapped = index_paths(&repo);
+        let tree = repo.write_tree_from_index(&repo.read_index().unwrap());
+        let files = tree.and_then(|tree| repo.flatten_tree(tree));
+
+        // The content of the symlink is its target, not the content of `e`
+        let rm_link = repo.rm(&[dir.join("link")], false);
+        fs::write(dir.join("e"), "modified\n").unwrap();
+        let rm_modified = repo.rm(&[dir.join("e")], false);
+        let removed = index_paths(&repo);
+        let link_exists = dir.join("link").symlink_metadata().is_ok();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(added, ["a/b", "c", "e", "link"]);
+        assert_eq!(swapped, ["a", "c/d", "e", "link"]);
+        let files = files.unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["a", "c/d", "e", "link"]);
+        assert_eq!(files["link"].0, 0o120000);
+        assert!(rm_link.is_ok());
+        assert!(!link_exists);
+        assert!(rm_modified.is_err());
+        assert_eq!(removed, ["a", "c/d", "e"]);
+    }
+
+    /// Git directory and work tree found by `discover` from `dir`, with the given environment
+    fn discover(dir: &Path, vars: &[(&str, &str)]) -> Result<(PathBuf, Option<PathBuf>)> {
+        let var = |name: &str| vars.iter().find(|(n, _)| *n == name).map(|(_, v)| v.into());
+        let repo = GitRepo::discover_with_env(dir, var)?;
+        Ok((repo.git_dir, repo.work_tree))
+    }
+
+    #[test]
+    fn test_discover() {
+        let repo = temp_repo("discover-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        let git_dir = dir.join(".git");
+        let sub = dir.join("sub");
+        fs::create_dir_all(sub.join("deeper")).unwrap();
+        // A work tree using the git directory of `repo`, like a submodule
+        let linked = sub.join("linked");
+        fs::create_dir(&linked).unwrap();
+        fs::write(linked.join(".git"), "gitdir: ../../.git\n").unwrap();
+        let bare = sub.join("bare.git");
+        GitRepo::new_bare(&bare).init(None, None).unwrap();
+        let sub_str = sub.to_str().unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let parent = discover(&sub.join("deeper"), &[]);
+        let gitfile = discover(&linked, &[]);
+        let inside_bare = discover(&bare.join("refs"), &[]);
+        let inside_git_dir = discover(&git_dir.join("objects"), &[]);
+        let env_git_dir = discover(&sub, &[("GIT_DIR", "../.git")]);
+        let env_work_tree = discover(&sub, &[("GIT_DIR", "../.git"), ("GIT_WORK_TREE", "..")]);
+        let only_work_tree = discover(&sub, &[("GIT_WORK_TREE", "deeper")]);
+        let not_git_dir = discover(&dir, &[("GIT_DIR", sub_str)]);
+        let ceiling = discover(&sub.join("deeper"), &[("GIT_CEILING_DIRECTORIES", sub_str)]);
+        let at_ceiling = discover(&dir, &[("GIT_CEILING_DIRECTORIES", dir_str)]);
+        let relative_ceiling = discover(&sub, &[("GIT_CEILING_DIRECTORIES", "..")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let found = (git_dir.clone(), Some(dir.clone()));
+        assert_eq!(parent.unwrap(), found);
+        assert_eq!(gitfile.unwrap(), (git_dir.clone(), Some(linked)));
+        assert_eq!(inside_bare.unwrap(), (bare, None));
+        assert_eq!(inside_git_dir.unwrap(), (git_dir.clone(), None));
+        // GIT_DIR makes the current directory the root of the work tree
+        assert_eq!(env_git_dir.unwrap(), (git_dir.clone(), Some(sub.clone())));
+        assert_eq!(env_work_tree.unwrap(), found);
+        assert_eq!(only_work_tree.unwrap(), (git_dir, Some(sub.join("deeper"))));
+        assert!(not_git_dir.is_err());
+        assert!(ceiling.is_err());
+        assert_eq!(at_ceiling.unwrap(), found);
+        // Relative ceiling directories are ignored
+        assert_eq!(relative_ceiling.unwrap(), found);
+    }
+
+    #[test]
+    fn test_modes_roundtrip() {
+        let repo = temp_repo("modes-roundtrip-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("run"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir.join("run"), fs::Permissions::from_mode(0o755)).unwrap();
+        // Link targets are stored as is, even if they aren't UTF-8
+        let target = OsStr::from_bytes(b"caf\xe9");
+        symlink(target, dir.join("link")).unwrap();
+
+        let work_tree = repo
+            .write_tree_dir(&dir)
+            .and_then(|tree| repo.flatten_tree(tree));
+        repo.add(&[&dir]).unwrap();
+        let commit = repo.commit("modes".to_string()).unwrap();
+        let committed = repo
+            .peel(commit, ObjectType::Tree)
+            .and_then(|tree| repo.flatten_tree(tree));
+        let link_blob = committed
+            .as_ref()
+            .ok()
+            .and_then(|files| repo.get_object(files["link"].1).ok());
+
+        fs::remove_file(dir.join("run")).unwrap();
+        fs::remove_file(dir.join("link")).unwrap();
+        let checked_out = repo.checkout_head();
+        let run_mode = fs::metadata(dir.join("run")).map(|m| m.permissions().mode());
+        let link_target = fs::read_link(dir.join("link"));
+        let status = repo.status();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let work_tree = work_tree.unwrap();
+        let modes = work_tree
+            .iter()
+            .map(|(path, (mode, _))| (path.as_str(), *mode));
+        assert_eq!(
+            modes.collect::<Vec<_>>(),
+            [("link", 0o120000), ("run", 0o100755)]
+        );
+        assert_eq!(committed.unwrap(), work_tree);
+        assert_eq!(link_blob.unwrap().content, b"caf\xe9"[..]);
+        assert!(checked_out.is_ok());
+        assert_eq!(run_mode.unwrap() & 0o111, 0o111);
+        assert_eq!(link_target.unwrap(), target);
+        // The symlink hashes to the same blob
+        assert!(status.unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_object_prefixes() {
+        let repo = temp_repo("object-prefixes-test");
+        let pack_dir = repo.object_dir.join("pack");
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        fs::copy(
+            format!("{fixtures}/ofs-delta.pack"),
+            pack_dir.join("pack-t.pack"),
+        )
+        .unwrap();
+        fs::copy(
+            format!("{fixtures}/ofs-delta.idx"),
+            pack_dir.join("pack-t.idx"),
+        )
+        .unwrap();
+        repo.reload_packs().unwrap();
+        let packed = repo.packs.borrow()[0]
+            .index
+            .entries
+            .iter()
+            .map(|e| e.oid)
+            .collect::<Vec<_>>();
+        // A loose blob whose id starts with the same 4 hex digits as a packed object
+        let (loose, other) = (0..)
+            .map(|i| Object::blob(format!("{i}\n").into_bytes()))
+            .find_map(|blob| {
+                let oid = blob.id();
+                let other = packed
+                    .iter()
+                    .find(|p| p.to_string()[..4] == oid.to_string()[..4])?;
+                Some((repo.store_object(blob).unwrap(), *other))
+            })
+            .unwrap();
+        let (loose_hex, other_hex) = (loose.to_string(), other.to_string());
+        let common = loose_hex
+            .bytes()
+            .zip(other_hex.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let both = repo.find_objects_by_prefix(&loose_hex[..4].to_uppercase());
+        let too_short = repo.find_objects_by_prefix(&loose_hex[..1]);
+        let not_hex = repo.find_objects_by_prefix("zz");
+        let ambiguous = repo.resolve_prefix(&loose_hex[..4]);
+        let resolved = (
+            repo.resolve_prefix(&loose_hex[..common + 1]).unwrap(),
+            repo.resolve_prefix(&other_hex[..common + 1]).unwrap(),
+            repo.resolve_prefix(&loose_hex).unwrap(),
+        );
+        let abbreviated = (
+            repo.abbreviate(loose, 4).unwrap(),
+            repo.abbreviate(other, 4).unwrap(),
+            repo.abbreviate(loose, 12).unwrap(),
+        );
+        let default_len = repo.abbrev_len().unwrap();
+        let mut config = ConfigFile::open(repo.git_dir.join("config")).unwrap();
+        let mut abbrev_len = |value: &str| {
+            config.set("core.abbrev", value).unwrap();
+            config.write().unwrap();
+            repo.abbrev_len()
+        };
+        let configured = (abbrev_len("12").unwrap(), abbrev_len("no").unwrap());
+        let invalid = abbrev_len("3");
+        fs::remove_dir_all(repo.work_tree().unwrap()).unwrap();
+
+        let mut expected = vec![loose, other];
+        expected.sort();
+        assert_eq!(both.unwrap(), expected);
+        assert!(too_short.is_err());
+        assert!(not_hex.is_err());
+        let error = ambiguous.unwrap_err().to_string();
+        assert!(error.contains("is ambiguous"), "{error}");
+        assert!(
+            error.contains(&format!("{} blob", &loose_hex[..7])),
+            "{error}"
+        );
+        assert!(error.contains(&other_hex[..7]), "{error}");
+        assert_eq!(resolved, (Some(loose), Some(other), Some(loose)));
+        assert_eq!(abbreviated.0, loose_hex[..common + 1]);
+        assert_eq!(abbreviated.1, other_hex[..common + 1]);
+        assert_eq!(abbreviated.2, loose_hex[..12]);
+        assert_eq!(default_len, 7);
+        assert_eq!(configured, (12, 40));
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_commit_roundtrip() {
+        let content = "tree e82d3e7cf1a64f2b4cff8aa8e788d8f38d8437b1\n\
+                       parent fa297d8cabc8abda23ed4f9e4119fb87d272add1\n\
+                       parent 5dad6da107b0ebf80c2cfe54cce800730c137b46\n\
+                       author A U Thor <author@example.com> 1700000000 -0430\n\
+                       committer C O Mitter <committer@example.com> 1700000100 +0100\n\
+                       encoding ISO-8859-1\n\
+                       mergetag object 5dad6da107b0ebf80c2cfe54cce800730c137b46\n \
+                       type commit\n \
+                       tag v1\n\
+                       gpgsig -----BEGIN PGP SIGNATURE-----\n \
+                       \n \
+                       abcdef\n \
+                       -----END PGP SIGNATURE-----\n\
+                       \n\
+                       Merge v1\n";
+        let object = Object::commit(content.as_bytes().to_vec());
+        let commit = object.as_commit().unwrap();
+
+        assert_eq!(commit.parents.len(), 2);
+        assert_eq!(commit.author.name, "A U Thor");
+        assert_eq!(commit.author.tz_offset, -270);
+        assert_eq!(commit.committer.timestamp, 1700000100);
+        assert_eq!(commit.header("encoding").unwrap(), "ISO-8859-1");
+        assert_eq!(commit.headers[1].0, "mergetag");
+        assert_eq!(
+            commit.header("gpgsig").map(|v| &v[..]),
+            Some(&b"-----BEGIN PGP SIGNATURE-----\n\nabcdef\n-----END PGP SIGNATURE-----"[..])
+        );
+        assert_eq!(&commit.message[..], b"Merge v1\n");
+        assert_eq!(Object::commit(commit.as_bytes().to_vec()).id(), object.id());
+    }
+
+    #[test]
+    fn test_commit_roundtrip_raw() {
+        // Latin-1 names, extra spaces and a `-0000` timezone must be written back as they are
+        let content = b"tree e82d3e7cf1a64f2b4cff8aa8e788d8f38d8437b1\n\
+                        author A U  Thor \xe9 <author@example.com> 1700000000 -0000\n\
+                        committer Ren\xe9 <committer@example.com> 1700000100 +0000\n\
+                        encoding ISO-8859-1\n\
+                        mergetag object 5dad6da107b0ebf80c2cfe54cce800730c137b46\n \
+                        type commit\n \
+                        tag v1\n \
+                        tagger Ren\xe9 <committer@example.com> 1700000000 -0000\n \
+                        \n \
+                        Caf\xe9\n\
+                        gpgsig -----BEGIN PGP SIGNATURE-----\n \
+                        \n \
+                        abcdef\n \
+                        -----END PGP SIGNATURE-----\n\
+                        \n\
+                        Caf\xe9\n";
+        let commit = Object::commit(content.to_vec()).as_commit().unwrap();
+
+        assert_eq!(commit.author.name, "A U  Thor \u{fffd}");
+        assert_eq!(commit.author.tz_offset, 0);
+        assert_eq!(commit.headers.len(), 3);
+        assert!(commit.header("gpgsig").is_some());
+        assert_eq!(&commit.as_bytes()[..], &content[..]);
+
+        // Changed fields are written instead of what was parsed
+        let mut author = commit.author.clone();
+        assert_eq!(
+            author,
+            Signature::new(author.name.clone(), author.email.clone(), 1700000000, 0)
+        );
+        author.timestamp += 1;
+        assert_eq!(
+            &author.as_bytes()[..],
+            b"A U  Thor \xef\xbf\xbd <author@example.com> 1700000001 +0000"
+        );
+    }
+
+    #[test]
+    fn test_commit_header_order() {
+        // Headers that git doesn't write in this order, or doesn't know about
+        let content = "tree e82d3e7cf1a64f2b4cff8aa8e788d8f38d8437b1\n\
+                       author A U Thor <author@example.com> 1700000000 +0000\n\
+                       committer C O Mitter <committer@example.com> 1700000100 +0000\n\
+                       gpgsig-sha256 -----BEGIN PGP SIGNATURE-----\n \
+                       abcdef\n \
+                       -----END PGP SIGNATURE-----\n\
+                       gpgsig -----BEGIN PGP SIGNATURE-----\n \
+                       abcdef\n \
+                       -----END PGP SIGNATURE-----\n\
+                       mergetag object 5dad6da107b0ebf80c2cfe54cce800730c137b46\n \
+                       type commit\n \
+                       tag v1\n\
+                       x-custom value\n\
+                       \n\
+                       Message\n";
+        let commit = Object::commit(content.as_bytes().to_vec())
+            .as_commit()
+            .unwrap();
+        let keys: Vec<_> = commit.headers.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["gpgsig-sha256", "gpgsig", "mergetag", "x-custom"]);
+        assert_eq!(commit.as_bytes(), content.as_bytes());
+
+        // The required headers must come first
+        let content = "tree e82d3e7cf1a64f2b4cff8aa8e788d8f38d8437b1\n\
+                       encoding ISO-8859-1\n\
+                       author A U Thor <author@example.com> 1700000000 +0000\n\
+                       committer C O Mitter <committer@example.com> 1700000100 +0000\n\
+                       \n\
+                       Message\n";
+        assert!(Commit::parse(&mut content.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_tag_roundtrip() {
+        let content = "object 0a7aab6138d85bf58b81ad7f9c311944957b4410\n\
+                       type blob\n\
+                       tag v1.0\n\
+                       tagger A U Thor <author@example.com> 1700000000 +0100\n\
+                       \n\
+                       Release 1.0\n\
+                       -----BEGIN PGP SIGNATURE-----\n\
+                       \n\
+                       abcdef\n\
+                       -----END PGP SIGNATURE-----\n";
+        let tag = Object::tag(content.as_bytes().to_vec()).as_tag().unwrap();
+
+        assert_eq!(tag.object_type, ObjectType::Blob);
+        assert_eq!(tag.tag, "v1.0");
+        assert!(tag.headers.is_empty());
+        assert_eq!(tag.message, "Release 1.0\n");
+        assert!(tag
+            .signature
+            .as_deref()
+            .unwrap()
+            .starts_with(b"-----BEGIN PGP SIGNATURE-----"));
+        assert_eq!(tag.as_bytes(), content.as_bytes());
+    }
+
+    #[test]
+    fn test_tag_raw_message() {
+        // The message isn't UTF-8, and quotes a signature before the one of the tag
+        let content = b"object 0a7aab6138d85bf58b81ad7f9c311944957b4410\n\
+                        type blob\n\
+                        tag v1.0\n\
+                        tagger A U Thor <author@example.com> 1700000000 +0100\n\
+                        \n\
+                        Caf\xe9\n\
+                        -----BEGIN PGP SIGNATURE-----\n\
+                        quoted\n\
+                        -----END PGP SIGNATURE-----\n\
+                        -----BEGIN SSH SIGNATURE-----\n\
+                        abcdef\n\
+                        -----END SSH SIGNATURE-----\n";
+        let tag = Object::tag(content.to_vec()).as_tag().unwrap();
+
+        assert!(tag
+            .message
+            .starts_with(b"Caf\xe9\n-----BEGIN PGP SIGNATURE-----\n"));
+        assert!(tag.message.ends_with(b"-----END PGP SIGNATURE-----\n"));
+        assert!(tag
+            .signature
+            .as_deref()
+            .unwrap()
+            .starts_with(b"-----BEGIN SSH SIGNATURE-----"));
+        assert_eq!(&tag.as_bytes()[..], &content[..]);
+    }
+
+    #[test]
+    fn test_tag_unknown_headers() {
+        let content = "object 0a7aab6138d85bf58b81ad7f9c311944957b4410\n\
+                       type blob\n\
+                       tag v1.0\n\
+                       tagger A U Thor <author@example.com> 1700000000 +0100\n\
+                       x-custom first\n \
+                       continued\n\
+                       x-other second\n\
+                       \n\
+                       Release 1.0\n";
+        let tag = Object::tag(content.as_bytes().to_vec()).as_tag().unwrap();
+
+        assert_eq!(tag.headers.len(), 2);
+        assert_eq!(tag.headers[0].1, "first\ncontinued");
+        assert_eq!(tag.as_bytes(), content.as_bytes());
+    }
+}
@@ -1,10 +1,18 @@
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use clap::Parser;
 use clap::Subcommand;
-use git_starter_rust::GitRepo;
-use git_starter_rust::ObjectId;
+use clap::ValueEnum;
+use git_starter_rust::branch::short_name;
+use git_starter_rust::config::{
+    config_file_path, parse_bool, parse_int, Config, ConfigFile, ConfigScope,
+};
+use git_starter_rust::refs::RefValue;
+use git_starter_rust::revision::Revision;
+use git_starter_rust::{GitRepo, Head, ObjectType};
 use reqwest::Url;
 
 #[derive(Parser)]
@@ -17,10 +25,22 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     /// Initialize a new git repo
-    Init,
+    Init {
+        /// Create a repo without work tree
+        #[arg(long)]
+        bare: bool,
+        /// Name of the initial branch, `init.defaultBranch` or `master` by default
+        #[arg(short = 'b', long)]
+        initial_branch: Option<String>,
+        /// Directory whose content is copied into the git directory
+        #[arg(long)]
+        template: Option<PathBuf>,
+        /// Directory of the repo, the current directory by default
+        directory: Option<PathBuf>,
+    },
     CatFile {
-        #[arg(short = 'p', value_name = "blob_sha")]
-        sha: ObjectId,
+        #[arg(short = 'p', value_name = "object")]
+        object: String,
     },
     HashObject {
         #[arg(short = 'w', value_name = "file")]
@@ -29,41 +49,697 @@ pub enum Commands {
     LsTree {
         #[arg(long)]
         name_only: bool,
-        sha: ObjectId,
+        /// Abbreviate object ids, to `core.abbrev` hex digits by default
+        #[arg(long, num_args = 0..=1, require_equals = true)]
+        abbrev: Option<Option<usize>>,
+        tree_ish: String,
+    },
+    /// Add file contents to the index
+    Add {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Remove files from the index and the work tree
+    Rm {
+        /// Only remove the files from the index
+        #[arg(long)]
+        cached: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Show the working tree status
+    Status {
+        /// Machine-readable output
+        #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "v1")]
+        porcelain: Option<Porcelain>,
+        /// Short format, same as --porcelain=v1
+        #[arg(short, long)]
+        short: bool,
+    },
+    /// Show the content of the index
+    LsFiles {
+        #[arg(short, long)]
+        stage: bool,
     },
     WriteTree,
     CommitTree {
-        #[arg(short, long)]
-        parent: ObjectId,
+        #[arg(short, long = "parent")]
+        parents: Vec<String>,
+        /// Commit message. Each one is a separate paragraph.
+        #[arg(short, long, conflicts_with = "file")]
+        message: Vec<String>,
+        /// Read the commit message from a file, or from stdin if it's `-`. If neither this nor
+        /// `-m` is given, the message is read from stdin.
+        #[arg(short = 'F', long)]
+        file: Option<PathBuf>,
+        tree_ish: String,
+    },
+    /// Record the content of the index as a new commit on the current branch
+    Commit {
         #[arg(short, long)]
         message: String,
-        tree_sha: ObjectId,
     },
     Clone {
+        /// Make a repo without work tree, with the branches of the remote
+        #[arg(long)]
+        bare: bool,
+        /// Make a bare repo with all the refs of the remote
+        #[arg(long)]
+        mirror: bool,
         url: Url,
         dir: PathBuf,
     },
+    /// Get and set config values
+    Config {
+        /// Use the global config file
+        #[arg(long, group = "location")]
+        global: bool,
+        /// Use the system config file
+        #[arg(long, group = "location")]
+        system: bool,
+        /// Use the config file of the repo
+        #[arg(long, group = "location")]
+        local: bool,
+        /// Use the given config file
+        #[arg(short, long, group = "location")]
+        file: Option<PathBuf>,
+        /// Check and normalize the value
+        #[arg(long = "type", value_enum)]
+        value_type: Option<ConfigType>,
+        /// Get all the values of a multi-valued key
+        #[arg(long, group = "action", requires = "name")]
+        get_all: bool,
+        /// Add a value without replacing the existing ones
+        #[arg(long, group = "action", requires = "value")]
+        add: bool,
+        #[arg(long, group = "action", requires = "name")]
+        unset: bool,
+        #[arg(long, group = "action", requires = "name")]
+        unset_all: bool,
+        /// List all the values
+        #[arg(short, long, group = "action")]
+        list: bool,
+        #[arg(required_unless_present = "list")]
+        name: Option<String>,
+        value: Option<String>,
+    },
+    /// Update a ref safely
+    UpdateRef {
+        /// Delete the ref. The only value given is then the expected current value.
+        #[arg(short)]
+        delete: bool,
+        /// Update the ref itself rather than the ref it points to
+        #[arg(long)]
+        no_deref: bool,
+        /// Read updates from stdin, and apply them all at once
+        #[arg(long, conflicts_with_all = ["delete", "name"])]
+        stdin: bool,
+        /// With --stdin, arguments are terminated by NUL characters
+        #[arg(short = 'z', requires = "stdin")]
+        nul_terminated: bool,
+        /// Reason of the update, recorded in the reflog
+        #[arg(short)]
+        message: Option<String>,
+        #[arg(required_unless_present = "stdin")]
+        name: Option<String>,
+        #[arg(required_unless_present_any = ["stdin", "delete"])]
+        new: Option<String>,
+        /// Expected current value. The zero id means the ref must not exist.
+        old: Option<String>,
+    },
+    /// List, create, delete or rename branches
+    Branch {
+        /// Delete branches, which must be merged in their upstream or HEAD
+        #[arg(short, long, group = "action")]
+        delete: bool,
+        /// Delete branches, even if they are not merged
+        #[arg(short = 'D', group = "action")]
+        force_delete: bool,
+        /// Rename a branch, the current one if only the new name is given
+        #[arg(short = 'm', long = "move", group = "action")]
+        rename: bool,
+        /// Rename a branch, even if the new name already exists
+        #[arg(short = 'M', group = "action")]
+        force_rename: bool,
+        /// Make a branch, the current one by default, track an upstream branch
+        #[arg(short = 'u', long, group = "action", value_name = "upstream")]
+        set_upstream_to: Option<String>,
+        /// List the remote-tracking branches
+        #[arg(short, long)]
+        remotes: bool,
+        /// List both local and remote-tracking branches
+        #[arg(short, long)]
+        all: bool,
+        /// Reset the branch to the start point if it already exists
+        #[arg(short, long)]
+        force: bool,
+        names: Vec<String>,
+    },
+    /// Switch to a branch, or detach HEAD at a commit
+    Checkout {
+        /// Create a branch with this name at the target, and switch to it
+        #[arg(short = 'b')]
+        new_branch: Option<String>,
+        /// Detach HEAD even if the target is a branch
+        #[arg(long)]
+        detach: bool,
+        /// Throw away local changes
+        #[arg(short, long)]
+        force: bool,
+        /// Branch, tag or commit
+        target: Option<String>,
+    },
+    /// Switch to a branch
+    Switch {
+        /// Create a branch with this name at the target, and switch to it
+        #[arg(short, long)]
+        create: Option<String>,
+        /// Detach HEAD at a commit
+        #[arg(short, long)]
+        detach: bool,
+        /// Throw away local changes
+        #[arg(short, long, alias = "discard-changes")]
+        force: bool,
+        /// Branch, or commit with --detach
+        target: Option<String>,
+    },
+    /// Show and manage the history of the refs
+    Reflog {
+        #[command(subcommand)]
+        command: Option<ReflogCommand>,
+    },
+    /// Create a tag object from its content on stdin
+    Mktag,
+    /// Create, list or delete tags
+    Tag {
+        /// Make an annotated tag. Unlike git, which opens an editor for its message, the message
+        /// must be given with -m.
+        #[arg(short = 'a')]
+        annotate: bool,
+        /// Message of an annotated tag. Implies -a.
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Replace an existing tag
+        #[arg(short, long)]
+        force: bool,
+        #[arg(short, long, group = "action")]
+        delete: bool,
+        /// List the tags matching the given patterns
+        #[arg(short, long, group = "action")]
+        list: bool,
+        /// When listing, show that many lines of the message of each tag (`-n<num>`, 1 by
+        /// default)
+        #[arg(short = 'n', num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+        lines: Option<usize>,
+        /// Sort the listed tags by `refname`, `version:refname` or `creatordate`, in reverse
+        /// order with a `-` prefix
+        #[arg(long)]
+        sort: Option<String>,
+        /// Tag name and the object to tag, or patterns and names when listing and deleting
+        args: Vec<String>,
+    },
+    /// Resolve revisions to object ids
+    RevParse {
+        /// Check that there's a single revision naming an existing object
+        #[arg(long)]
+        verify: bool,
+        /// Abbreviate object ids, to `core.abbrev` hex digits by default. Implies --verify.
+        #[arg(long, num_args = 0..=1, require_equals = true)]
+        short: Option<Option<usize>>,
+        #[arg(required = true, allow_hyphen_values = true)]
+        revisions: Vec<String>,
+    },
+    /// Show the commits reachable from revisions
+    Log {
+        /// Show each commit on a single line, with an abbreviated id
+        #[arg(long)]
+        oneline: bool,
+        /// Maximum number of commits to show
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        /// Revisions or ranges, HEAD by default
+        revisions: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ReflogCommand {
+    /// Show the reflog of a ref, HEAD by default
+    Show { name: Option<String> },
+    /// Remove old reflog entries
+    Expire {
+        /// Remove entries older than this, `gc.reflogExpire` or 90 days by default
+        #[arg(long)]
+        expire: Option<String>,
+        /// Process the reflogs of all the refs
+        #[arg(long)]
+        all: bool,
+        #[arg(required_unless_present = "all")]
+        refs: Vec<String>,
+    },
+    /// Delete single entries, given as `<ref>@{<n>}`
+    Delete {
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ConfigType {
+    Bool,
+    Int,
+    Path,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Porcelain {
+    V1,
+    V2,
 }
 
 fn main() -> Result<()> {
-    let args = Cli::parse();
+    let args = Cli::parse_from(attach_tag_lines(std::env::args_os()));
     let cwd = std::env::current_dir()?;
-    let repo = GitRepo::new(cwd);
+    let repo = match args.command {
+        Commands::Init {
+            bare,
+            ref directory,
+            ..
+        } => {
+            let dir = cwd.join(directory.as_deref().unwrap_or(Path::new(".")));
+            if bare {
+                GitRepo::new_bare(dir)
+            } else {
+                GitRepo::new(dir)
+            }
+        }
+        Commands::Clone { .. } => GitRepo::new(cwd),
+        // The config can be used outside of a repo, e.g with --global
+        Commands::Config { .. } => GitRepo::discover(&cwd).unwrap_or_else(|_| GitRepo::new(&cwd)),
+        _ => GitRepo::discover(&cwd)?,
+    };
     match args.command {
-        Commands::Init => repo.init()?,
-        Commands::CatFile { sha } => repo.cat_file(sha)?,
+        Commands::Init {
+            initial_branch,
+            template,
+            ..
+        } => repo.init(initial_branch.as_deref(), template.as_deref())?,
+        Commands::CatFile { object } => repo.cat_file(repo.resolve_revision(&object)?)?,
         Commands::HashObject { file } => repo.hash_object(file)?,
-        Commands::LsTree { name_only, sha } => repo.read_tree(sha, name_only)?,
+        Commands::LsTree {
+            name_only,
+            abbrev,
+            tree_ish,
+        } => {
+            let tree = repo.peel(repo.resolve_revision(&tree_ish)?, ObjectType::Tree)?;
+            let abbrev = match abbrev {
+                Some(len) => Some(len.map_or_else(|| repo.abbrev_len(), Ok)?),
+                None => None,
+            };
+            repo.read_tree(tree, name_only, abbrev)?
+        }
+        Commands::Add { paths } => repo.add(&paths)?,
+        Commands::Rm { cached, paths } => repo.rm(&paths, cached)?,
+        Commands::Status { porcelain, short } => {
+            let status = repo.status()?;
+            match porcelain {
+                Some(Porcelain::V2) => print!("{}", status.porcelain_v2()),
+                Some(Porcelain::V1) => print!("{}", status.porcelain_v1()),
+                None if short => print!("{}", status.porcelain_v1()),
+                None => print!("{}\n\n{status}", repo.head()?),
+            }
+        }
+        Commands::LsFiles { stage } => repo.ls_files(stage)?,
         Commands::WriteTree => repo.write_tree()?,
         Commands::CommitTree {
-            parent,
+            parents,
             message,
-            tree_sha,
-        } => repo.commit_tree(tree_sha, parent, message)?,
-        Commands::Clone { url, dir } => {
-            GitRepo::clone(url, dir)?;
+            file,
+            tree_ish,
+        } => {
+            let tree = repo.peel(repo.resolve_revision(&tree_ish)?, ObjectType::Tree)?;
+            let parents = parents
+                .iter()
+                .map(|parent| repo.resolve_commit(parent))
+                .collect::<Result<_>>()?;
+            let message = if message.is_empty() {
+                read_message(file)?
+            } else {
+                message
+                    .iter()
+                    .map(|m| format!("{m}\n"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            repo.commit_tree(tree, parents, message)?
+        }
+        Commands::Commit { message } => {
+            repo.commit(message)?;
+        }
+        Commands::Clone {
+            bare,
+            mirror,
+            url,
+            dir,
+        } => {
+            GitRepo::clone(url, dir, bare, mirror)?;
+        }
+        Commands::Config {
+            global,
+            system,
+            local: _,
+            file,
+            value_type,
+            get_all,
+            add,
+            unset,
+            unset_all,
+            list,
+            name,
+            value,
+        } => {
+            let scope = if global {
+                ConfigScope::Global
+            } else if system {
+                ConfigScope::System
+            } else {
+                ConfigScope::Local
+            };
+            let is_write = add || unset || unset_all || value.is_some();
+            if is_write {
+                let path = match file {
+                    Some(file) => file,
+                    None => config_file_path(scope, repo.git_dir())?,
+                };
+                let mut config = ConfigFile::open(path)?;
+                let name = name.expect("clap to require a name");
+                let found = match value {
+                    Some(value) if add => {
+                        config.add(&name, &value)?;
+                        true
+                    }
+                    Some(value) => {
+                        config.set(&name, &value)?;
+                        true
+                    }
+                    None if unset_all => config.unset_all(&name)? > 0,
+                    None => config.unset(&name)?,
+                };
+                if !found {
+                    std::process::exit(5);
+                }
+                config.write()?;
+                return Ok(());
+            }
+
+            let config = match file {
+                Some(file) => Config::from_file(&file, ConfigScope::Local)?,
+                None if global || system => {
+                    let mut config = repo.config()?;
+                    config.entries.retain(|e| e.scope == scope);
+                    config
+                }
+                None => repo.config()?,
+            };
+            if list {
+                for entry in &config.entries {
+                    match &entry.value {
+                        Some(value) => println!("{}={value}", entry.name()),
+                        None => println!("{}", entry.name()),
+                    }
+                }
+                return Ok(());
+            }
+            let name = name.expect("clap to require a name");
+            let values = if get_all {
+                config.get_all(&name)
+            } else {
+                config.get(&name).into_iter().collect()
+            };
+            if values.is_empty() {
+                std::process::exit(1);
+            }
+            for value in values {
+                match value_type {
+                    None => println!("{value}"),
+                    Some(ConfigType::Bool) => match parse_bool(value) {
+                        Some(b) => println!("{b}"),
+                        None => bail!("Bad boolean config value '{value}' for '{name}'"),
+                    },
+                    Some(ConfigType::Int) => match parse_int(value) {
+                        Some(i) => println!("{i}"),
+                        None => bail!("Bad numeric config value '{value}' for '{name}'"),
+                    },
+                    Some(ConfigType::Path) => {
+                        let path = config.get_path(&name).expect("value to exist");
+                        println!("{}", path.display());
+                    }
+                }
+            }
+        }
+        Commands::UpdateRef {
+            delete,
+            no_deref,
+            stdin,
+            nul_terminated,
+            message,
+            name,
+            new,
+            old,
+        } => {
+            if stdin {
+                let mut input = String::new();
+                std::io::stdin().read_to_string(&mut input)?;
+                repo.update_refs_from_stdin(&input, nul_terminated, message.as_deref())?;
+            } else {
+                let name = name.expect("clap to require a ref name");
+                // With -d, the only value is the old one
+                let (new, old) = if delete { (None, new) } else { (new, old) };
+                repo.update_ref(
+                    &name,
+                    new.as_deref(),
+                    old.as_deref(),
+                    no_deref,
+                    message.as_deref(),
+                )?;
+            }
+        }
+        Commands::Branch {
+            delete,
+            force_delete,
+            rename,
+            force_rename,
+            set_upstream_to,
+            remotes,
+            all,
+            force,
+            names,
+        } => {
+            if delete || force_delete {
+                ensure!(!names.is_empty(), "Branch name required");
+                for name in names {
+                    repo.delete_branch(&name, force_delete || force)?;
+                }
+            } else if rename || force_rename {
+                let force = force_rename || force;
+                match &names[..] {
+                    [new_name] => repo.rename_branch(None, new_name, force)?,
+                    [old_name, new_name] => repo.rename_branch(Some(old_name), new_name, force)?,
+                    _ => bail!("Renaming needs a new branch name, and optionally the old one"),
+                }
+            } else if let Some(upstream) = set_upstream_to {
+                let name = match &names[..] {
+                    [name] => name.clone(),
+                    [] => match repo.head()? {
+                        Head::Branch(branch, _) => short_name(&branch).to_string(),
+                        Head::Detached(_) => bail!("HEAD is not on a branch"),
+                    },
+                    _ => bail!("Too many arguments to set the upstream"),
+                };
+                repo.set_upstream(&name, &upstream)?;
+            } else if names.is_empty() || remotes || all {
+                let head = repo.head()?;
+                if let Head::Detached(oid) = head {
+                    println!(
+                        "* (HEAD detached at {})",
+                        repo.abbreviate(oid, repo.abbrev_len()?)?
+                    );
+                }
+                let branches = repo.list_branches(remotes || all)?;
+                for branch in branches {
+                    let is_remote = branch.name.starts_with("refs/remotes/");
+                    if remotes && !is_remote {
+                        continue;
+                    }
+                    let current = matches!(&head, Head::Branch(name, _) if *name == branch.name);
+                    let marker = if current { '*' } else { ' ' };
+                    let name = match is_remote && all {
+                        true => branch.name.trim_start_matches("refs/"),
+                        false => short_name(&branch.name),
+                    };
+                    match repo.refs().read(&branch.name)? {
+                        Some(RefValue::Symbolic(target)) => {
+                            println!("{marker} {name} -> {}", short_name(&target))
+                        }
+                        _ => println!("{marker} {name}"),
+                    }
+                }
+            } else {
+                match &names[..] {
+                    [name] => repo.create_branch(name, None, force)?,
+                    [name, start] => repo.create_branch(name, Some(start), force)?,
+                    _ => bail!("Too many arguments to create a branch"),
+                }
+            }
+        }
+        Commands::Log {
+            oneline,
+            max_count,
+            revisions,
+        } => repo.log(&revisions, max_count, oneline)?,
+        Commands::Checkout {
+            new_branch,
+            detach,
+            force,
+            target,
+        } => {
+            repo.checkout(target.as_deref(), new_branch.as_deref(), detach, force)?;
+        }
+        Commands::Switch {
+            create,
+            detach,
+            force,
+            target,
+        } => {
+            repo.switch(target.as_deref(), create.as_deref(), detach, force)?;
+        }
+        Commands::Reflog { command } => match command {
+            None => repo.reflog_show(None)?,
+            Some(ReflogCommand::Show { name }) => repo.reflog_show(name.as_deref())?,
+            Some(ReflogCommand::Expire { expire, all, refs }) => {
+                repo.reflog_expire(&refs, all, expire.as_deref())?
+            }
+            Some(ReflogCommand::Delete { entries }) => repo.reflog_delete(&entries)?,
+        },
+        Commands::Mktag => {
+            let mut content = Vec::new();
+            std::io::stdin().read_to_end(&mut content)?;
+            repo.mktag(content)?;
+        }
+        Commands::Tag {
+            annotate,
+            message,
+            force,
+            delete,
+            list,
+            lines,
+            sort,
+            args,
+        } => {
+            if delete {
+                ensure!(!args.is_empty(), "Tag name required");
+                for name in args {
+                    repo.delete_tag(&name)?;
+                }
+            } else if list || args.is_empty() || lines.is_some() {
+                for tag in repo.list_tags(&args, sort.as_deref())? {
+                    let name = tag.name.trim_start_matches("refs/tags/");
+                    let Some(lines) = lines else {
+                        println!("{name}");
+                        continue;
+                    };
+                    let message = repo.tag_message_lines(tag.oid, lines)?;
+                    println!("{name:<15} {}", message.join("\n    ").trim_end());
+                }
+            } else {
+                let (name, object) = match &args[..] {
+                    [name] => (name, None),
+                    [name, object] => (name, Some(repo.resolve_revision(object)?)),
+                    _ => bail!("Too many arguments to create a tag"),
+                };
+                // There's no editor to write the message in
+                ensure!(
+                    message.is_some() || !annotate,
+                    "An annotated tag needs a message (-m)"
+                );
+                repo.create_tag(name, object, message, force)?;
+            }
+        }
+        Commands::RevParse {
+            verify,
+            short,
+            revisions,
+        } => {
+            if verify || short.is_some() {
+                let [revision] = &revisions[..] else {
+                    bail!("Needed a single revision");
+                };
+                let oid = repo.resolve_revision(revision)?;
+                // Make sure the object exists
+                repo.get_object(oid)?;
+                match short {
+                    Some(len) => {
+                        let len = len.map_or_else(|| repo.abbrev_len(), Ok)?;
+                        println!("{}", repo.abbreviate(oid, len)?);
+                    }
+                    None => println!("{oid}"),
+                }
+                return Ok(());
+            }
+            for revision in revisions {
+                // `^<rev>` excludes the commits reachable from `<rev>`
+                if let Some(excluded) = revision.strip_prefix('^') {
+                    println!("^{}", repo.resolve_commit(excluded)?);
+                    continue;
+                }
+                match repo.parse_revision(&revision)? {
+                    Revision::Single(oid) => println!("{oid}"),
+                    Revision::Range(from, to) => println!("{to}\n^{from}"),
+                    Revision::Symmetric(left, right, bases) => {
+                        println!("{right}\n{left}");
+                        for base in bases {
+                            println!("^{base}");
+                        }
+                    }
+                }
+            }
         }
     }
 
     Ok(())
 }
+
+/// `tag -n` only takes a value attached to it, as `-n<num>`, so that `tag -n v1*` lists the
+/// tags matching `v1*`. clap needs `-n=<num>` for that, so add the `=`.
+fn attach_tag_lines(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.collect::<Vec<_>>();
+    if args.len() > 2 && args[1] == "tag" {
+        for arg in &mut args[2..] {
+            if arg == "--" {
+                break;
+            }
+            let Some(cluster) = arg.to_str().and_then(|arg| arg.strip_prefix('-')) else {
+                continue;
+            };
+            // Flags without value can come first, as in `-ln3`
+            let flags = cluster.len() - cluster.trim_start_matches(['a', 'd', 'f', 'l']).len();
+            let lines = cluster[flags..].strip_prefix('n');
+            if let Some(lines) = lines.filter(|l| !l.is_empty() && !l.starts_with('=')) {
+                *arg = format!("-{}n={lines}", &cluster[..flags]).into();
+            }
+        }
+    }
+    args
+}
+
+/// Read a message from the given file, or from stdin if there is none or it's `-`
+fn read_message(file: Option<PathBuf>) -> Result<String> {
+    match file {
+        Some(file) if file.as_os_str() != "-" => Ok(std::fs::read_to_string(file)?),
+        _ => {
+            let mut message = String::new();
+            std::io::stdin().read_to_string(&mut message)?;
+            Ok(message)
+        }
+    }
+}
@@ -1,14 +1,20 @@
 //! Module to parse pack-files
 
+use std::cell::OnceCell;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
+use std::fs;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use flate2::bufread::ZlibDecoder;
+use flate2::Crc;
+use sha1::{Digest, Sha1};
 
-use crate::{GitRepo, Object, ObjectId};
+use crate::idx::{IndexEntry, PackIndex};
+use crate::{GitRepo, Object, ObjectId, ObjectType};
 
 pub fn parse_pack_from_file<P: AsRef<Path>>(file: P) -> Result<PackFile> {
     let mut bytes: Bytes = std::fs::read(file)?.into();
@@ -23,6 +29,9 @@ pub struct PackFile {
 
 impl PackFile {
     pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        // Offsets are relative to the start of the pack, header included
+        let pack_len = bytes.remaining();
+
         // Read header
         let header = PackHeader::parse(bytes)?;
         let num_objs = header.num_objects;
@@ -30,97 +39,374 @@ impl PackFile {
         // Parse objects
         let mut objects = Vec::new();
         for _ in 0..num_objs {
-            let obj = PackObject::parse(bytes)?;
+            let offset = (pack_len - bytes.remaining()) as u64;
+            let obj = PackObject::parse(bytes, offset)?;
             objects.push(obj);
         }
 
         Ok(PackFile { header, objects })
     }
 
-    pub fn explode_into_repo(self, repo: &GitRepo) -> Result<()> {
-        // TODO: implement support for packfiles directly, i.e:
-        // - store the packfile in `.git/objects/packs/`
-        // - generate a `.idx` file alongside it
-        // - implement lookup of objects directly from the packfile
-        let mut deltas = Vec::new();
-        let mut count = 0;
-        // Store full objects directly
-        for entry in self.objects {
-            let obj = match entry.object_type {
-                PackObjectType::ObjCommit => Object::commit(entry.data.into()),
-                PackObjectType::ObjTree => Object::tree(entry.data.into()),
-                PackObjectType::ObjBlob => Object::blob(entry.data.into()),
-                PackObjectType::ObjTag => {
-                    // TODO: implement annotated tags
-                    println!("Tag objects not implemented!");
-                    continue;
+    /// Store the pack `pack_data` in `.git/objects/pack/` along with a generated `.idx` file
+    pub fn write_into_repo(pack_data: &Bytes, repo: &GitRepo) -> Result<PathBuf> {
+        ensure!(pack_data.len() >= 12 + 20, "Truncated pack file");
+        let (content, checksum) = pack_data.split_at(pack_data.len() - 20);
+        ensure!(
+            Sha1::digest(content).as_slice() == checksum,
+            "Pack file checksum mismatch"
+        );
+        let pack_checksum = ObjectId::from_bytes(checksum)?;
+
+        let entries = resolve_objects(pack_data, repo)?;
+        let index = PackIndex::new(entries, pack_checksum);
+
+        let pack_dir = repo.object_dir.join("pack");
+        fs::create_dir_all(&pack_dir)?;
+        let pack_path = pack_dir.join(format!("pack-{pack_checksum}.pack"));
+        fs::write(&pack_path, pack_data).context("Writing pack file")?;
+        // Write the index last, so that the pack is never visible without its content
+        fs::write(pack_path.with_extension("idx"), index.as_bytes())
+            .context("Writing pack index")?;
+        println!(
+            "Stored {} objects in {}",
+            index.entries.len(),
+            pack_path.display()
+        );
+
+        Ok(pack_path)
+    }
+}
+
+/// Resolve all the objects in the pack `pack_data`, applying deltas. Returns the id, offset and
+/// CRC32 of every entry, in no particular order.
+///
+/// Entries are inflated one at a time: only the ids and offsets of the objects are kept, and
+/// deltas are resolved from their offset with a bounded cache of their bases.
+fn resolve_objects(pack_data: &Bytes, repo: &GitRepo) -> Result<Vec<IndexEntry>> {
+    ensure!(pack_data.len() >= 12 + 20, "Truncated pack file");
+    let content_len = pack_data.len() - 20;
+    let mut bytes = pack_data.slice(..content_len);
+    let header = PackHeader::parse(&mut bytes)?;
+
+    // Full objects can be handled directly, while the id of deltas is only known once their
+    // base is resolved
+    let mut entries = Vec::with_capacity(header.num_objects as usize);
+    let mut deltas = Vec::new();
+    for _ in 0..header.num_objects {
+        ensure!(bytes.has_remaining(), "Truncated pack file");
+        let offset = content_len - bytes.remaining();
+        let entry = PackObject::parse(&mut bytes, offset as u64)?;
+        // The CRC32 of each entry covers its raw bytes in the pack, i.e up to the next entry
+        let mut crc = Crc::new();
+        crc.update(&pack_data[offset..content_len - bytes.remaining()]);
+        match entry.object_type.object_type() {
+            Some(object_type) => entries.push(IndexEntry {
+                oid: Object {
+                    object_type,
+                    content: entry.data,
                 }
-                PackObjectType::ObjOfsDelta(_) => {
-                    deltas.push(entry);
-                    continue;
+                .id(),
+                crc32: crc.sum(),
+                offset: offset as u64,
+            }),
+            None => deltas.push((offset as u64, crc.sum())),
+        }
+    }
+
+    // Offset of each resolved object, for OBJ_REF_DELTA whose base is in the pack
+    let mut offsets = entries
+        .iter()
+        .map(|entry| (entry.oid, entry.offset))
+        .collect::<HashMap<_, _>>();
+    let mut cache = DeltaBaseCache::new(DELTA_BASE_CACHE_LIMIT);
+    // The base of an OBJ_REF_DELTA can come later in the pack, so keep going until we stop
+    // making progress
+    while !deltas.is_empty() {
+        let pending = deltas.len();
+        let mut unresolved = Vec::new();
+        for (offset, crc32) in deltas {
+            let Some(object) = resolve_delta(pack_data, offset, &offsets, repo, &mut cache)? else {
+                unresolved.push((offset, crc32));
+                continue;
+            };
+            let oid = object.id();
+            entries.push(IndexEntry { oid, crc32, offset });
+            offsets.insert(oid, offset);
+        }
+        ensure!(
+            unresolved.len() < pending,
+            "Failed to resolve the base of {} deltas",
+            unresolved.len()
+        );
+        deltas = unresolved;
+    }
+
+    Ok(entries)
+}
+
+/// Resolve the delta at `offset` in the pack `pack_data`, or return `None` if the base of an
+/// OBJ_REF_DELTA in its chain isn't known yet. `offsets` gives the offset of the objects of the
+/// pack resolved so far, and bases that aren't in it are looked up in the repo (thin packs).
+fn resolve_delta(
+    pack_data: &Bytes,
+    mut offset: u64,
+    offsets: &HashMap<ObjectId, u64>,
+    repo: &GitRepo,
+    cache: &mut DeltaBaseCache,
+) -> Result<Option<Object>> {
+    // Walk down the delta chain until we find a known object...
+    let mut deltas = Vec::new();
+    let (object_type, mut content) = loop {
+        ensure!(
+            deltas.len() <= MAX_DELTA_DEPTH,
+            "Delta chain is too long at offset {offset}"
+        );
+        if let Some(object) = cache.get(offset) {
+            break object;
+        }
+        ensure!(
+            (offset as usize) < pack_data.len() - 20,
+            "Offset {offset} is out of bounds of the pack"
+        );
+        let entry = PackObject::parse(&mut pack_data.slice(offset as usize..), offset)?;
+        match entry.object_type {
+            PackObjectType::ObjOfsDelta(ofs) => {
+                deltas.push((offset, entry.data));
+                offset -= ofs;
+            }
+            PackObjectType::ObjRefDelta(base) => {
+                deltas.push((offset, entry.data));
+                match offsets.get(&base) {
+                    Some(base_offset) => offset = *base_offset,
+                    None => match repo.get_object(base) {
+                        Ok(object) => break (object.object_type, object.content),
+                        Err(_) => return Ok(None),
+                    },
                 }
-                PackObjectType::ObjRefDelta(_) => {
-                    deltas.push(entry);
-                    continue;
+            }
+            typ => {
+                let Some(object_type) = typ.object_type() else {
+                    bail!("Unsupported object type in pack: {typ}");
+                };
+                // Other deltas are likely to have the same base
+                if !deltas.is_empty() {
+                    cache.insert(offset, object_type, entry.data.clone());
                 }
-            };
-            repo.store_object(obj)?;
-            count += 1;
+                break (object_type, entry.data);
+            }
         }
-        println!("Exploded {count} objects");
-
-        // now apply deltas
-        println!("Processing deltas");
-        let mut count = 0;
-        for delta in deltas {
-            let PackObjectType::ObjRefDelta(base) = delta.object_type else {
-                println!("Error: unsupported delta type");
-                continue;
+    };
+
+    // ... then apply the deltas back up, keeping the results as bases for the next deltas
+    for (offset, delta) in deltas.into_iter().rev() {
+        content = apply_delta(&content, delta)?;
+        cache.insert(offset, object_type, content.clone());
+    }
+
+    Ok(Some(Object {
+        object_type,
+        content,
+    }))
+}
+
+/// Default size of [`DeltaBaseCache`], like `core.deltaBaseCacheLimit`
+const DELTA_BASE_CACHE_LIMIT: usize = 96 << 20;
+
+/// Resolved objects by offset in a pack, to be used as the base of deltas. The oldest ones are
+/// dropped once their total size goes over the limit.
+#[derive(Debug)]
+struct DeltaBaseCache {
+    objects: HashMap<u64, (ObjectType, Bytes)>,
+    order: VecDeque<u64>,
+    size: usize,
+    limit: usize,
+}
+
+impl DeltaBaseCache {
+    fn new(limit: usize) -> Self {
+        Self {
+            objects: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    fn get(&self, offset: u64) -> Option<(ObjectType, Bytes)> {
+        self.objects.get(&offset).cloned()
+    }
+
+    fn insert(&mut self, offset: u64, object_type: ObjectType, content: Bytes) {
+        if content.len() > self.limit || self.objects.contains_key(&offset) {
+            return;
+        }
+        self.size += content.len();
+        self.order.push_back(offset);
+        self.objects.insert(offset, (object_type, content));
+        while self.size > self.limit {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
             };
+            if let Some((_, content)) = self.objects.remove(&oldest) {
+                self.size -= content.len();
+            }
+        }
+    }
+}
+
+/// Maximum length of a delta chain, to guard against cycles in corrupt packs
+pub(crate) const MAX_DELTA_DEPTH: usize = 10_000;
+
+/// An object read from a pack
+pub enum PackedObject {
+    Full(Object),
+    /// Deltas to apply, in reverse order, to an object that isn't in the pack
+    Deltas {
+        base: ObjectId,
+        deltas: Vec<Bytes>,
+    },
+}
+
+/// Maximum size to allocate upfront for an object or a delta result: sizes in a pack can't be
+/// trusted until the data has been read
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+/// A pack file stored in `.git/objects/pack`, along with its index
+#[derive(Debug)]
+pub struct Pack {
+    pub path: PathBuf,
+    pub index: PackIndex,
+    /// Content of the pack file, only read the first time an object is requested from it
+    data: OnceCell<Bytes>,
+}
+
+impl Pack {
+    /// Open the pack corresponding to the given `.idx` file
+    pub fn open<P: AsRef<Path>>(idx_path: P) -> Result<Self> {
+        let idx_path = idx_path.as_ref();
+        let mut idx_data: Bytes = fs::read(idx_path)?.into();
+        let index = PackIndex::parse(&mut idx_data)
+            .with_context(|| format!("Parsing pack index {}", idx_path.display()))?;
+
+        Ok(Self {
+            path: idx_path.with_extension("pack"),
+            index,
+            data: OnceCell::new(),
+        })
+    }
+
+    /// Read the given object from the pack, or return `None` if it's not in there
+    pub fn read_object(&self, oid: ObjectId) -> Result<Option<PackedObject>> {
+        let Some(offset) = self.index.find_offset(oid) else {
+            return Ok(None);
+        };
+        self.read_object_at(offset)
+            .with_context(|| format!("Reading {oid} from {}", self.path.display()))
+            .map(Some)
+    }
 
-            let base_object = repo.get_object(base)?;
-            let mut bytes = delta.data;
-            let base_size = read_var_int(&mut bytes);
-            assert_eq!(
-                base_size as usize,
-                base_object.content.len(),
-                "Base size in delta doesn't match base object size"
+    /// Read the object at `offset` in the pack, resolving deltas whose base is in the pack
+    fn read_object_at(&self, mut offset: u64) -> Result<PackedObject> {
+        let data = self.data()?;
+
+        // Walk down the delta chain until we find a full object...
+        let mut deltas = Vec::new();
+        let base = loop {
+            ensure!(
+                deltas.len() <= MAX_DELTA_DEPTH,
+                "Delta chain is too long at offset {offset}"
             );
-            let target_size = read_var_int(&mut bytes);
-            let target_size = if target_size == 0 {
-                0x10000
-            } else {
-                target_size
-            };
-            let base_data = base_object.content;
-            let mut reconstructed_data = BytesMut::with_capacity(target_size as usize);
-            // println!("sha={base}, base size={base_size}, target_size={target_size}");
-            while bytes.has_remaining() {
-                let instr = DeltaInstruction::parse(&mut bytes)?;
-                match instr {
-                    DeltaInstruction::Copy { size, offset } => {
-                        reconstructed_data.put(&base_data[offset..][..size])
-                    }
-                    DeltaInstruction::Add { size } => {
-                        reconstructed_data.put(bytes.copy_to_bytes(size))
+            ensure!(
+                (offset as usize) < data.len() - 20,
+                "Offset {offset} is out of bounds of the pack"
+            );
+            let entry = PackObject::parse(&mut data.slice(offset as usize..), offset)?;
+            match entry.object_type {
+                PackObjectType::ObjOfsDelta(ofs) => {
+                    offset -= ofs;
+                    deltas.push(entry.data);
+                }
+                PackObjectType::ObjRefDelta(base) => {
+                    deltas.push(entry.data);
+                    match self.index.find_offset(base) {
+                        Some(base_offset) => offset = base_offset,
+                        // The base can be anywhere in the repo, not just in this pack
+                        None => return Ok(PackedObject::Deltas { base, deltas }),
                     }
                 }
+                typ => {
+                    let Some(object_type) = typ.object_type() else {
+                        bail!("Unsupported object type in pack: {typ}");
+                    };
+                    break Object {
+                        object_type,
+                        content: entry.data,
+                    };
+                }
             }
-            // println!("reconstructed object has size {}", reconstructed_data.len());
-            assert_eq!(target_size as usize, reconstructed_data.len());
-            let reconstructed_object = Object {
-                object_type: base_object.object_type,
-                content: reconstructed_data.freeze(),
-            };
-            let _reconstructed_sha = repo.store_object(reconstructed_object)?;
-            // println!("Reconstructed object has sha {reconstructed_sha}");
-            count += 1;
+        };
+
+        // ... then apply the deltas back up
+        let mut object = base;
+        for delta in deltas.into_iter().rev() {
+            object.content = apply_delta(&object.content, delta)?;
         }
-        println!("Reconstructed {count} objects from deltas");
-        Ok(())
+
+        Ok(PackedObject::Full(object))
+    }
+
+    fn data(&self) -> Result<&Bytes> {
+        if let Some(data) = self.data.get() {
+            return Ok(data);
+        }
+
+        let data: Bytes = fs::read(&self.path)
+            .with_context(|| format!("Reading pack {}", self.path.display()))?
+            .into();
+        ensure!(
+            data.len() >= 12 + 20
+                && data[data.len() - 20..] == *self.index.pack_checksum.as_bytes(),
+            "Pack {} doesn't match its index",
+            self.path.display()
+        );
+
+        Ok(self.data.get_or_init(|| data))
     }
 }
 
+/// Reconstruct an object by applying the instructions in `delta` to `base`.
+pub fn apply_delta(base: &[u8], mut delta: Bytes) -> Result<Bytes> {
+    let base_size = read_var_int(&mut delta)?;
+    ensure!(
+        base_size as usize == base.len(),
+        "Base size in delta doesn't match base object size"
+    );
+    let target_size = read_var_int(&mut delta)? as usize;
+    let mut reconstructed_data = BytesMut::with_capacity(target_size.min(MAX_PREALLOCATION));
+    while delta.has_remaining() {
+        let instr = DeltaInstruction::parse(&mut delta)?;
+        match instr {
+            DeltaInstruction::Copy { size, offset } => {
+                ensure!(
+                    offset + size <= base.len(),
+                    "Delta copy instruction out of bounds of the base object"
+                );
+                reconstructed_data.put(&base[offset..][..size])
+            }
+            DeltaInstruction::Add { size } => {
+                ensure!(size <= delta.remaining(), "Truncated delta add instruction");
+                reconstructed_data.put(delta.copy_to_bytes(size))
+            }
+        }
+    }
+    ensure!(
+        target_size == reconstructed_data.len(),
+        "Reconstructed object size doesn't match the size in the delta"
+    );
+
+    Ok(reconstructed_data.freeze())
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct PackHeader {
     pub sig: [u8; 4],
@@ -130,6 +416,7 @@ pub struct PackHeader {
 
 impl PackHeader {
     pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        ensure!(bytes.remaining() >= 12, "Truncated pack header");
         let mut sig = [0; 4];
         bytes.copy_to_slice(&mut sig);
         if &sig != b"PACK" {
@@ -161,6 +448,19 @@ pub enum PackObjectType {
     ObjRefDelta(ObjectId),
 }
 
+impl PackObjectType {
+    /// The type of the object, for entries that aren't deltas
+    pub fn object_type(&self) -> Option<ObjectType> {
+        match self {
+            PackObjectType::ObjCommit => Some(ObjectType::Commit),
+            PackObjectType::ObjTree => Some(ObjectType::Tree),
+            PackObjectType::ObjBlob => Some(ObjectType::Blob),
+            PackObjectType::ObjTag => Some(ObjectType::Tag),
+            PackObjectType::ObjOfsDelta(_) | PackObjectType::ObjRefDelta(_) => None,
+        }
+    }
+}
+
 impl Display for PackObjectType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -176,34 +476,43 @@ impl Display for PackObjectType {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PackObject {
+    /// Offset of the object from the start of the pack
+    pub offset: u64,
     pub object_type: PackObjectType,
     pub data: Bytes,
 }
 
 impl PackObject {
-    pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
-        let (typ, size) = read_type_and_var_int(bytes);
+    pub fn parse(bytes: &mut impl Buf, offset: u64) -> Result<Self> {
+        let (typ, size) = read_type_and_var_int(bytes)?;
         let object_type = match typ {
             1 => PackObjectType::ObjCommit,
             2 => PackObjectType::ObjTree,
             3 => PackObjectType::ObjBlob,
             4 => PackObjectType::ObjTag,
             6 => {
-                let ofs = read_var_int(bytes);
+                let ofs = read_ofs_offset(bytes)?;
+                // The base must come strictly before the delta
+                ensure!(
+                    ofs > 0 && ofs < offset,
+                    "Invalid base offset for OBJ_OFS_DELTA: {ofs}"
+                );
                 PackObjectType::ObjOfsDelta(ofs)
             }
             7 => {
+                ensure!(bytes.remaining() >= 20, "Truncated OBJ_REF_DELTA base");
                 let sha = bytes.copy_to_bytes(20);
                 PackObjectType::ObjRefDelta(ObjectId::from_bytes(&sha)?)
             }
             _ => bail!("Invalid pack object type: {typ}"),
         };
         // println!("Found object with size {size}: {object_type}");
-        let mut buf = Vec::with_capacity(size as usize);
+        let mut buf = Vec::with_capacity((size as usize).min(MAX_PREALLOCATION));
         let mut reader = ZlibDecoder::new(bytes.reader());
         reader.read_to_end(&mut buf)?;
 
         Ok(PackObject {
+            offset,
             object_type,
             data: buf.into(),
         })
@@ -218,7 +527,14 @@ pub enum DeltaInstruction {
 
 impl DeltaInstruction {
     pub fn parse(bytes: &mut impl Buf) -> Result<Self> {
+        ensure!(bytes.has_remaining(), "Truncated delta instruction");
         let instr = bytes.get_u8();
+        // The copy instruction is followed by one byte for each bit set in its lower 7 bits
+        let operands = (instr & 0b01111111).count_ones() as usize;
+        ensure!(
+            instr & 128 == 0 || bytes.remaining() >= operands,
+            "Truncated delta copy instruction"
+        );
         // println!("instr={instr:08b}");
         if instr & 128 != 0 {
             // copy instruction
@@ -238,14 +554,15 @@ impl DeltaInstruction {
                 }
             }
             let offset = offset as usize;
-            let size = size as usize;
+            // a size of 0 means 0x10000
+            let size = if size == 0 { 0x10000 } else { size as usize };
             // println!("Found copy instruction size={size}, offset={offset}");
             Ok(Self::Copy { size, offset })
         } else {
             // add instruction
             let size = (instr & 127) as usize;
             // println!("Found add instruction size={size}");
-            assert!(size != 0, "Delta add instruction has zero size!");
+            ensure!(size != 0, "Delta add instruction has zero size");
             Ok(Self::Add { size })
         }
     }
@@ -255,14 +572,16 @@ impl DeltaInstruction {
 ///
 /// - The first bit of each byte indicates if another byte must be read (if 1, yes, if 0, we stop)
 /// - For the first byte, the next 3 bits encode the object type, and the remaining 4 are part of
-/// the integer
+///   the integer
 /// - For all subsequent bytes, the lower 7 bits are concatenated before the previous ones (i.e
-/// each byte is more significant than the previous)
-fn read_type_and_var_int(buf: &mut impl Buf) -> (u8, u64) {
+///   each byte is more significant than the previous)
+fn read_type_and_var_int(buf: &mut impl Buf) -> Result<(u8, u64)> {
     let mut res = 0u64;
     let mut shift_offset = 0;
     let mut typ = 0;
     loop {
+        ensure!(buf.has_remaining(), "Truncated pack object header");
+        ensure!(shift_offset < 64, "Pack object size is too large");
         let b = buf.get_u8();
         if shift_offset == 0 {
             // first byte:
@@ -282,35 +601,264 @@ fn read_type_and_var_int(buf: &mut impl Buf) -> (u8, u64) {
         }
     }
 
-    (typ, res)
+    Ok((typ, res))
 }
 
 /// Read a variable-length encoded offset
 ///
 /// Same as [read_var_int] except without the type.
-pub fn read_var_int(buf: &mut impl Buf) -> u64 {
+pub fn read_var_int(buf: &mut impl Buf) -> Result<u64> {
     let mut res = 0u64;
     let mut shift_offset = 0;
     loop {
+        ensure!(buf.has_remaining(), "Truncated variable-length integer");
+        ensure!(shift_offset < 64, "Variable-length integer is too large");
         let b = buf.get_u8();
         // add the lower 7 bits to the result
         res |= ((b & 0b01111111) as u64) << shift_offset;
         shift_offset += 7;
 
-        if b < 127 {
+        if b <= 127 {
             break;
         }
     }
 
-    res
+    Ok(res)
+}
+
+/// Read the base offset of an OBJ_OFS_DELTA. The same encoding is used for path prefixes in
+/// version 4 indexes.
+///
+/// Unlike [read_var_int], the bytes are in big-endian order, and 1 is added to each byte but the
+/// last so that there is only one way to encode a given offset.
+pub(crate) fn read_ofs_offset(buf: &mut impl Buf) -> Result<u64> {
+    ensure!(buf.has_remaining(), "Truncated variable-length offset");
+    let mut b = buf.get_u8();
+    let mut res = (b & 0b01111111) as u64;
+    while b > 127 {
+        ensure!(buf.has_remaining(), "Truncated variable-length offset");
+        ensure!(res < 1 << 56, "Variable-length offset is too large");
+        b = buf.get_u8();
+        res = ((res + 1) << 7) | (b & 0b01111111) as u64;
+    }
+
+    Ok(res)
+}
+
+/// Write an offset in the encoding of [read_ofs_offset]
+pub(crate) fn write_ofs_offset(buf: &mut impl BufMut, mut value: u64) {
+    let mut bytes = vec![(value & 0b01111111) as u8];
+    while value > 127 {
+        value = (value >> 7) - 1;
+        bytes.push(0b10000000 | (value & 0b01111111) as u8);
+    }
+    bytes.reverse();
+    buf.put(&bytes[..]);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const OFS_DELTA_PACK: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ofs-delta.pack");
+    const OFS_DELTA_IDX: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ofs-delta.idx");
+
     #[test]
     fn test_parse() {
-        parse_pack_from_file("/Users/abusch/code/rust/yew/.git/objects/pack/pack-0eda438f06d4f311b4005e3f2511dce1c9a385de.pack").unwrap();
+        let pack = parse_pack_from_file(OFS_DELTA_PACK).unwrap();
+        assert_eq!(pack.objects.len(), pack.header.num_objects as usize);
+        assert_eq!(pack.objects[0].offset, 12);
+    }
+
+    #[test]
+    fn test_resolve_ofs_delta() {
+        let pack = parse_pack_from_file(OFS_DELTA_PACK).unwrap();
+        let delta = pack
+            .objects
+            .iter()
+            .find(|o| matches!(o.object_type, PackObjectType::ObjOfsDelta(_)))
+            .expect("fixture to contain an OBJ_OFS_DELTA");
+        let PackObjectType::ObjOfsDelta(ofs) = delta.object_type else {
+            unreachable!()
+        };
+        let base = pack
+            .objects
+            .iter()
+            .find(|o| o.offset == delta.offset - ofs)
+            .expect("base of the delta to be in the pack");
+
+        let content = apply_delta(&base.data, delta.data.clone()).unwrap();
+        assert!(content.starts_with(b"1\n2\n3\n"));
+
+        // Resolving the delta caches its base, whether it's a delta or not
+        let pack_data = Bytes::from(fs::read(OFS_DELTA_PACK).unwrap());
+        let repo = GitRepo::new(std::env::temp_dir().join("ofs-delta-test"));
+        let mut cache = DeltaBaseCache::new(DELTA_BASE_CACHE_LIMIT);
+        let object = resolve_delta(&pack_data, delta.offset, &HashMap::new(), &repo, &mut cache);
+        assert_eq!(object.unwrap().unwrap().content, content);
+        assert!(cache.get(base.offset).is_some());
+    }
+
+    #[test]
+    fn test_ofs_offset_roundtrip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            16511,
+            16512,
+            2_113_663,
+            u32::MAX as u64,
+            u64::MAX >> 1,
+        ] {
+            let mut buf = BytesMut::new();
+            write_ofs_offset(&mut buf, value);
+            let mut bytes = buf.freeze();
+            assert_eq!(read_ofs_offset(&mut bytes).unwrap(), value);
+            assert!(!bytes.has_remaining());
+        }
+        // 16511 is the largest value that fits in two bytes
+        let mut buf = BytesMut::new();
+        write_ofs_offset(&mut buf, 16511);
+        assert_eq!(&buf[..], [0xff, 0x7f]);
+        assert!(read_ofs_offset(&mut &[0x80][..]).is_err());
+        assert!(read_ofs_offset(&mut &[0xff; 12][..]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_ofs_delta() {
+        // OBJ_OFS_DELTA headers whose base would be the delta itself, or before the pack
+        for (entry, offset) in [([0x61, 0x00], 100), ([0x61, 0x0c], 12), ([0x61, 0x0d], 12)] {
+            let error = PackObject::parse(&mut &entry[..], offset).unwrap_err();
+            assert!(error.to_string().contains("Invalid base offset"), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_write_into_repo() {
+        let dir = std::env::temp_dir().join(format!("pack-test-{}", std::process::id()));
+        let repo = GitRepo::new(&dir);
+        let pack_data = fs::read(OFS_DELTA_PACK).unwrap().into();
+
+        let pack_path = PackFile::write_into_repo(&pack_data, &repo).unwrap();
+        let idx = fs::read(pack_path.with_extension("idx")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The generated index should be identical to the one generated by git
+        assert_eq!(idx, fs::read(OFS_DELTA_IDX).unwrap());
+    }
+
+    #[test]
+    fn test_delta_base_cache() {
+        let mut cache = DeltaBaseCache::new(10);
+        cache.insert(1, ObjectType::Blob, Bytes::from_static(b"12345"));
+        cache.insert(2, ObjectType::Blob, Bytes::from_static(b"1234"));
+        // Too big to be cached at all
+        cache.insert(3, ObjectType::Blob, Bytes::from_static(b"12345678901"));
+        let kept = (
+            cache.get(1).is_some(),
+            cache.get(2).is_some(),
+            cache.get(3).is_some(),
+        );
+        // Goes over the limit, so the oldest object is dropped
+        cache.insert(4, ObjectType::Tree, Bytes::from_static(b"123"));
+
+        assert_eq!(kept, (true, true, false));
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(2).unwrap().1, "1234");
+        assert_eq!(cache.get(4).unwrap().0, ObjectType::Tree);
+        assert_eq!(cache.size, 7);
+    }
+
+    #[test]
+    fn test_corrupt_pack_data() {
+        assert!(PackHeader::parse(&mut &b"PACK\0\0"[..]).is_err());
+        // Truncated size, and size that doesn't fit in 64 bits
+        assert!(PackObject::parse(&mut &[0x80][..], 12).is_err());
+        assert!(PackObject::parse(&mut &[0xff; 12][..], 12).is_err());
+        // OBJ_REF_DELTA without its full base id
+        assert!(PackObject::parse(&mut &[0x71, 1, 2, 3][..], 12).is_err());
+        // Truncated sizes, an add instruction of size 0, and a truncated copy instruction
+        assert!(apply_delta(b"", Bytes::from_static(&[0x80])).is_err());
+        assert!(apply_delta(b"", Bytes::from_static(&[0x00, 0x01, 0x00])).is_err());
+        assert!(apply_delta(b"a", Bytes::from_static(&[0x01, 0x01, 0x91, 0x00])).is_err());
+    }
+
+    /// Store a pack in `repo` with an OBJ_REF_DELTA for each `(oid, base)`, which creates `oid`
+    /// from `base`
+    fn write_ref_delta_pack(repo: &GitRepo, name: &str, deltas: &[(ObjectId, ObjectId)]) {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        let mut pack = BytesMut::new();
+        pack.put(&b"PACK"[..]);
+        pack.put_u32(2);
+        pack.put_u32(deltas.len() as u32);
+        let mut entries = Vec::new();
+        for (oid, base) in deltas {
+            entries.push(IndexEntry {
+                oid: *oid,
+                crc32: 0,
+                offset: pack.len() as u64,
+            });
+            // 1 byte base, 1 byte result, added by the delta
+            let delta = [0x01, 0x01, 0x01, b'x'];
+            pack.put_u8(0x70 | delta.len() as u8);
+            pack.put(base.as_bytes());
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&delta).unwrap();
+            pack.put(&encoder.finish().unwrap()[..]);
+        }
+        let checksum = ObjectId::from_bytes(Sha1::digest(&pack).as_slice()).unwrap();
+        pack.put(checksum.as_bytes());
+
+        let pack_dir = repo.object_dir.join("pack");
+        fs::create_dir_all(&pack_dir).unwrap();
+        fs::write(pack_dir.join(format!("{name}.pack")), &pack).unwrap();
+        let index = PackIndex::new(entries, checksum);
+        fs::write(pack_dir.join(format!("{name}.idx")), index.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_ref_delta_cycles() {
+        let dir = std::env::temp_dir().join(format!("ref-delta-test-{}", std::process::id()));
+        let repo = GitRepo::new(&dir);
+        let oid = |content: &str| Object::blob(content.as_bytes().to_vec()).id();
+        let (a, b, c) = (oid("a"), oid("b"), oid("c"));
+        // `a` is a delta of itself, `b` and `c` are deltas of each other across packs
+        write_ref_delta_pack(&repo, "pack-self", &[(a, a)]);
+        write_ref_delta_pack(&repo, "pack-b", &[(b, c)]);
+        write_ref_delta_pack(&repo, "pack-c", &[(c, b)]);
+
+        let own_base = repo.get_object(a);
+        let cycle = repo.get_object(b);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = format!("{:#}", own_base.err().unwrap());
+        assert!(error.contains("too long"), "{error}");
+        let error = format!("{:#}", cycle.err().unwrap());
+        assert!(error.contains("too long"), "{error}");
+    }
+
+    #[test]
+    fn test_read_packed_object() {
+        let dir = std::env::temp_dir().join(format!("packed-object-test-{}", std::process::id()));
+        let pack_dir = dir.join(".git/objects/pack");
+        fs::create_dir_all(&pack_dir).unwrap();
+        fs::copy(OFS_DELTA_PACK, pack_dir.join("pack-test.pack")).unwrap();
+        fs::copy(OFS_DELTA_IDX, pack_dir.join("pack-test.idx")).unwrap();
+        let repo = GitRepo::new(&dir);
+
+        // This blob is stored as an OBJ_OFS_DELTA
+        let oid = "57f7cca4afdc7098e48cf03f6ec6f088475609df".parse().unwrap();
+        let object = repo.get_object(oid);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let object = object.unwrap();
+        assert_eq!(object.object_type, ObjectType::Blob);
+        assert_eq!(object.id(), oid);
     }
 }
@@ -0,0 +1,71 @@
+//! Module to read and write reflogs, the history of the values of refs kept in `.git/logs/`
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::{ObjectId, Signature};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// Previous value of the ref, or the zero id if it was created
+    pub old: ObjectId,
+    pub new: ObjectId,
+    /// Who changed the ref, and when
+    pub committer: Signature,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// Parse the entries of a reflog file, oldest first
+    pub fn parse_all(content: &str) -> Result<Vec<Self>> {
+        content.lines().map(Self::from_str).collect()
+    }
+}
+
+impl FromStr for ReflogEntry {
+    type Err = anyhow::Error;
+
+    /// `<old> <new> <name> <<email>> <timestamp> <tz>\t<message>`
+    fn from_str(line: &str) -> Result<Self> {
+        let (entry, message) = line.split_once('\t').unwrap_or((line, ""));
+        let invalid = || anyhow!("Invalid reflog entry: {line}");
+        let (old, rest) = entry.split_once(' ').ok_or_else(invalid)?;
+        let (new, committer) = rest.split_once(' ').ok_or_else(invalid)?;
+
+        Ok(Self {
+            old: ObjectId::from_str(old)?,
+            new: ObjectId::from_str(new)?,
+            committer: committer.parse()?,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl Display for ReflogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The message must stay on a single line
+        let message = self.message.lines().collect::<Vec<_>>().join(" ");
+        write!(f, "{} {} {}\t{message}", self.old, self.new, self.committer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let line = "0000000000000000000000000000000000000000 \
+                    e37a3b6af309a86aef68d7296d908a1b067bb0e6 \
+                    A U Thor <author@example.com> 1700000000 +0200\t\
+                    clone: from http://example.com/repo.git";
+        let entry = ReflogEntry::from_str(line).unwrap();
+
+        assert_eq!(entry.old, ObjectId::ZERO);
+        assert_eq!(entry.committer.tz_offset, 120);
+        assert_eq!(entry.message, "clone: from http://example.com/repo.git");
+        assert_eq!(entry.to_string(), line);
+    }
+}
@@ -0,0 +1,828 @@
+//! Module to read and write refs, either loose (one file per ref) or in `packed-refs`
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::config::Config;
+use crate::reflog::ReflogEntry;
+use crate::{ObjectId, Ref, Signature};
+
+/// Maximum number of symbolic refs to follow, to protect against loops
+const MAX_SYMREF_DEPTH: usize = 5;
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+/// Content of a ref: either an object id, or the name of another ref
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    Direct(ObjectId),
+    /// `ref: <name>`, e.g for HEAD
+    Symbolic(String),
+}
+
+impl RefValue {
+    pub fn parse(content: &str) -> Result<Self> {
+        let content = content.trim_end();
+        match content.strip_prefix("ref:") {
+            Some(target) => Ok(RefValue::Symbolic(target.trim().to_string())),
+            None => Ok(RefValue::Direct(ObjectId::from_str(content)?)),
+        }
+    }
+}
+
+impl std::fmt::Display for RefValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefValue::Direct(oid) => write!(f, "{oid}"),
+            RefValue::Symbolic(target) => write!(f, "ref: {target}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedRef {
+    pub name: String,
+    pub oid: ObjectId,
+    /// For annotated tags, the object the tag points to (from the `^` line)
+    pub peeled: Option<ObjectId>,
+}
+
+/// Content of `.git/packed-refs`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedRefs {
+    /// Refs, sorted by name
+    pub refs: Vec<PackedRef>,
+}
+
+impl PackedRefs {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut refs: Vec<PackedRef> = Vec::new();
+        for line in content.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Some(peeled) = line.strip_prefix('^') {
+                let last = refs
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("Peeled line without a ref in packed-refs"))?;
+                last.peeled = Some(ObjectId::from_str(peeled)?);
+                continue;
+            }
+            let Some((oid, name)) = line.split_once(' ') else {
+                bail!("Invalid line in packed-refs: {line}");
+            };
+            refs.push(PackedRef {
+                name: name.to_string(),
+                oid: ObjectId::from_str(oid)?,
+                peeled: None,
+            });
+        }
+        // Old versions of git didn't always sort the file
+        refs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self { refs })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&PackedRef> {
+        self.refs
+            .binary_search_by(|r| r.name.as_str().cmp(name))
+            .ok()
+            .map(|idx| &self.refs[idx])
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.refs.len();
+        self.refs.retain(|r| r.name != name);
+        self.refs.len() != len
+    }
+
+    pub fn as_string(&self) -> String {
+        let mut out = PACKED_REFS_HEADER.to_string();
+        for r in &self.refs {
+            out.push_str(&format!("{} {}\n", r.oid, r.name));
+            if let Some(peeled) = r.peeled {
+                out.push_str(&format!("^{peeled}\n"));
+            }
+        }
+        out
+    }
+}
+
+/// The refs of a repo
+#[derive(Debug, Clone)]
+pub struct RefStore {
+    /// Directory of HEAD and the other refs specific to a worktree
+    git_dir: PathBuf,
+    /// Directory of `refs/` and `packed-refs`, shared by all the worktrees
+    common_dir: PathBuf,
+}
+
+impl RefStore {
+    pub fn new(git_dir: PathBuf, common_dir: PathBuf) -> Self {
+        Self {
+            git_dir,
+            common_dir,
+        }
+    }
+
+    /// Path of the loose file of a ref
+    pub fn path(&self, name: &str) -> PathBuf {
+        if name.starts_with("refs/") {
+            self.common_dir.join(name)
+        } else {
+            self.git_dir.join(name)
+        }
+    }
+
+    /// Read a ref without following symbolic refs
+    pub fn read(&self, name: &str) -> Result<Option<RefValue>> {
+        let path = self.path(name);
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                return RefValue::parse(&content)
+                    .map(Some)
+                    .with_context(|| format!("Invalid ref {name}"));
+            }
+            // A directory, e.g `refs/heads` itself, isn't a ref
+            Err(_) if path.is_dir() => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Reading ref {name}")),
+        }
+
+        Ok(self
+            .packed_refs()?
+            .find(name)
+            .map(|r| RefValue::Direct(r.oid)))
+    }
+
+    /// Follow symbolic refs from `name`, and return the name of the final ref along with its
+    /// value, which is `None` if it doesn't exist (e.g HEAD on an unborn branch).
+    pub fn resolve_symbolic(&self, name: &str) -> Result<(String, Option<ObjectId>)> {
+        let mut name = name.to_string();
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.read(&name)? {
+                None => return Ok((name, None)),
+                Some(RefValue::Direct(oid)) => return Ok((name, Some(oid))),
+                Some(RefValue::Symbolic(target)) => name = target,
+            }
+        }
+        bail!("Too many levels of symbolic refs at {name}")
+    }
+
+    /// Object id a ref points to, following symbolic refs
+    pub fn resolve(&self, name: &str) -> Result<Option<ObjectId>> {
+        Ok(self.resolve_symbolic(name)?.1)
+    }
+
+    pub fn packed_refs(&self) -> Result<PackedRefs> {
+        match fs::read_to_string(self.common_dir.join("packed-refs")) {
+            Ok(content) => PackedRefs::parse(&content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PackedRefs::default()),
+            Err(e) => Err(e).context("Reading packed-refs"),
+        }
+    }
+
+    /// All the refs whose name starts with `prefix` (e.g `refs/tags/`), sorted by name, with
+    /// symbolic refs resolved
+    pub fn list(&self, prefix: &str) -> Result<Vec<Ref>> {
+        let mut names = self
+            .packed_refs()?
+            .refs
+            .into_iter()
+            .map(|r| r.name)
+            .collect::<Vec<_>>();
+        find_loose_refs(&self.common_dir.join("refs"), "refs", &mut names)?;
+        names.sort();
+        names.dedup();
+
+        let mut refs = Vec::new();
+        for name in names.into_iter().filter(|n| n.starts_with(prefix)) {
+            // Symbolic refs pointing nowhere are skipped
+            match self.resolve(&name) {
+                Ok(Some(oid)) => refs.push(Ref { oid, name }),
+                Ok(None) => {}
+                Err(_) => eprintln!("warning: ignoring broken ref {name}"),
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Set a ref, creating it if needed
+    pub fn write(&self, name: &str, value: &RefValue) -> Result<()> {
+        let mut transaction = RefTransaction::new();
+        transaction.add(RefUpdate {
+            name: name.to_string(),
+            change: RefChange::Set(value.clone()),
+            old: None,
+            deref: false,
+        });
+        transaction.commit(self)
+    }
+
+    /// Delete a ref, both the loose file and its entry in `packed-refs`
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let mut transaction = RefTransaction::new();
+        transaction.add(RefUpdate {
+            name: name.to_string(),
+            change: RefChange::Delete,
+            old: None,
+            deref: false,
+        });
+        transaction.commit(self)
+    }
+
+    /// Path of the reflog of a ref, in `logs/`
+    fn log_path(&self, name: &str) -> PathBuf {
+        if name.starts_with("refs/") {
+            self.common_dir.join("logs").join(name)
+        } else {
+            self.git_dir.join("logs").join(name)
+        }
+    }
+
+    pub fn has_reflog(&self, name: &str) -> bool {
+        self.log_path(name).is_file()
+    }
+
+    /// Names of the refs which have a reflog, sorted
+    pub fn reflog_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        find_loose_refs(&self.common_dir.join("logs/refs"), "refs", &mut names)?;
+        names.sort();
+        if self.has_reflog("HEAD") {
+            names.insert(0, "HEAD".to_string());
+        }
+        Ok(names)
+    }
+
+    /// Entries of the reflog of a ref, oldest first. A ref without a reflog has no entries.
+    pub fn read_reflog(&self, name: &str) -> Result<Vec<ReflogEntry>> {
+        match fs::read_to_string(self.log_path(name)) {
+            Ok(content) => ReflogEntry::parse_all(&content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).with_context(|| format!("Reading reflog of {name}")),
+        }
+    }
+
+    /// Replace the whole reflog of a ref, going through a lock file
+    pub fn write_reflog(&self, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+        let mut lock = LockFile::acquire(&self.log_path(name))
+            .with_context(|| format!("Unable to lock reflog of {name}"))?;
+        let content: String = entries.iter().map(|e| format!("{e}\n")).collect();
+        lock.write(content.as_bytes())?;
+        Ok(lock.commit()?)
+    }
+
+    pub fn append_reflog(&self, name: &str, entry: &ReflogEntry) -> Result<()> {
+        let path = self.log_path(name);
+        fs::create_dir_all(path.parent().expect("reflog to have a parent"))?;
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Opening reflog of {name}"))?;
+        file.write_all(format!("{entry}\n").as_bytes())
+            .with_context(|| format!("Writing reflog of {name}"))
+    }
+
+    /// Move the reflog of a ref to another ref, if it has one
+    pub fn rename_reflog(&self, old_name: &str, new_name: &str) -> Result<()> {
+        if !self.has_reflog(old_name) {
+            return Ok(());
+        }
+        let new_path = self.log_path(new_name);
+        fs::create_dir_all(new_path.parent().expect("reflog to have a parent"))?;
+        fs::rename(self.log_path(old_name), new_path)
+            .with_context(|| format!("Moving reflog of {old_name} to {new_name}"))
+    }
+
+    pub fn delete_reflog(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.log_path(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Deleting reflog of {name}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether updates of a ref are recorded in its reflog, according to
+    /// `core.logAllRefUpdates`. A ref which already has a reflog is always logged.
+    fn should_log(&self, name: &str, config: &Config) -> Result<bool> {
+        if self.has_reflog(name) {
+            return Ok(true);
+        }
+        let log_all = match config.get("core.logallrefupdates") {
+            Some(value) if value.eq_ignore_ascii_case("always") => return Ok(true),
+            Some(_) => config.get_bool("core.logallrefupdates")?.unwrap_or(false),
+            // Enabled by default, except in bare repos
+            None => config.get_bool("core.bare")? != Some(true),
+        };
+        Ok(log_all
+            && (name == "HEAD"
+                || name.starts_with("refs/heads/")
+                || name.starts_with("refs/remotes/")
+                || name.starts_with("refs/notes/")))
+    }
+
+    /// Write `packed-refs`, going through a lock file
+    pub fn write_packed_refs(&self, packed: &PackedRefs) -> Result<()> {
+        let mut lock = LockFile::acquire(&self.common_dir.join("packed-refs"))
+            .context("Unable to lock packed-refs")?;
+        lock.write(packed.as_string().as_bytes())?;
+        Ok(lock.commit()?)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefChange {
+    Set(RefValue),
+    Delete,
+    /// Only check the current value of the ref
+    Verify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub change: RefChange,
+    /// Expected current value, if it must be checked. `Some(None)` means the ref must not exist.
+    pub old: Option<Option<ObjectId>>,
+    /// Whether to update the ref a symbolic ref points to, rather than the symbolic ref itself
+    pub deref: bool,
+}
+
+/// A set of ref updates, that are either all applied or not at all
+#[derive(Debug, Clone, Default)]
+pub struct RefTransaction {
+    updates: Vec<RefUpdate>,
+    /// Who makes the updates and why, to record them in the reflogs
+    reflog: Option<(Signature, String)>,
+}
+
+impl RefTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, update: RefUpdate) {
+        self.updates.push(update);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Record the updates in the reflogs of the refs, with the given message
+    pub fn set_reflog(&mut self, committer: Signature, message: &str) {
+        self.reflog = Some((committer, message.to_string()));
+    }
+
+    /// Apply the updates. Every ref is locked and checked before any of them is changed, and the
+    /// locks are all released if anything fails. If putting one of the new values in place
+    /// fails, the refs that were already changed are restored.
+    pub fn commit(self, store: &RefStore) -> Result<()> {
+        self.prepare(store)?.commit()
+    }
+
+    /// Lock and check every ref, without changing any of them yet, so that other changes can be
+    /// made before committing the transaction. The locks are released if it's dropped instead.
+    pub fn prepare(self, store: &RefStore) -> Result<PreparedTransaction<'_>> {
+        // Moving the branch HEAD points to also moves HEAD, which gets its own reflog entry
+        let head_target = match store.read("HEAD")? {
+            Some(RefValue::Symbolic(target)) => Some(target),
+            _ => None,
+        };
+        let mut locked: Vec<(String, RefChange, LockFile)> = Vec::new();
+        let mut previous = Vec::new();
+        for update in self.updates {
+            let name = if update.deref {
+                store.resolve_symbolic(&update.name)?.0
+            } else {
+                update.name
+            };
+            ensure!(is_valid_name(&name), "Invalid ref name: {name}");
+            ensure!(
+                !locked.iter().any(|(n, ..)| *n == name),
+                "Multiple updates for ref '{name}' not allowed"
+            );
+            let lock = LockFile::acquire(&store.path(&name))
+                .with_context(|| format!("Cannot lock ref '{name}'"))?;
+
+            let current = store.resolve(&name)?;
+            if let Some(expected) = update.old {
+                if current != expected {
+                    let reason = match (current, expected) {
+                        (Some(_), None) => "reference already exists".to_string(),
+                        (None, _) => "unable to resolve reference".to_string(),
+                        (Some(current), Some(expected)) => {
+                            format!("is at {current} but expected {expected}")
+                        }
+                    };
+                    bail!("Cannot lock ref '{name}': {reason}");
+                }
+            }
+            locked.push((name, update.change, lock));
+            previous.push(current);
+        }
+
+        Ok(PreparedTransaction {
+            store,
+            locked,
+            previous,
+            head_target,
+            reflog: self.reflog,
+        })
+    }
+}
+
+/// A [`RefTransaction`] whose refs are locked and checked, ready to be committed
+#[derive(Debug)]
+pub struct PreparedTransaction<'a> {
+    store: &'a RefStore,
+    locked: Vec<(String, RefChange, LockFile)>,
+    /// Value of each locked ref before the transaction
+    previous: Vec<Option<ObjectId>>,
+    /// The ref HEAD points to, if it's symbolic
+    head_target: Option<String>,
+    reflog: Option<(Signature, String)>,
+}
+
+impl PreparedTransaction<'_> {
+    /// Apply the updates, see [`RefTransaction::commit`]
+    pub fn commit(self) -> Result<()> {
+        let Self {
+            store,
+            mut locked,
+            previous,
+            head_target,
+            reflog,
+        } = self;
+
+        // Deleted refs also need to be removed from `packed-refs`
+        let mut packed = store.packed_refs()?;
+        let mut packed_changed = false;
+        for (name, change, _) in &locked {
+            if *change == RefChange::Delete {
+                packed_changed |= packed.remove(name);
+            }
+        }
+        let packed_lock = if packed_changed {
+            let mut lock = LockFile::acquire(&store.common_dir.join("packed-refs"))
+                .context("Unable to lock packed-refs")?;
+            lock.write(packed.as_string().as_bytes())?;
+            Some(lock)
+        } else {
+            None
+        };
+
+        for (name, change, lock) in &mut locked {
+            if let RefChange::Set(value) = change {
+                lock.write(format!("{value}\n").as_bytes())
+                    .with_context(|| format!("Writing ref {name}"))?;
+            }
+        }
+
+        // Everything is ready: put the new values in place, keeping the previous content of the
+        // files to restore them if anything fails
+        let mut applied = Vec::new();
+        if let Err(e) = apply(store, packed_lock, &mut locked, &mut applied) {
+            for (path, content) in applied.into_iter().rev() {
+                let _ = restore(&path, content.as_deref());
+            }
+            return Err(e);
+        }
+
+        let mut changed = Vec::new();
+        for ((name, change, _), old) in locked.into_iter().zip(previous) {
+            match change {
+                RefChange::Set(_) => changed.push((name, old)),
+                RefChange::Delete => store.delete_reflog(&name)?,
+                RefChange::Verify => {}
+            }
+        }
+
+        if let Some((committer, message)) = reflog {
+            let config = Config::load(Some(&store.git_dir))?;
+            for (name, old) in changed {
+                let entry = ReflogEntry {
+                    old: old.unwrap_or(ObjectId::ZERO),
+                    new: store.resolve(&name)?.unwrap_or(ObjectId::ZERO),
+                    committer: committer.clone(),
+                    message: message.clone(),
+                };
+                if store.should_log(&name, &config)? {
+                    store.append_reflog(&name, &entry)?;
+                }
+                if head_target.as_deref() == Some(name.as_str())
+                    && store.should_log("HEAD", &config)?
+                {
+                    store.append_reflog("HEAD", &entry)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Put the new values of the locked refs (and `packed-refs`) in place, recording the previous
+/// content of each file changed in `applied`
+fn apply(
+    store: &RefStore,
+    packed_lock: Option<LockFile>,
+    locked: &mut [(String, RefChange, LockFile)],
+    applied: &mut Vec<(PathBuf, Option<Vec<u8>>)>,
+) -> Result<()> {
+    if let Some(lock) = packed_lock {
+        let (path, previous) = (lock.path.clone(), read_optional(&lock.path)?);
+        lock.commit().context("Updating packed-refs")?;
+        applied.push((path, previous));
+    }
+    for (name, change, lock) in locked {
+        let path = store.path(name);
+        match change {
+            RefChange::Set(_) => {
+                let previous = read_optional(&path)?;
+                lock.commit_in_place()
+                    .with_context(|| format!("Updating ref {name}"))?;
+                applied.push((path, previous));
+            }
+            RefChange::Delete => {
+                let previous = read_optional(&path)?;
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e).with_context(|| format!("Deleting ref {name}"));
+                    }
+                    _ => applied.push((path, previous)),
+                }
+            }
+            RefChange::Verify => {}
+        }
+    }
+    Ok(())
+}
+
+/// Content of a file, or `None` if it doesn't exist
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+    }
+}
+
+/// Put back the previous content of a file, or remove it if it didn't exist
+fn restore(path: &Path, content: Option<&[u8]>) -> Result<()> {
+    match content {
+        Some(content) => {
+            let mut lock = LockFile::acquire(path)?;
+            lock.write(content)?;
+            lock.commit()?;
+        }
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+/// A `<file>.lock` file, used to get exclusive access to a file while preparing its new
+/// content. The lock file is removed when dropped, unless it's been committed.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(path: &Path) -> Result<Self> {
+        fs::create_dir_all(path.parent().expect("locked file to have a parent"))?;
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_context(|| {
+                format!(
+                    "Unable to create '{}': another process may be running",
+                    lock_path.display()
+                )
+            })?;
+        Ok(Self {
+            path: path.to_owned(),
+            lock_path,
+            file,
+            committed: false,
+        })
+    }
+
+    /// Write the new content of the file, and make sure it's on disk
+    pub(crate) fn write(&mut self, content: &[u8]) -> io::Result<()> {
+        self.file.write_all(content)?;
+        self.file.sync_all()
+    }
+
+    /// Replace the file with the lock file
+    pub(crate) fn commit(mut self) -> io::Result<()> {
+        self.commit_in_place()
+    }
+
+    /// Like [`commit`](Self::commit), for a lock that can't be moved out of a collection
+    fn commit_in_place(&mut self) -> io::Result<()> {
+        fs::rename(&self.lock_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// Whether `name` is a valid ref name, following the rules of `git check-ref-format`
+pub fn check_ref_format(name: &str) -> bool {
+    !name.is_empty()
+        && name != "@"
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|part| !part.starts_with('.') && !part.ends_with(".lock"))
+}
+
+/// Whether `name` can be written: either under `refs/`, or a pseudo-ref like `HEAD`
+fn is_valid_name(name: &str) -> bool {
+    if name.starts_with("refs/") {
+        check_ref_format(name)
+    } else {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+    }
+}
+
+/// Full names to try, in order, for a short ref name like `master` or `v1.0`
+pub fn dwim_names(name: &str) -> Vec<String> {
+    [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ]
+    .into_iter()
+    .filter(|full_name| is_valid_name(full_name))
+    .collect()
+}
+
+/// Collect the names of the loose refs in `dir`, whose name is `prefix`
+fn find_loose_refs(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("Reading refs"),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let name = format!("{prefix}/{file_name}");
+        if entry.file_type()?.is_dir() {
+            find_loose_refs(&entry.path(), &name, names)?;
+        } else if check_ref_format(&name) {
+            // skips lock files
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed_refs() {
+        let content = "# pack-refs with: peeled fully-peeled sorted \n\
+                       e37a3b6af309a86aef68d7296d908a1b067bb0e6 refs/heads/master\n\
+                       5dad6da107b0ebf80c2cfe54cce800730c137b46 refs/tags/light\n\
+                       6e6de7d6035b2a207adb637a475d8de1e00c99be refs/tags/v1\n\
+                       ^e37a3b6af309a86aef68d7296d908a1b067bb0e6\n";
+        let packed = PackedRefs::parse(content).unwrap();
+
+        let v1 = packed.find("refs/tags/v1").unwrap();
+        assert_eq!(
+            v1.peeled,
+            Some(ObjectId::from_str("e37a3b6af309a86aef68d7296d908a1b067bb0e6").unwrap())
+        );
+        assert_eq!(packed.find("refs/tags/light").unwrap().peeled, None);
+        assert!(packed.find("refs/heads/main").is_none());
+        assert_eq!(packed.as_string(), content);
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let dir = std::env::temp_dir().join(format!("refs-test-{}", std::process::id()));
+        let store = RefStore::new(dir.clone(), dir.clone());
+        let one = ObjectId::from_str("e37a3b6af309a86aef68d7296d908a1b067bb0e6").unwrap();
+        let two = ObjectId::from_str("5dad6da107b0ebf80c2cfe54cce800730c137b46").unwrap();
+        store.write("refs/heads/a", &RefValue::Direct(one)).unwrap();
+
+        let mut transaction = RefTransaction::new();
+        transaction.add(RefUpdate {
+            name: "refs/heads/b".to_string(),
+            change: RefChange::Set(RefValue::Direct(two)),
+            old: Some(None),
+            deref: true,
+        });
+        // Fails, since `a` isn't at `two`
+        transaction.add(RefUpdate {
+            name: "refs/heads/a".to_string(),
+            change: RefChange::Delete,
+            old: Some(Some(two)),
+            deref: true,
+        });
+        let res = transaction.commit(&store);
+        let b = store.read("refs/heads/b").unwrap();
+        let a = store.resolve("refs/heads/a").unwrap();
+        let leftovers = fs::read_dir(dir.join("refs/heads")).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(res.is_err());
+        assert_eq!(b, None);
+        assert_eq!(a, Some(one));
+        // No lock file left behind
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn test_transaction_restores_applied_updates() {
+        let dir = std::env::temp_dir().join(format!("refs-restore-test-{}", std::process::id()));
+        let store = RefStore::new(dir.clone(), dir.clone());
+        let one = ObjectId::from_str("e37a3b6af309a86aef68d7296d908a1b067bb0e6").unwrap();
+        let two = ObjectId::from_str("5dad6da107b0ebf80c2cfe54cce800730c137b46").unwrap();
+        store.write("refs/heads/a", &RefValue::Direct(one)).unwrap();
+        let packed = format!("{PACKED_REFS_HEADER}{one} refs/heads/p\n");
+        fs::write(dir.join("packed-refs"), &packed).unwrap();
+        // A directory where the ref `d` would be can't be deleted like a ref, so the last update
+        // fails once the other ones are in place
+        fs::create_dir_all(dir.join("refs/heads/d/x")).unwrap();
+
+        let mut transaction = RefTransaction::new();
+        for (name, change) in [
+            ("refs/heads/a", RefChange::Set(RefValue::Direct(two))),
+            ("refs/heads/new", RefChange::Set(RefValue::Direct(two))),
+            ("refs/heads/p", RefChange::Delete),
+            ("refs/heads/d", RefChange::Delete),
+        ] {
+            transaction.add(RefUpdate {
+                name: name.to_string(),
+                change,
+                old: None,
+                deref: false,
+            });
+        }
+        let res = transaction.commit(&store);
+        let a = store.resolve("refs/heads/a").unwrap();
+        let new = store.read("refs/heads/new").unwrap();
+        let packed_after = fs::read_to_string(dir.join("packed-refs")).unwrap();
+        let mut leftovers = fs::read_dir(dir.join("refs/heads"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        leftovers.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(res.is_err());
+        assert_eq!(a, Some(one));
+        assert_eq!(new, None);
+        assert_eq!(packed_after, packed);
+        assert_eq!(leftovers, ["a", "d"]);
+    }
+
+    #[test]
+    fn test_check_ref_format() {
+        assert!(check_ref_format("refs/heads/feature/x"));
+        assert!(!check_ref_format("refs/heads/a..b"));
+        assert!(!check_ref_format("refs/heads/x.lock"));
+        assert!(!check_ref_format("refs/heads/.hidden"));
+        assert!(!check_ref_format("refs/heads/a b"));
+        assert!(!check_ref_format("refs/heads/x/"));
+    }
+}
@@ -0,0 +1,597 @@
+//! Module to resolve revisions as in `git rev-parse`: `HEAD~2`, `v1.0^{tree}`, `master@{1}`,
+//! `HEAD:src/main.rs`, `main..topic`...
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::refs::dwim_names;
+use crate::{date, Commit, GitRepo, Head, ObjectId, ObjectType};
+
+/// Shortest abbreviated object id that is looked up
+const MIN_ABBREV: usize = 4;
+
+/// A revision argument: a single object, or a range of commits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revision {
+    Single(ObjectId),
+    /// `A..B`: the commits reachable from B but not from A
+    Range(ObjectId, ObjectId),
+    /// `A...B`: the commits reachable from either A or B but not from both, along with the merge
+    /// bases of A and B
+    Symmetric(ObjectId, ObjectId, Vec<ObjectId>),
+}
+
+impl GitRepo {
+    /// Resolve a revision, which may be a range of commits
+    pub fn parse_revision(&self, spec: &str) -> Result<Revision> {
+        // `..` in a path, as in `HEAD:../file`, doesn't make a range
+        let revs = split_path(spec).map_or(spec, |(rev, _)| rev);
+        let Some(idx) = revs.find("..") else {
+            return Ok(Revision::Single(self.resolve_revision(spec)?));
+        };
+
+        let (from, to) = (&spec[..idx], &spec[idx + 2..]);
+        let (symmetric, to) = match to.strip_prefix('.') {
+            Some(to) => (true, to),
+            None => (false, to),
+        };
+        // A missing end is HEAD
+        let resolve = |rev: &str| self.resolve_commit(if rev.is_empty() { "HEAD" } else { rev });
+        let (from, to) = (resolve(from)?, resolve(to)?);
+        Ok(if symmetric {
+            Revision::Symmetric(from, to, self.merge_bases(from, to)?)
+        } else {
+            Revision::Range(from, to)
+        })
+    }
+
+    /// Object meant by a single revision
+    pub fn resolve_revision(&self, rev: &str) -> Result<ObjectId> {
+        if let Some(path) = rev.strip_prefix(':') {
+            return self.index_object(path);
+        }
+        if let Some((tree_rev, path)) = split_path(rev) {
+            let tree = self.peel(self.resolve_revision(tree_rev)?, ObjectType::Tree)?;
+            return self.tree_object(tree, path, tree_rev);
+        }
+
+        // The name ends where the operators start
+        let (name, mut ops) = rev.split_at(rev.find(['~', '^']).unwrap_or(rev.len()));
+        let mut oid = match name.split_once("@{") {
+            Some((name, selector)) => {
+                let selector = selector
+                    .strip_suffix('}')
+                    .ok_or_else(|| anyhow!("Invalid revision: {rev}"))?;
+                self.resolve_selector(name, selector)?
+            }
+            None if name == "@" => self.resolve_name("HEAD")?,
+            None => self.resolve_name(name)?,
+        };
+
+        while let Some(op) = ops.chars().next() {
+            ops = &ops[1..];
+            if op == '^' && ops.starts_with('{') {
+                let end = ops
+                    .find('}')
+                    .ok_or_else(|| anyhow!("Invalid revision: {rev}"))?;
+                oid = match &ops[1..end] {
+                    "" => self.peel_tags(oid)?,
+                    "object" => oid,
+                    object_type => {
+                        let object_type = ObjectType::from_str(object_type)
+                            .map_err(|_| anyhow!("Invalid object type in {rev}"))?;
+                        self.peel(oid, object_type)?
+                    }
+                };
+                ops = &ops[end + 1..];
+                continue;
+            }
+
+            // `~` and `^` are the same as `~1` and `^1`
+            let digits = ops.find(|c: char| !c.is_ascii_digit()).unwrap_or(ops.len());
+            let n = match digits {
+                0 => 1,
+                _ => ops[..digits]
+                    .parse()
+                    .with_context(|| format!("Invalid revision: {rev}"))?,
+            };
+            ops = &ops[digits..];
+            oid = match op {
+                '~' => {
+                    // Like `^0`, `~0` peels to a commit
+                    oid = self.peel(oid, ObjectType::Commit)?;
+                    for _ in 0..n {
+                        oid = self.nth_parent(oid, 1)?;
+                    }
+                    oid
+                }
+                '^' if n == 0 => self.peel(oid, ObjectType::Commit)?,
+                '^' => self.nth_parent(oid, n)?,
+                _ => bail!("Invalid revision: {rev}"),
+            };
+        }
+
+        Ok(oid)
+    }
+
+    /// Commit meant by a revision, peeling tags
+    pub fn resolve_commit(&self, rev: &str) -> Result<ObjectId> {
+        self.peel(self.resolve_revision(rev)?, ObjectType::Commit)
+    }
+
+    /// Full name and value of the ref meant by a short name like `master`, trying the possible
+    /// names in the same order as git
+    pub fn dwim_ref(&self, name: &str) -> Result<Option<(String, ObjectId)>> {
+        for full_name in dwim_names(name) {
+            if let Some(oid) = self.refs.resolve(&full_name)? {
+                return Ok(Some((full_name, oid)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remote-tracking branch that a branch tracks, according to `branch.<name>.remote` and
+    /// `branch.<name>.merge`
+    pub fn upstream(&self, branch: &str) -> Result<String> {
+        let short = branch
+            .strip_prefix("refs/heads/")
+            .ok_or_else(|| anyhow!("'{branch}' is not a branch"))?;
+        let config = self.config()?;
+        let remote = config.get(&format!("branch.{short}.remote"));
+        let merge = config.get(&format!("branch.{short}.merge"));
+        let (Some(remote), Some(merge)) = (remote, merge) else {
+            bail!("No upstream configured for branch '{short}'");
+        };
+        // The upstream of a branch can be another local branch
+        if remote == "." {
+            return Ok(merge.to_string());
+        }
+        let merge = merge.strip_prefix("refs/heads/").unwrap_or(merge);
+        Ok(format!("refs/remotes/{remote}/{merge}"))
+    }
+
+    /// Follow tags, and commits to their tree, until reaching an object of type `target`
+    pub fn peel(&self, mut oid: ObjectId, target: ObjectType) -> Result<ObjectId> {
+        loop {
+            let object = self.get_object(oid)?;
+            if object.object_type == target {
+                return Ok(oid);
+            }
+            oid = match object.object_type {
+                ObjectType::Tag => object.as_tag().context("Invalid tag")?.object,
+                ObjectType::Commit if target == ObjectType::Tree => {
+                    object.as_commit().context("Invalid commit")?.tree
+                }
+                object_type => bail!("{oid} is a {object_type}, not a {target}"),
+            };
+        }
+    }
+
+    /// Follow tags until reaching an object which isn't one
+    fn peel_tags(&self, mut oid: ObjectId) -> Result<ObjectId> {
+        loop {
+            match self.get_object(oid)?.as_tag() {
+                Some(tag) => oid = tag.object,
+                None => return Ok(oid),
+            }
+        }
+    }
+
+    /// The commits selected by the given revisions, as for `git log`: the ones reachable from
+    /// any of them, but not from the excluded ones (`^A`, or A in `A..B`). HEAD by default.
+    /// The most recent commits come first, and are only read as the walk reaches them.
+    pub fn rev_list(&self, revisions: &[String]) -> Result<RevWalk<'_>> {
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for revision in revisions {
+            if let Some(revision) = revision.strip_prefix('^') {
+                excluded.push(self.resolve_commit(revision)?);
+                continue;
+            }
+            match self.parse_revision(revision)? {
+                Revision::Single(oid) => included.push(self.peel(oid, ObjectType::Commit)?),
+                Revision::Range(from, to) => {
+                    excluded.push(from);
+                    included.push(to);
+                }
+                Revision::Symmetric(left, right, bases) => {
+                    included.extend([left, right]);
+                    excluded.extend(bases);
+                }
+            }
+        }
+        if revisions.is_empty() {
+            included.push(self.resolve_commit("HEAD")?);
+        }
+
+        let mut walk = RevWalk {
+            repo: self,
+            excluded: self.ancestors(&excluded)?,
+            queue: BinaryHeap::new(),
+            found: HashMap::new(),
+            seen: HashSet::new(),
+        };
+        for oid in included {
+            walk.push(oid)?;
+        }
+        Ok(walk)
+    }
+
+    /// All the commits reachable from the given ones, including themselves
+    pub fn ancestors(&self, commits: &[ObjectId]) -> Result<HashSet<ObjectId>> {
+        let mut seen = HashSet::new();
+        let mut queue = commits.to_vec();
+        while let Some(oid) = queue.pop() {
+            if seen.insert(oid) {
+                queue.extend(self.read_commit(oid)?.parents);
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Best common ancestors of two commits, i.e the common ancestors which aren't ancestors of
+    /// other common ancestors
+    pub fn merge_bases(&self, a: ObjectId, b: ObjectId) -> Result<Vec<ObjectId>> {
+        let from_a = self.ancestors(&[a])?;
+        let common = self
+            .ancestors(&[b])?
+            .into_iter()
+            .filter(|oid| from_a.contains(oid))
+            .collect::<HashSet<_>>();
+
+        let mut parents = Vec::new();
+        for oid in &common {
+            parents.extend(self.read_commit(*oid)?.parents);
+        }
+        let below = self.ancestors(&parents)?;
+        let mut bases = common.difference(&below).copied().collect::<Vec<_>>();
+        bases.sort();
+        Ok(bases)
+    }
+
+    fn read_commit(&self, oid: ObjectId) -> Result<Commit> {
+        self.get_object(oid)?
+            .as_commit()
+            .ok_or_else(|| anyhow!("{oid} is not a commit"))
+    }
+
+    /// `n`th parent of a commit, starting at 1
+    fn nth_parent(&self, oid: ObjectId, n: usize) -> Result<ObjectId> {
+        let commit = self.read_commit(self.peel(oid, ObjectType::Commit)?)?;
+        commit
+            .parents
+            .get(n - 1)
+            .copied()
+            .ok_or_else(|| anyhow!("Commit {oid} doesn't have a parent #{n}"))
+    }
+
+    /// Object meant by a name: a full or abbreviated object id, or a ref name
+    fn resolve_name(&self, name: &str) -> Result<ObjectId> {
+        if let Ok(oid) = ObjectId::from_str(name) {
+            return Ok(oid);
+        }
+        // Refs take precedence over abbreviated ids
+        if let Some((_, oid)) = self.dwim_ref(name)? {
+            return Ok(oid);
+        }
+        if name.len() >= MIN_ABBREV && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            if let Some(oid) = self.resolve_prefix(name)? {
+                return Ok(oid);
+            }
+        }
+        bail!("Unknown revision: {name}")
+    }
+
+    /// `<name>@{<selector>}`: a previous value of a ref from its reflog, by position or date, the
+    /// upstream of a branch, or a previously checked out branch with `@{-<n>}`
+    fn resolve_selector(&self, name: &str, selector: &str) -> Result<ObjectId> {
+        if let Some(n) = selector.strip_prefix('-') {
+            ensure!(name.is_empty(), "Invalid revision: {name}@{{{selector}}}");
+            let n = n.parse().context("Invalid @{-<n>}")?;
+            return self.resolve_name(&self.previous_branch(n)?);
+        }
+
+        // No name is the current branch
+        let full_name = match name {
+            "" => match self.head()? {
+                Head::Branch(branch, _) => branch,
+                Head::Detached(_) => "HEAD".to_string(),
+            },
+            _ => {
+                self.dwim_ref(name)?
+                    .ok_or_else(|| anyhow!("Unknown ref: {name}"))?
+                    .0
+            }
+        };
+        if selector.eq_ignore_ascii_case("u") || selector.eq_ignore_ascii_case("upstream") {
+            let upstream = self.upstream(&full_name)?;
+            return self
+                .refs
+                .resolve(&upstream)?
+                .ok_or_else(|| anyhow!("Upstream branch {upstream} doesn't exist"));
+        }
+
+        let entries = self.refs.read_reflog(&full_name)?;
+        if let Ok(n) = selector.parse::<usize>() {
+            return entries.iter().rev().nth(n).map(|e| e.new).ok_or_else(|| {
+                anyhow!("Log for '{full_name}' only has {} entries", entries.len())
+            });
+        }
+
+        // The value at a given date
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let timestamp = date::parse_expiry(selector, now)?;
+        match entries
+            .iter()
+            .rev()
+            .find(|e| e.committer.timestamp <= timestamp)
+        {
+            Some(entry) => Ok(entry.new),
+            // Before the first entry, use the oldest known value
+            None => {
+                let first = entries
+                    .first()
+                    .ok_or_else(|| anyhow!("Log for '{full_name}' is empty"))?;
+                let date = date::format_date(first.committer.timestamp, first.committer.tz_offset);
+                eprintln!("warning: log for '{full_name}' only goes back to {date}");
+                Ok(if first.old != ObjectId::ZERO {
+                    first.old
+                } else {
+                    first.new
+                })
+            }
+        }
+    }
+
+    /// Branch checked out before the `n`th last checkout, from the reflog of HEAD
+    fn previous_branch(&self, n: usize) -> Result<String> {
+        ensure!(n > 0, "Invalid @{{-0}}");
+        self.refs
+            .read_reflog("HEAD")?
+            .iter()
+            .rev()
+            .filter_map(|e| e.message.strip_prefix("checkout: moving from "))
+            .filter_map(|moved| moved.split_once(" to ").map(|(from, _)| from.to_string()))
+            .nth(n - 1)
+            .ok_or_else(|| anyhow!("There weren't {n} checkouts before"))
+    }
+
+    /// `:<path>` or `:<stage>:<path>`: a file in the index
+    fn index_object(&self, path: &str) -> Result<ObjectId> {
+        let (stage, path) = match path.split_once(':') {
+            // Anything else is part of the path, as in `:a:b`
+            Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse::<u8>()?, path),
+            _ => (0, path),
+        };
+        self.read_index()?
+            .entries
+            .iter()
+            .find(|e| e.path == path.as_bytes() && e.stage == stage)
+            .map(|e| e.oid)
+            .ok_or_else(|| anyhow!("Path '{path}' is not in the index at stage {stage}"))
+    }
+
+    /// Object at `path` in a tree
+    fn tree_object(&self, tree: ObjectId, path: &str, rev: &str) -> Result<ObjectId> {
+        let mut oid = tree;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            oid = self
+                .get_object(oid)?
+                .as_tree()
+                .and_then(|tree| {
+                    let entry = tree.entries().iter().find(|e| e.name == name)?;
+                    Some(entry.sha1)
+                })
+                .ok_or_else(|| anyhow!("Path '{path}' does not exist in '{rev}'"))?;
+        }
+        Ok(oid)
+    }
+}
+
+/// The commits of [`GitRepo::rev_list`], walked from the most recent ones like git: commits with
+/// the same date come in the order they were found
+pub struct RevWalk<'a> {
+    repo: &'a GitRepo,
+    excluded: HashSet<ObjectId>,
+    /// Commits to visit, by committer date then discovery order
+    queue: BinaryHeap<(i64, Reverse<usize>, ObjectId)>,
+    /// Commits in the queue
+    found: HashMap<ObjectId, Commit>,
+    seen: HashSet<ObjectId>,
+}
+
+impl RevWalk<'_> {
+    fn push(&mut self, oid: ObjectId) -> Result<()> {
+        if self.seen.insert(oid) {
+            let commit = self.repo.read_commit(oid)?;
+            let order = Reverse(self.seen.len());
+            self.queue.push((commit.committer.timestamp, order, oid));
+            self.found.insert(oid, commit);
+        }
+        Ok(())
+    }
+
+    fn next_commit(&mut self) -> Result<Option<(ObjectId, Commit)>> {
+        while let Some((_, _, oid)) = self.queue.pop() {
+            let commit = self.found.remove(&oid).expect("queued commit to be read");
+            if self.excluded.contains(&oid) {
+                continue;
+            }
+            for parent in &commit.parents {
+                self.push(*parent)?;
+            }
+            return Ok(Some((oid, commit)));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<(ObjectId, Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_commit().transpose()
+    }
+}
+
+/// Split `<rev>:<path>`, ignoring colons in `@{...}` (which may contain a time)
+fn split_path(rev: &str) -> Option<(&str, &str)> {
+    let mut in_braces = false;
+    for (i, c) in rev.char_indices() {
+        match c {
+            '{' => in_braces = true,
+            '}' => in_braces = false,
+            ':' if !in_braces => return Some((&rev[..i], &rev[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tests::temp_repo;
+    use crate::Object;
+
+    #[test]
+    fn test_rev_list_stops_early() {
+        let repo = temp_repo("rev-list-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        let mut commits = Vec::new();
+        for i in 0..3 {
+            fs::write(dir.join("a"), format!("{i}\n")).unwrap();
+            repo.add(&[dir.join("a")]).unwrap();
+            commits.push(repo.commit(format!("commit {i}")).unwrap());
+        }
+        // Only the commits shown, and their parents, are read
+        fs::remove_file(repo.get_object_path(commits[0])).unwrap();
+        let first = repo
+            .rev_list(&[])
+            .and_then(|walk| walk.take(1).collect::<Result<Vec<_>>>());
+        let all = repo
+            .rev_list(&[])
+            .and_then(|walk| walk.collect::<Result<Vec<_>>>());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let first = first.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].0, commits[2]);
+        assert!(all.is_err());
+    }
+
+    #[test]
+    fn test_resolve_revision() {
+        let repo = temp_repo("revision-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        let commit = |files: &[(&str, &str)], message: &str| {
+            for (path, content) in files {
+                fs::write(dir.join(path), content).unwrap();
+            }
+            repo.add(&[&dir]).unwrap();
+            repo.commit(message.to_string()).unwrap()
+        };
+        fs::create_dir(dir.join("dir")).unwrap();
+        let c1 = commit(&[("a", "1\n"), ("dir/f", "f\n"), ("x:y", "x\n")], "c1");
+        let c2 = commit(&[("a", "2\n")], "c2");
+        repo.checkout(Some(&c1.to_string()), Some("side"), false, false)
+            .unwrap();
+        let c3 = commit(&[("b", "b\n")], "c3");
+        repo.checkout(Some("master"), None, false, false).unwrap();
+        let tree2 = repo.peel(c2, ObjectType::Tree).unwrap();
+        let merge = repo
+            .write_commit(tree2, vec![c2, c3], "merge\n".to_string())
+            .unwrap();
+        let tag = repo
+            .create_tag("v1", Some(c1), Some("v1".to_string()), false)
+            .unwrap();
+        repo.set_upstream("master", "side").unwrap();
+        // A ref named like an abbreviated id wins over the object
+        let c3_prefix = &c3.to_string()[..7];
+        repo.create_branch(c3_prefix, Some("master"), false)
+            .unwrap();
+        let blob = |content: &str| Object::blob(content.as_bytes().to_vec()).id();
+        let tree1 = repo.peel(c1, ObjectType::Tree).unwrap();
+        let dir_tree = repo.flatten_tree(tree1).unwrap();
+
+        let m = merge.to_string();
+        let cases = [
+            (format!("{m}^"), Some(c2)),
+            (format!("{m}^2"), Some(c3)),
+            (format!("{m}^3"), None),
+            (format!("{m}~2"), Some(c1)),
+            (format!("{m}^2~1"), Some(c1)),
+            (format!("{m}^0"), Some(merge)),
+            (format!("{m}^{{tree}}"), Some(tree2)),
+            ("v1".to_string(), Some(tag)),
+            ("v1^{}".to_string(), Some(c1)),
+            ("v1^{object}".to_string(), Some(tag)),
+            ("v1^{commit}".to_string(), Some(c1)),
+            ("v1^0".to_string(), Some(c1)),
+            ("v1~0".to_string(), Some(c1)),
+            ("v1^{blob}".to_string(), None),
+            ("@".to_string(), Some(c2)),
+            ("master@{0}".to_string(), Some(c2)),
+            ("master@{1}".to_string(), Some(c1)),
+            ("@{1}".to_string(), Some(c1)),
+            ("master@{5}".to_string(), None),
+            ("@{-1}".to_string(), Some(c3)),
+            ("@{-2}".to_string(), Some(c2)),
+            ("@{u}".to_string(), Some(c3)),
+            ("master@{upstream}".to_string(), Some(c3)),
+            ("side@{u}".to_string(), None),
+            (":a".to_string(), Some(blob("2\n"))),
+            (":0:a".to_string(), Some(blob("2\n"))),
+            (":1:a".to_string(), None),
+            (":x:y".to_string(), Some(blob("x\n"))),
+            (":dir/f".to_string(), Some(blob("f\n"))),
+            ("HEAD:a".to_string(), Some(blob("2\n"))),
+            ("HEAD~:a".to_string(), Some(blob("1\n"))),
+            ("side:dir/f".to_string(), Some(dir_tree["dir/f"].1)),
+            ("HEAD:missing".to_string(), None),
+            (c3_prefix.to_string(), Some(c2)),
+            (c3.to_string(), Some(c3)),
+        ];
+        let resolved = cases
+            .iter()
+            .map(|(rev, _)| repo.resolve_revision(rev).ok())
+            .collect::<Vec<_>>();
+        let ranges = [
+            repo.parse_revision("master~..master").ok(),
+            repo.parse_revision("side..").ok(),
+            repo.parse_revision("side...master").ok(),
+            repo.parse_revision("HEAD:a").ok(),
+        ];
+        fs::remove_dir_all(&dir).unwrap();
+
+        for ((rev, expected), resolved) in cases.iter().zip(resolved) {
+            assert_eq!(resolved, *expected, "{rev}");
+        }
+        assert_eq!(
+            ranges,
+            [
+                Some(Revision::Range(c1, c2)),
+                Some(Revision::Range(c3, c2)),
+                Some(Revision::Symmetric(c3, c2, vec![c1])),
+                Some(Revision::Single(blob("2\n"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(
+            split_path("HEAD:src/main.rs"),
+            Some(("HEAD", "src/main.rs"))
+        );
+        assert_eq!(split_path(":README"), Some(("", "README")));
+        assert_eq!(split_path("master@{2024-01-01 10:00:00}"), None);
+        assert_eq!(split_path("HEAD~2"), None);
+    }
+}
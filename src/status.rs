@@ -0,0 +1,477 @@
+//! Module to compare HEAD, the index and the work tree
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::ignore::IgnoreRules;
+use crate::index::{mode_from_metadata, Index, Stat};
+use crate::{GitRepo, Object, ObjectId};
+
+const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+/// Kind of change of a file between two states
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    Unmodified,
+    Added,
+    Deleted,
+    Modified,
+    /// The file changed from a regular file to a symlink or the other way around
+    TypeChanged,
+    /// The file has conflicts from a merge
+    Unmerged,
+}
+
+impl Change {
+    /// Status code, as used by `git status --porcelain`
+    pub fn code(&self) -> char {
+        match self {
+            Change::Unmodified => '.',
+            Change::Added => 'A',
+            Change::Deleted => 'D',
+            Change::Modified => 'M',
+            Change::TypeChanged => 'T',
+            Change::Unmerged => 'U',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusEntry {
+    pub path: String,
+    /// Change between HEAD and the index, i.e what is staged
+    pub staged: Change,
+    /// Change between the index and the work tree, i.e what is not staged
+    pub unstaged: Change,
+    /// Mode and id of the file in HEAD, if it's there
+    pub head: Option<(u32, ObjectId)>,
+    /// Mode and id of the file in the index, if it's there
+    pub index: Option<(u32, ObjectId)>,
+    /// Mode of the file in the work tree, if it's there
+    pub worktree_mode: Option<u32>,
+    /// For unmerged files, mode and id of the file at each stage of the conflict (base, ours,
+    /// theirs)
+    pub stages: [Option<(u32, ObjectId)>; 3],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    /// Files with staged or unstaged changes, sorted by path
+    pub entries: Vec<StatusEntry>,
+    /// Files that are neither in the index nor in HEAD. Directories that don't contain any
+    /// tracked file are listed once, with a trailing `/`.
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    pub fn compute(repo: &GitRepo) -> Result<Self> {
+        let work_tree = repo.work_tree()?;
+        let head = match repo.head_commit()? {
+            Some(commit) => {
+                let Some(commit) = repo.get_object(commit)?.as_commit() else {
+                    anyhow::bail!("HEAD doesn't point to a commit");
+                };
+                repo.flatten_tree(commit.tree)?
+            }
+            None => BTreeMap::new(),
+        };
+        let index = repo.read_index()?;
+        // Files modified in the same second as the index was written may have changed without
+        // their stat data changing, so they always need to be re-hashed.
+        let index_mtime = fs::metadata(repo.git_dir.join("index"))
+            .map(|m| Stat::from_metadata(&m).mtime)
+            .unwrap_or(0);
+
+        let mut paths = head.keys().cloned().collect::<BTreeSet<_>>();
+        let mut unmerged: BTreeMap<String, [Option<(u32, ObjectId)>; 3]> = BTreeMap::new();
+        let mut index_files = BTreeMap::new();
+        for entry in &index.entries {
+            let path = entry.path_str().into_owned();
+            paths.insert(path.clone());
+            if entry.stage == 0 {
+                index_files.insert(path, entry);
+            } else {
+                let stages = unmerged.entry(path).or_default();
+                stages[entry.stage as usize - 1] = Some((entry.mode, entry.oid));
+            }
+        }
+
+        let mut entries = Vec::new();
+        for path in paths {
+            let head_file = head.get(&path).copied();
+            let index_entry = index_files.get(path.as_str());
+            // The path of the entry isn't necessarily UTF-8
+            let full_path = match index_entry {
+                Some(entry) => work_tree.join(entry.fs_path()),
+                None => work_tree.join(&path),
+            };
+            let metadata = full_path.symlink_metadata().ok();
+            let worktree_mode = metadata.as_ref().map(mode_from_metadata);
+
+            if let Some(stages) = unmerged.get(path.as_str()) {
+                entries.push(StatusEntry {
+                    stages: *stages,
+                    path,
+                    staged: Change::Unmerged,
+                    unstaged: Change::Unmerged,
+                    head: head_file,
+                    index: None,
+                    worktree_mode,
+                });
+                continue;
+            }
+
+            let index_file = index_entry.map(|e| (e.mode, e.oid));
+            let staged = compare(head_file, index_file);
+            let unstaged = match (index_entry, &metadata) {
+                (None, _) => Change::Unmodified,
+                (Some(_), None) => Change::Deleted,
+                (Some(entry), Some(metadata)) => {
+                    let mode = mode_from_metadata(metadata);
+                    if is_gitlink(entry.mode) {
+                        // TODO: submodules
+                        Change::Unmodified
+                    } else if is_symlink(mode) != is_symlink(entry.mode) {
+                        Change::TypeChanged
+                    } else if mode != entry.mode {
+                        Change::Modified
+                    } else if Stat::from_metadata(metadata) == entry.stat
+                        && entry.stat.mtime < index_mtime
+                    {
+                        // Same stat data: no need to hash the file
+                        Change::Unmodified
+                    } else if hash_file(&full_path, metadata.is_symlink())? == entry.oid {
+                        Change::Unmodified
+                    } else {
+                        Change::Modified
+                    }
+                }
+            };
+
+            if staged != Change::Unmodified || unstaged != Change::Unmodified {
+                entries.push(StatusEntry {
+                    path,
+                    staged,
+                    unstaged,
+                    head: head_file,
+                    index: index_file,
+                    worktree_mode,
+                    stages: [None; 3],
+                });
+            }
+        }
+
+        let mut untracked = Vec::new();
+        let mut rules = IgnoreRules::load(repo)?;
+        find_untracked(work_tree, "", &index, &head, &mut rules, &mut untracked)?;
+
+        Ok(Self { entries, untracked })
+    }
+
+    /// Whether there are any changes, staged or not, including untracked files
+    pub fn is_dirty(&self) -> bool {
+        !self.entries.is_empty() || !self.untracked.is_empty()
+    }
+
+    /// Format as `git status --porcelain=v1`
+    pub fn porcelain_v1(&self) -> String {
+        let mut out = String::new();
+        for entry in &self.entries {
+            let (x, y) = match (entry.staged, entry.unstaged) {
+                (Change::Unmerged, _) => ('U', 'U'),
+                (staged, unstaged) => (short_code(staged), short_code(unstaged)),
+            };
+            out.push_str(&format!("{x}{y} {}\n", entry.path));
+        }
+        for path in &self.untracked {
+            out.push_str(&format!("?? {path}\n"));
+        }
+        out
+    }
+
+    /// Format as `git status --porcelain=v2`
+    pub fn porcelain_v2(&self) -> String {
+        let mut out = String::new();
+        for entry in &self.entries {
+            let mode_w = entry.worktree_mode.unwrap_or(0);
+            if entry.staged == Change::Unmerged {
+                let [m1, m2, m3] = entry.stages.map(mode_of);
+                let [h1, h2, h3] = entry.stages.map(oid_of);
+                out.push_str(&format!(
+                    "u UU N... {m1:06o} {m2:06o} {m3:06o} {mode_w:06o} {h1} {h2} {h3} {}\n",
+                    entry.path
+                ));
+                continue;
+            }
+            let (mode_h, oid_h) = (mode_of(entry.head), oid_of(entry.head));
+            let (mode_i, oid_i) = (mode_of(entry.index), oid_of(entry.index));
+            out.push_str(&format!(
+                "1 {}{} N... {mode_h:06o} {mode_i:06o} {mode_w:06o} {oid_h} {oid_i} {}\n",
+                entry.staged.code(),
+                entry.unstaged.code(),
+                entry.path
+            ));
+        }
+        for path in &self.untracked {
+            out.push_str(&format!("? {path}\n"));
+        }
+        out
+    }
+}
+
+impl Display for Status {
+    /// Human-readable format, similar to `git status`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let staged = self
+            .entries
+            .iter()
+            .filter(|e| e.staged != Change::Unmodified)
+            .collect::<Vec<_>>();
+        let unstaged = self
+            .entries
+            .iter()
+            .filter(|e| e.unstaged != Change::Unmodified && e.staged != Change::Unmerged)
+            .collect::<Vec<_>>();
+
+        if !staged.is_empty() {
+            writeln!(f, "Changes to be committed:")?;
+            for entry in staged {
+                writeln!(f, "\t{:<12}{}", describe(entry.staged), entry.path)?;
+            }
+            writeln!(f)?;
+        }
+        if !unstaged.is_empty() {
+            writeln!(f, "Changes not staged for commit:")?;
+            for entry in unstaged {
+                writeln!(f, "\t{:<12}{}", describe(entry.unstaged), entry.path)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.untracked.is_empty() {
+            writeln!(f, "Untracked files:")?;
+            for path in &self.untracked {
+                writeln!(f, "\t{path}")?;
+            }
+            writeln!(f)?;
+        }
+        if !self.is_dirty() {
+            writeln!(f, "nothing to commit, working tree clean")?;
+        }
+        Ok(())
+    }
+}
+
+fn mode_of(file: Option<(u32, ObjectId)>) -> u32 {
+    file.map_or(0, |(mode, _)| mode)
+}
+
+fn oid_of(file: Option<(u32, ObjectId)>) -> String {
+    file.map_or(ZERO_OID.to_string(), |(_, oid)| oid.to_string())
+}
+
+fn short_code(change: Change) -> char {
+    match change {
+        Change::Unmodified => ' ',
+        c => c.code(),
+    }
+}
+
+fn describe(change: Change) -> &'static str {
+    match change {
+        Change::Unmodified => "",
+        Change::Added => "new file:",
+        Change::Deleted => "deleted:",
+        Change::Modified => "modified:",
+        Change::TypeChanged => "typechange:",
+        Change::Unmerged => "both modified:",
+    }
+}
+
+fn compare(old: Option<(u32, ObjectId)>, new: Option<(u32, ObjectId)>) -> Change {
+    match (old, new) {
+        (None, None) => Change::Unmodified,
+        (None, Some(_)) => Change::Added,
+        (Some(_), None) => Change::Deleted,
+        (Some(old), Some(new)) if is_symlink(old.0) != is_symlink(new.0) => Change::TypeChanged,
+        (Some(old), Some(new)) if old != new => Change::Modified,
+        _ => Change::Unmodified,
+    }
+}
+
+fn is_symlink(mode: u32) -> bool {
+    mode & 0o170000 == 0o120000
+}
+
+pub(crate) fn is_gitlink(mode: u32) -> bool {
+    mode & 0o170000 == 0o160000
+}
+
+/// Compute the id the file would have as a blob. The content of a symlink is its target.
+pub(crate) fn hash_file(path: &Path, symlink: bool) -> std::io::Result<ObjectId> {
+    let content = if symlink {
+        fs::read_link(path)?.as_os_str().as_bytes().to_vec()
+    } else {
+        fs::read(path)?
+    };
+    Ok(Object::blob(content).id())
+}
+
+/// Find the untracked files in `dir`, whose path in the index is `prefix`, leaving out the
+/// ignored ones
+fn find_untracked(
+    dir: &Path,
+    prefix: &str,
+    index: &Index,
+    head: &BTreeMap<String, (u32, ObjectId)>,
+    rules: &mut IgnoreRules,
+    untracked: &mut Vec<String>,
+) -> Result<()> {
+    let added = rules.add_file(&dir.join(".gitignore"), prefix)?;
+    let mut children = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    children.sort_by_key(|e| e.file_name());
+    for e in children {
+        // ignore `.git/` directory
+        if e.file_name() == ".git" {
+            continue;
+        }
+        let path = format!("{prefix}{}", e.file_name().to_string_lossy());
+        let raw_path = [prefix.as_bytes(), e.file_name().as_bytes()].concat();
+        let is_tracked = index.get(&raw_path).is_some() || head.contains_key(&path);
+        if e.file_type()?.is_dir() {
+            // A tracked directory is a submodule, and untracked files in an ignored directory
+            // are ignored, even if it has tracked files
+            if is_tracked || rules.is_ignored(&path, true) {
+                continue;
+            }
+            let dir_prefix = format!("{path}/");
+            let has_tracked = index
+                .entries
+                .iter()
+                .any(|e| e.path.starts_with(dir_prefix.as_bytes()))
+                || head
+                    .range(dir_prefix.clone()..)
+                    .next()
+                    .is_some_and(|(p, _)| p.starts_with(&dir_prefix));
+            if has_tracked {
+                find_untracked(&e.path(), &dir_prefix, index, head, rules, untracked)?;
+            } else if has_unignored_files(&e.path(), &dir_prefix, rules)? {
+                untracked.push(dir_prefix);
+            }
+        } else if !is_tracked && !rules.is_ignored(&path, false) {
+            untracked.push(path);
+        }
+    }
+    rules.pop(added);
+    Ok(())
+}
+
+/// Whether a directory has files that aren't ignored, even in sub-directories
+fn has_unignored_files(dir: &Path, prefix: &str, rules: &mut IgnoreRules) -> Result<bool> {
+    let added = rules.add_file(&dir.join(".gitignore"), prefix)?;
+    let mut found = false;
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let path = format!("{prefix}{}", e.file_name().to_string_lossy());
+        let is_dir = e.file_type()?.is_dir();
+        if rules.is_ignored(&path, is_dir) {
+            continue;
+        }
+        found = !is_dir || has_unignored_files(&e.path(), &format!("{path}/"), rules)?;
+        if found {
+            break;
+        }
+    }
+    rules.pop(added);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::tests::temp_repo;
+
+    #[test]
+    fn test_status() {
+        let repo = temp_repo("status-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "a\n").unwrap();
+        fs::write(dir.join("b"), "b\n").unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        repo.add(&[&dir]).unwrap();
+        repo.commit("one".to_string()).unwrap();
+
+        fs::write(dir.join("a"), "modified\n").unwrap();
+        fs::remove_file(dir.join("b")).unwrap();
+        fs::write(dir.join("n"), "n\n").unwrap();
+        repo.add(&[dir.join("n")]).unwrap();
+        fs::write(dir.join("u"), "u\n").unwrap();
+        fs::write(dir.join("x.log"), "ignored\n").unwrap();
+        fs::create_dir_all(dir.join("target/debug")).unwrap();
+        fs::write(dir.join("target/debug/bin"), "ignored\n").unwrap();
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(dir.join("logs/a.log"), "ignored\n").unwrap();
+        fs::create_dir_all(dir.join("new/sub")).unwrap();
+        fs::write(dir.join("new/sub/f"), "f\n").unwrap();
+        let status = repo.status();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let status = status.unwrap();
+        assert!(status.is_dirty());
+        assert_eq!(status.porcelain_v1(), " M a\n D b\nA  n\n?? new/\n?? u\n");
+        let (a, b, n) = (
+            Object::blob(b"a\n".to_vec()).id(),
+            Object::blob(b"b\n".to_vec()).id(),
+            Object::blob(b"n\n".to_vec()).id(),
+        );
+        assert_eq!(
+            status.porcelain_v2(),
+            format!(
+                "1 .M N... 100644 100644 100644 {a} {a} a\n\
+                 1 .D N... 100644 100644 000000 {b} {b} b\n\
+                 1 A. N... 000000 100644 100644 {ZERO_OID} {n} n\n\
+                 ? new/\n\
+                 ? u\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_racy_entries() {
+        let repo = temp_repo("racy-status-test");
+        let dir = repo.work_tree().unwrap().to_path_buf();
+        fs::write(dir.join("a"), "1\n").unwrap();
+        repo.add(&[dir.join("a")]).unwrap();
+        // The file changes without its stat data changing, as if it was modified right after
+        // being added
+        fs::write(dir.join("a"), "2\n").unwrap();
+        let mut index = repo.read_index().unwrap();
+        let metadata = fs::symlink_metadata(dir.join("a")).unwrap();
+        index.entries[0].stat = Stat::from_metadata(&metadata);
+        repo.write_index(&index).unwrap();
+        let set_index_mtime = |mtime| {
+            let index = File::options().write(true).open(repo.git_dir.join("index"));
+            let mtime = UNIX_EPOCH + Duration::from_secs(mtime as u64);
+            index.unwrap().set_modified(mtime).unwrap();
+        };
+
+        // Written in the same second as the index: the stat data can't be trusted
+        set_index_mtime(index.entries[0].stat.mtime);
+        let racy = repo.status();
+        // Written before the index: the stat data is enough
+        set_index_mtime(index.entries[0].stat.mtime + 10);
+        let not_racy = repo.status();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let racy = racy.unwrap();
+        assert_eq!(racy.entries[0].unstaged, Change::Modified);
+        assert_eq!(not_racy.unwrap().entries[0].unstaged, Change::Unmodified);
+    }
+}
@@ -0,0 +1,230 @@
+//! Module to create, list and delete tags
+
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::config::glob_match;
+use crate::refs::{check_ref_format, RefChange, RefUpdate, RefValue};
+use crate::{GitRepo, Object, ObjectId, ObjectType, Ref, Tag};
+
+impl GitRepo {
+    /// Create a tag pointing to `target` (or HEAD). With a message, the tag is an annotated tag,
+    /// i.e a tag object, otherwise the ref points directly to the target. An existing tag is
+    /// only replaced with `force`.
+    pub fn create_tag(
+        &self,
+        name: &str,
+        target: Option<ObjectId>,
+        message: Option<String>,
+        force: bool,
+    ) -> Result<ObjectId> {
+        let tag_ref = format!("refs/tags/{name}");
+        ensure!(
+            check_ref_format(&tag_ref),
+            "'{name}' is not a valid tag name"
+        );
+        let previous = self.refs.resolve(&tag_ref)?;
+        ensure!(force || previous.is_none(), "Tag '{name}' already exists");
+
+        let target = match target {
+            Some(target) => target,
+            None => self.resolve_head()?,
+        };
+        let oid = match message {
+            Some(message) => {
+                let object_type = self.get_object(target)?.object_type;
+                let tag = Tag {
+                    object: target,
+                    object_type,
+                    tag: name.to_string(),
+                    tagger: Some(self.identity("COMMITTER", "committer")?),
+                    headers: Vec::new(),
+                    message: cleanup_message(&message).into(),
+                    signature: None,
+                };
+                self.store_object(Object::tag(tag.as_bytes().to_vec()))?
+            }
+            None => target,
+        };
+
+        let mut transaction = self.ref_transaction("")?;
+        transaction.add(RefUpdate {
+            name: tag_ref,
+            change: RefChange::Set(RefValue::Direct(oid)),
+            old: Some(previous),
+            deref: false,
+        });
+        transaction.commit(&self.refs)?;
+
+        if let Some(previous) = previous.filter(|previous| *previous != oid) {
+            let short = self.abbreviate(previous, self.abbrev_len()?)?;
+            println!("Updated tag '{name}' (was {short})");
+        }
+        Ok(oid)
+    }
+
+    pub fn delete_tag(&self, name: &str) -> Result<()> {
+        let tag_ref = format!("refs/tags/{name}");
+        let oid = self
+            .refs
+            .resolve(&tag_ref)?
+            .ok_or_else(|| anyhow!("Tag '{name}' not found"))?;
+        let mut transaction = self.ref_transaction("")?;
+        transaction.add(RefUpdate {
+            name: tag_ref,
+            change: RefChange::Delete,
+            old: Some(Some(oid)),
+            deref: false,
+        });
+        transaction.commit(&self.refs)?;
+
+        let short = self.abbreviate(oid, self.abbrev_len()?)?;
+        println!("Deleted tag '{name}' (was {short})");
+        Ok(())
+    }
+
+    /// Tags matching any of the glob patterns (all of them if there are none), sorted by `sort`:
+    /// `refname` (the default), `version:refname` (or `v:refname`) or `creatordate`, and in
+    /// reverse order with a `-` prefix
+    pub fn list_tags(&self, patterns: &[String], sort: Option<&str>) -> Result<Vec<Ref>> {
+        let mut tags = self
+            .refs
+            .list("refs/tags/")?
+            .into_iter()
+            .filter(|tag| {
+                let name = tag.name.trim_start_matches("refs/tags/");
+                patterns.is_empty() || patterns.iter().any(|p| glob_match(p, name, false))
+            })
+            .collect::<Vec<_>>();
+
+        let sort = sort.unwrap_or("refname");
+        let (key, reverse) = match sort.strip_prefix('-') {
+            Some(key) => (key, true),
+            None => (sort, false),
+        };
+        match key {
+            "refname" => {}
+            "version:refname" | "v:refname" => {
+                tags.sort_by(|a, b| version_cmp(&a.name, &b.name));
+            }
+            "creatordate" => {
+                let mut dates = Vec::new();
+                for tag in &tags {
+                    dates.push(self.creator_date(tag.oid)?);
+                }
+                let mut dated = dates.into_iter().zip(tags).collect::<Vec<_>>();
+                // Stable sort, so that tags with the same date stay sorted by name
+                dated.sort_by_key(|(date, _)| *date);
+                tags = dated.into_iter().map(|(_, tag)| tag).collect();
+            }
+            _ => bail!("Unsupported sort key: {sort}"),
+        }
+        if reverse {
+            tags.reverse();
+        }
+        Ok(tags)
+    }
+
+    /// The first `n` lines of the message of a tag, or of the commit it points to for a
+    /// lightweight tag
+    pub fn tag_message_lines(&self, oid: ObjectId, n: usize) -> Result<Vec<String>> {
+        let object = self.get_object(oid)?;
+        let message = match object.object_type {
+            ObjectType::Tag => object.as_tag().map(|tag| tag.message),
+            ObjectType::Commit => object.as_commit().map(|commit| commit.message),
+            _ => None,
+        };
+        let message = message.unwrap_or_default();
+        Ok(String::from_utf8_lossy(&message)
+            .lines()
+            .take(n)
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Date of the tagger of an annotated tag, or of the committer of a commit
+    fn creator_date(&self, oid: ObjectId) -> Result<i64> {
+        let object = self.get_object(oid)?;
+        let date = match object.object_type {
+            ObjectType::Tag => object.as_tag().and_then(|tag| tag.tagger),
+            ObjectType::Commit => object.as_commit().map(|commit| commit.committer),
+            _ => None,
+        };
+        Ok(date.map_or(0, |signature| signature.timestamp))
+    }
+}
+
+/// Compare names such that numbers within them are compared by value, e.g `v1.9` < `v1.10`
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let (a_num, b_num) = (&a[..a_len], &b[..b_len]);
+                // Compare by value: ignore leading zeros, then longer numbers are larger
+                let zeros = |num: &[u8]| num.iter().take_while(|c| **c == b'0').count();
+                let (a_val, b_val) = (&a_num[zeros(a_num)..], &b_num[zeros(b_num)..]);
+                let ordering = a_val.len().cmp(&b_val.len()).then(a_val.cmp(b_val));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (&a[a_len..], &b[b_len..]);
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(y),
+            _ => (a, b) = (&a[1..], &b[1..]),
+        }
+    }
+}
+
+/// Clean up a tag message like git does by default: drop `#` comment lines and trailing
+/// whitespace, collapse consecutive blank lines and remove leading and trailing ones. Each
+/// remaining line ends with a newline.
+fn cleanup_message(message: &str) -> String {
+    let mut cleaned = String::new();
+    let mut blank = false;
+    for line in message.lines().filter(|line| !line.starts_with('#')) {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = !cleaned.is_empty();
+            continue;
+        }
+        if blank {
+            cleaned.push('\n');
+            blank = false;
+        }
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_cmp() {
+        assert_eq!(version_cmp("v1.9", "v1.10"), Ordering::Less);
+        assert_eq!(version_cmp("v2.0", "v10.0"), Ordering::Less);
+        assert_eq!(version_cmp("v1.2", "v1.2"), Ordering::Equal);
+        assert_eq!(version_cmp("v1.2.1", "v1.2"), Ordering::Greater);
+        assert_eq!(version_cmp("a", "b"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_cleanup_message() {
+        assert_eq!(cleanup_message("v1"), "v1\n");
+        assert_eq!(cleanup_message("v1\n"), "v1\n");
+        assert_eq!(
+            cleanup_message("\n\nTitle  \n# comment\n\n\n\tBody\t\r\n\n \n"),
+            "Title\n\n\tBody\n"
+        );
+        assert_eq!(cleanup_message(" \n# only a comment\n"), "");
+    }
+}